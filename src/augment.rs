@@ -0,0 +1,416 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{ops::Range, sync::Mutex};
+
+use crate::dataset::Dataset;
+use crate::network::TrainingData;
+
+// a random transformation of an image, applied with the given probability
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Augmentation {
+    // moves the image by up to max_pixels in both directions
+    Shift {
+        probability: f64,
+        max_pixels: f64,
+    },
+    // rotates around the image center by up to max_degrees either way
+    Rotate {
+        probability: f64,
+        max_degrees: f64,
+    },
+    // zooms around the image center by a factor between min and max
+    Scale {
+        probability: f64,
+        min: f64,
+        max: f64,
+    },
+    // smoothed random displacement field, see Simard et al. 2003
+    // alpha scales the displacement, sigma is the smoothing in pixels
+    Elastic {
+        probability: f64,
+        alpha: f64,
+        sigma: f64,
+    },
+    GaussianNoise {
+        probability: f64,
+        std_dev: f64,
+    },
+    // blanks a rectangle covering between min_area and max_area of the image
+    Erase {
+        probability: f64,
+        min_area: f64,
+        max_area: f64,
+        value: f64,
+    },
+}
+
+impl Augmentation {
+    fn probability(&self) -> f64 {
+        match self {
+            Augmentation::Shift { probability, .. }
+            | Augmentation::Rotate { probability, .. }
+            | Augmentation::Scale { probability, .. }
+            | Augmentation::Elastic { probability, .. }
+            | Augmentation::GaussianNoise { probability, .. }
+            | Augmentation::Erase { probability, .. } => *probability,
+        }
+    }
+
+    // rejects parameters that would make the augmentation meaningless
+    pub fn validate(&self) -> Result<(), String> {
+        let probability = self.probability();
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(format!(
+                "Augmentation probability {} is not between 0 and 1",
+                probability
+            ));
+        }
+        let non_negative = |name: &str, value: f64| {
+            if value >= 0.0 && value.is_finite() {
+                Ok(())
+            } else {
+                Err(format!("Augmentation {} {} is negative", name, value))
+            }
+        };
+        match *self {
+            Augmentation::Shift { max_pixels, .. } => non_negative("max_pixels", max_pixels),
+            Augmentation::Rotate { max_degrees, .. } => non_negative("max_degrees", max_degrees),
+            Augmentation::Scale { min, max, .. } => {
+                if !(min > 0.0 && min <= max && max.is_finite()) {
+                    return Err(format!(
+                        "Scale range {} to {} needs 0 < min <= max",
+                        min, max
+                    ));
+                }
+                Ok(())
+            }
+            Augmentation::Elastic { alpha, sigma, .. } => {
+                non_negative("alpha", alpha)?;
+                non_negative("sigma", sigma)
+            }
+            Augmentation::GaussianNoise { std_dev, .. } => non_negative("std_dev", std_dev),
+            Augmentation::Erase {
+                min_area, max_area, ..
+            } => {
+                if !(0.0..=max_area).contains(&min_area) || max_area > 1.0 {
+                    return Err(format!(
+                        "Erase area {} to {} needs 0 <= min_area <= max_area <= 1",
+                        min_area, max_area
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// applies augmentations in order to inputs treated as width x height images
+#[derive(Debug, Clone)]
+pub struct Augmenter {
+    width: usize,
+    height: usize,
+    augmentations: Vec<Augmentation>,
+}
+
+impl Augmenter {
+    pub fn new(width: usize, height: usize, augmentations: Vec<Augmentation>) -> Self {
+        Augmenter {
+            width,
+            height,
+            augmentations,
+        }
+    }
+
+    pub fn apply<R: Rng>(&self, inputs: &[f64], rng: &mut R) -> Vec<f64> {
+        if inputs.len() != self.width * self.height {
+            panic!(
+                "Inputs length needs to be {} to augment {}x{} images",
+                self.width * self.height,
+                self.width,
+                self.height
+            );
+        }
+        // geometric transforms get combined so the image is only resampled once
+        let mut angle: f64 = 0.0;
+        let mut scale: f64 = 1.0;
+        let mut shift: (f64, f64) = (0.0, 0.0);
+        let mut image = inputs.to_vec();
+        let mut pending_warp = false;
+
+        for augmentation in self.augmentations.iter() {
+            if rng.gen::<f64>() >= augmentation.probability() {
+                continue;
+            }
+            let geometric = matches!(
+                augmentation,
+                Augmentation::Shift { .. }
+                    | Augmentation::Rotate { .. }
+                    | Augmentation::Scale { .. }
+            );
+            if pending_warp && !geometric {
+                image = self.warp(&image, angle, scale, shift);
+                (angle, scale, shift, pending_warp) = (0.0, 1.0, (0.0, 0.0), false);
+            }
+            match augmentation {
+                Augmentation::Shift { max_pixels, .. } => {
+                    shift.0 += rng.gen_range(-1.0..=1.0) * max_pixels;
+                    shift.1 += rng.gen_range(-1.0..=1.0) * max_pixels;
+                    pending_warp = true;
+                }
+                Augmentation::Rotate { max_degrees, .. } => {
+                    angle += (rng.gen_range(-1.0..=1.0) * max_degrees).to_radians();
+                    pending_warp = true;
+                }
+                Augmentation::Scale { min, max, .. } => {
+                    scale *= if min < max {
+                        rng.gen_range(*min..*max)
+                    } else {
+                        *min
+                    };
+                    pending_warp = true;
+                }
+                Augmentation::Elastic { alpha, sigma, .. } => {
+                    image = self.elastic(&image, *alpha, *sigma, rng);
+                }
+                Augmentation::GaussianNoise { std_dev, .. } => {
+                    for pixel in image.iter_mut() {
+                        *pixel = (*pixel + gaussian(rng) * std_dev).clamp(0.0, 1.0);
+                    }
+                }
+                Augmentation::Erase {
+                    min_area,
+                    max_area,
+                    value,
+                    ..
+                } => {
+                    self.erase(&mut image, *min_area, *max_area, *value, rng);
+                }
+            }
+        }
+        if pending_warp {
+            image = self.warp(&image, angle, scale, shift);
+        }
+        image
+    }
+
+    fn sample(&self, image: &[f64], x: f64, y: f64) -> f64 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let pixel = |x: f64, y: f64| -> f64 {
+            if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+                0.0
+            } else {
+                image[y as usize * self.width + x as usize]
+            }
+        };
+        pixel(x0, y0) * (1.0 - tx) * (1.0 - ty)
+            + pixel(x0 + 1.0, y0) * tx * (1.0 - ty)
+            + pixel(x0, y0 + 1.0) * (1.0 - tx) * ty
+            + pixel(x0 + 1.0, y0 + 1.0) * tx * ty
+    }
+
+    // rotates and scales around the center, then shifts
+    fn warp(&self, image: &[f64], angle: f64, scale: f64, shift: (f64, f64)) -> Vec<f64> {
+        let center_x = (self.width as f64 - 1.0) / 2.0;
+        let center_y = (self.height as f64 - 1.0) / 2.0;
+        let (sin, cos) = (-angle).sin_cos();
+        let mut output = vec![0.0; image.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                // map every output pixel back to where it came from
+                let dx = (x as f64 - center_x - shift.0) / scale;
+                let dy = (y as f64 - center_y - shift.1) / scale;
+                let src_x = dx * cos - dy * sin + center_x;
+                let src_y = dx * sin + dy * cos + center_y;
+                output[y * self.width + x] = self.sample(image, src_x, src_y);
+            }
+        }
+        output
+    }
+
+    fn elastic<R: Rng>(&self, image: &[f64], alpha: f64, sigma: f64, rng: &mut R) -> Vec<f64> {
+        let random_field = |rng: &mut R| -> Vec<f64> {
+            (0..image.len())
+                .map(|_| rng.gen_range(-1.0..=1.0))
+                .collect()
+        };
+        let dx = self.blur(&random_field(rng), sigma);
+        let dy = self.blur(&random_field(rng), sigma);
+        let mut output = vec![0.0; image.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                output[i] = self.sample(image, x as f64 + dx[i] * alpha, y as f64 + dy[i] * alpha);
+            }
+        }
+        output
+    }
+
+    // separable gaussian blur, borders are clamped
+    fn blur(&self, field: &[f64], sigma: f64) -> Vec<f64> {
+        if sigma <= 0.0 {
+            return field.to_vec();
+        }
+        let radius = (sigma * 3.0).ceil() as isize;
+        let kernel: Vec<f64> = (-radius..=radius)
+            .map(|i| (-(i * i) as f64 / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let kernel_sum: f64 = kernel.iter().sum();
+        let (width, height) = (self.width as isize, self.height as isize);
+
+        let mut horizontal = vec![0.0; field.len()];
+        for y in 0..height {
+            for x in 0..width {
+                horizontal[(y * width + x) as usize] = (-radius..=radius)
+                    .map(|k| {
+                        let src = (x + k).clamp(0, width - 1);
+                        field[(y * width + src) as usize] * kernel[(k + radius) as usize]
+                    })
+                    .sum::<f64>()
+                    / kernel_sum;
+            }
+        }
+        let mut output = vec![0.0; field.len()];
+        for y in 0..height {
+            for x in 0..width {
+                output[(y * width + x) as usize] = (-radius..=radius)
+                    .map(|k| {
+                        let src = (y + k).clamp(0, height - 1);
+                        horizontal[(src * width + x) as usize] * kernel[(k + radius) as usize]
+                    })
+                    .sum::<f64>()
+                    / kernel_sum;
+            }
+        }
+        output
+    }
+
+    fn erase<R: Rng>(
+        &self,
+        image: &mut [f64],
+        min_area: f64,
+        max_area: f64,
+        value: f64,
+        rng: &mut R,
+    ) {
+        let area = if min_area < max_area {
+            rng.gen_range(min_area..max_area)
+        } else {
+            min_area
+        } * image.len() as f64;
+        // aspect ratio between 1:3 and 3:1
+        let aspect = rng.gen_range((1.0f64 / 3.0).ln()..3.0f64.ln()).exp();
+        let erase_width = ((area * aspect).sqrt().round() as usize).clamp(1, self.width);
+        let erase_height = ((area / aspect).sqrt().round() as usize).clamp(1, self.height);
+        let left = rng.gen_range(0..=self.width - erase_width);
+        let top = rng.gen_range(0..=self.height - erase_height);
+        for y in top..top + erase_height {
+            for x in left..left + erase_width {
+                image[y * self.width + x] = value;
+            }
+        }
+    }
+}
+
+// standard normal sample using the Box-Muller transform
+fn gaussian<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+// wraps a dataset so every access returns a freshly augmented sample,
+// meaning the network sees different variations of the images every epoch
+pub struct AugmentedDataset<D: Dataset> {
+    dataset: D,
+    augmenter: Augmenter,
+    rng: Mutex<StdRng>,
+}
+
+impl<D: Dataset> AugmentedDataset<D> {
+    pub fn new(dataset: D, augmenter: Augmenter, seed: u64) -> Self {
+        AugmentedDataset {
+            dataset,
+            augmenter,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dataset
+    }
+}
+
+impl<D: Dataset> Dataset for AugmentedDataset<D> {
+    fn len(&self) -> usize {
+        self.dataset.len()
+    }
+
+    fn get(&self, index: usize) -> TrainingData {
+        let mut data = self.dataset.get(index);
+        let mut rng = self.rng.lock().expect("Mutex mess.");
+        data.inputs = self.augmenter.apply(&data.inputs, &mut *rng);
+        data
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        self.dataset.class_names()
+    }
+
+    fn num_inputs(&self) -> usize {
+        self.dataset.num_inputs()
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.dataset.num_outputs()
+    }
+
+    fn batch(&self, range: Range<usize>) -> Vec<TrainingData> {
+        let mut batch = self.dataset.batch(range);
+        let mut rng = self.rng.lock().expect("Mutex mess.");
+        for data in batch.iter_mut() {
+            data.inputs = self.augmenter.apply(&data.inputs, &mut *rng);
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_scale_range() {
+        let scale = |min, max| Augmentation::Scale {
+            probability: 0.5,
+            min,
+            max,
+        };
+        assert!(scale(0.9, 1.1).validate().is_ok());
+        assert!(scale(1.0, 1.0).validate().is_ok());
+        assert!(scale(0.0, 1.1).validate().is_err());
+        assert!(scale(-0.5, 1.1).validate().is_err());
+        assert!(scale(1.2, 1.1).validate().is_err());
+        assert!(scale(f64::NAN, 1.1).validate().is_err());
+    }
+
+    #[test]
+    fn validates_probabilities_and_areas() {
+        let erase = |probability, min_area, max_area| Augmentation::Erase {
+            probability,
+            min_area,
+            max_area,
+            value: 0.0,
+        };
+        assert!(erase(0.5, 0.02, 0.2).validate().is_ok());
+        assert!(erase(1.5, 0.02, 0.2).validate().is_err());
+        assert!(erase(0.5, 0.3, 0.2).validate().is_err());
+        assert!(erase(0.5, 0.02, 1.2).validate().is_err());
+        let noise = Augmentation::GaussianNoise {
+            probability: 0.5,
+            std_dev: -0.1,
+        };
+        assert!(noise.validate().is_err());
+    }
+}
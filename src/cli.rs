@@ -0,0 +1,1128 @@
+use std::collections::HashMap;
+
+use neural_network::config::{DataSpec, NonFiniteAction, Optimizer, Storage, TrainingConfig};
+use neural_network::datasets::Split;
+use neural_network::image::DigitOptions;
+use neural_network::misclassified::{ExportOptions, ImageFormat};
+use neural_network::preprocessing::Preprocessing;
+use neural_network::server::ServerOptions;
+use neural_network::tabular::MissingValues;
+
+pub const DEFAULT_MODEL: &str = "network-data/data.json";
+
+struct Flag {
+    name: &'static str,
+    // placeholder shown in the help text, None for switches that don't take a value
+    value: Option<&'static str>,
+    default: Option<&'static str>,
+    help: &'static str,
+}
+
+const MODEL_FLAG: Flag = Flag {
+    name: "model",
+    value: Some("PATH"),
+    default: Some(DEFAULT_MODEL),
+    help: "Model file to load",
+};
+const DATA_DIR_FLAG: Flag = Flag {
+    name: "data-dir",
+    value: Some("DIR"),
+    default: Some("mnist"),
+    help: "Directory holding the IDX files of dataset presets",
+};
+const TEST_DATA_FLAG: Flag = Flag {
+    name: "test-data",
+    value: Some("DATASET"),
+    default: Some("mnist"),
+    help: "Dataset to evaluate on, see DATASETS below",
+};
+const STORAGE_FLAG: Flag = Flag {
+    name: "storage",
+    value: Some("MODE"),
+    default: Some("memory"),
+    help: "How presets are read: memory, mmap or stream (mmap and stream need extracted files)",
+};
+const LABEL_COLUMN_FLAG: Flag = Flag {
+    name: "label-column",
+    value: Some("COLUMN"),
+    default: Some("label"),
+    help: "Label column of CSV datasets, by header name or 0-based index, \
+           comma separated target columns for regression models",
+};
+const MISSING_VALUES_FLAG: Flag = Flag {
+    name: "missing-values",
+    value: Some("POLICY"),
+    default: Some("error"),
+    help: "Missing features of CSV datasets: error, drop, mean or fill:VALUE",
+};
+const NO_HEADER_FLAG: Flag = Flag {
+    name: "no-header",
+    value: None,
+    default: None,
+    help: "CSV datasets start with a sample instead of column names",
+};
+const HELP_FLAG: Flag = Flag {
+    name: "help",
+    value: None,
+    default: None,
+    help: "Print this help",
+};
+
+// train flags have no defaults of their own, they override the config
+const TRAIN_FLAGS: [Flag; 33] = [
+    Flag {
+        name: "config",
+        value: Some("PATH"),
+        default: None,
+        help: "TOML or JSON training config, see --print-config for its fields",
+    },
+    Flag {
+        name: "model",
+        value: Some("PATH"),
+        default: None,
+        help: "Model file to continue training from",
+    },
+    Flag {
+        name: "output",
+        value: Some("PATH"),
+        default: None,
+        help: "Where to save the trained model, defaults to --model",
+    },
+    Flag {
+        name: "train-data",
+        value: Some("DATASET"),
+        default: None,
+        help: "Dataset to train on, see DATASETS below",
+    },
+    Flag {
+        name: "test-data",
+        value: Some("DATASET"),
+        default: None,
+        help: "Dataset to evaluate on after every epoch, see DATASETS below",
+    },
+    Flag {
+        name: "data-dir",
+        value: Some("DIR"),
+        default: None,
+        help: "Directory holding the IDX files of dataset presets",
+    },
+    Flag {
+        name: "storage",
+        value: Some("MODE"),
+        default: None,
+        help: "How presets are read: memory, mmap or stream (mmap and stream need extracted files)",
+    },
+    Flag {
+        name: "label-column",
+        value: Some("COLUMN"),
+        default: None,
+        help: "Label column of CSV datasets, by header name or 0-based index, \
+               comma separated target columns for regression",
+    },
+    Flag {
+        name: "feature-columns",
+        value: Some("COLUMNS"),
+        default: None,
+        help: "Comma separated feature columns of CSV datasets, every other column by default",
+    },
+    Flag {
+        name: "categorical-columns",
+        value: Some("COLUMNS"),
+        default: None,
+        help: "Comma separated feature columns holding categories, which get one-hot encoded",
+    },
+    Flag {
+        name: "missing-values",
+        value: Some("POLICY"),
+        default: None,
+        help: "Missing features of CSV datasets: error, drop, mean or fill:VALUE",
+    },
+    NO_HEADER_FLAG,
+    Flag {
+        name: "task",
+        value: Some("TASK"),
+        default: None,
+        help: "classification or regression, which needs an identity output layer \
+               and defaults to 16,16,TARGETS:identity",
+    },
+    Flag {
+        name: "layers",
+        value: Some("SIZES"),
+        default: None,
+        help: "Layers of a new network, the last one being the output layer: dense sizes, \
+               conv:FILTERS:KERNEL[:STRIDE[:PADDING]], max_pool2d:SIZE[:STRIDE], \
+               avg_pool2d:SIZE[:STRIDE], global_avg_pool, flatten, reshape:CxHxW, dropout:RATE, \
+               batch_norm[:MOMENTUM], layer_norm or residual:LAYER/LAYER/... adding the \
+               block's inputs to its outputs. \
+               Dense sizes and convolutions can end in :sigmoid, :relu, :identity or :softmax",
+    },
+    Flag {
+        name: "preprocess",
+        value: Some("STEPS"),
+        default: None,
+        help: "Preprocessing of a new network: min-max, standardize, mean-image, pca[:N]",
+    },
+    Flag {
+        name: "l1",
+        value: Some("RATE"),
+        default: None,
+        help: "L1 penalty on the weights of every dense and convolution layer of a new network",
+    },
+    Flag {
+        name: "l2",
+        value: Some("RATE"),
+        default: None,
+        help: "L2 penalty on the weights of every dense and convolution layer of a new network",
+    },
+    Flag {
+        name: "regularize-biases",
+        value: None,
+        default: None,
+        help: "Apply --l1, --l2 and --weight-decay to biases as well",
+    },
+    Flag {
+        name: "loss",
+        value: Some("LOSS"),
+        default: None,
+        help: "mse, mae or huber[:DELTA]",
+    },
+    Flag {
+        name: "no-standardize-targets",
+        value: None,
+        default: None,
+        help: "Train regression on the raw targets instead of ones with zero mean and unit \
+               variance",
+    },
+    Flag {
+        name: "optimizer",
+        value: Some("NAME"),
+        default: None,
+        help: "sgd or adam",
+    },
+    Flag {
+        name: "learning-rate",
+        value: Some("RATE"),
+        default: None,
+        help: "Learning rate",
+    },
+    Flag {
+        name: "weight-decay",
+        value: Some("RATE"),
+        default: None,
+        help: "Decoupled weight decay of the adam optimizer",
+    },
+    Flag {
+        name: "clip-value",
+        value: Some("VALUE"),
+        default: None,
+        help: "Clamp every gradient to -VALUE..VALUE",
+    },
+    Flag {
+        name: "clip-norm",
+        value: Some("NORM"),
+        default: None,
+        help: "Scale the gradients of a batch down to at most this L2 norm",
+    },
+    Flag {
+        name: "rollback",
+        value: None,
+        default: None,
+        help: "On NaN or infinite values go back to the start of the epoch and halve the \
+               learning rate, instead of stopping",
+    },
+    Flag {
+        name: "batch-size",
+        value: Some("N"),
+        default: None,
+        help: "Samples per gradient update",
+    },
+    Flag {
+        name: "epochs",
+        value: Some("N"),
+        default: None,
+        help: "Epochs to train for, training stops early once accuracy stops improving",
+    },
+    Flag {
+        name: "seed",
+        value: Some("N"),
+        default: None,
+        help: "Seed for the weight initialization and augmentation",
+    },
+    Flag {
+        name: "log-file",
+        value: Some("PATH"),
+        default: None,
+        help: "CSV file to append the learning rate and accuracy of every epoch to",
+    },
+    Flag {
+        name: "fresh",
+        value: None,
+        default: None,
+        help: "Start from a new network even if the model file exists",
+    },
+    Flag {
+        name: "print-config",
+        value: None,
+        default: None,
+        help: "Print the resolved config as TOML instead of training",
+    },
+    HELP_FLAG,
+];
+
+const EVAL_FLAGS: [Flag; 13] = [
+    MODEL_FLAG,
+    TEST_DATA_FLAG,
+    DATA_DIR_FLAG,
+    STORAGE_FLAG,
+    LABEL_COLUMN_FLAG,
+    MISSING_VALUES_FLAG,
+    NO_HEADER_FLAG,
+    Flag {
+        name: "top-k",
+        value: Some("KS"),
+        default: Some("3,5"),
+        help: "Comma separated k values to report top-k accuracy for",
+    },
+    Flag {
+        name: "json",
+        value: Some("PATH"),
+        default: None,
+        help: "Also write the metrics to a JSON file",
+    },
+    Flag {
+        name: "misclassified",
+        value: Some("DIR"),
+        default: None,
+        help: "Write the misclassified samples as images to DIR, with an index.csv",
+    },
+    Flag {
+        name: "image-format",
+        value: Some("FORMAT"),
+        default: Some("png"),
+        help: "Format of the misclassified images: png or pgm",
+    },
+    Flag {
+        name: "contact-sheet",
+        value: Some("N"),
+        default: Some("0"),
+        help: "Also put the N most confident mistakes on one contact sheet image",
+    },
+    HELP_FLAG,
+];
+
+const PREDICT_FLAGS: [Flag; 14] = [
+    MODEL_FLAG,
+    TEST_DATA_FLAG,
+    DATA_DIR_FLAG,
+    STORAGE_FLAG,
+    LABEL_COLUMN_FLAG,
+    MISSING_VALUES_FLAG,
+    NO_HEADER_FLAG,
+    Flag {
+        name: "index",
+        value: Some("INDICES"),
+        default: Some("0"),
+        help: "Comma separated sample indices to predict when no images are given",
+    },
+    Flag {
+        name: "top-k",
+        value: Some("K"),
+        default: Some("3"),
+        help: "Number of most likely classes to print",
+    },
+    Flag {
+        name: "invert",
+        value: Some("MODE"),
+        default: Some("auto"),
+        help: "Invert images to light on dark: auto, always or never",
+    },
+    Flag {
+        name: "no-center",
+        value: None,
+        default: None,
+        help: "Only resize images instead of centering them like MNIST digits",
+    },
+    Flag {
+        name: "json",
+        value: None,
+        default: None,
+        help: "Print the predictions as JSON",
+    },
+    Flag {
+        name: "stdio",
+        value: None,
+        default: None,
+        help:
+            "Read one JSON request per line from stdin, {\"inputs\": [...]} or {\"image\": PATH} \
+               with an optional id and top_k, and answer each with a line of JSON",
+    },
+    HELP_FLAG,
+];
+
+const INSPECT_FLAGS: [Flag; 2] = [MODEL_FLAG, HELP_FLAG];
+
+const CONVERT_FLAGS: [Flag; 7] = [
+    DATA_DIR_FLAG,
+    Flag {
+        name: "split",
+        value: Some("SPLIT"),
+        default: Some("train"),
+        help: "Split of dataset presets to export: train or test",
+    },
+    LABEL_COLUMN_FLAG,
+    MISSING_VALUES_FLAG,
+    NO_HEADER_FLAG,
+    Flag {
+        name: "gzip",
+        value: None,
+        default: None,
+        help: "Compress the output, also implied by a .gz output file name",
+    },
+    HELP_FLAG,
+];
+
+const INIT_FLAGS: [Flag; 6] = [
+    MODEL_FLAG,
+    Flag {
+        name: "layers",
+        value: Some("SIZES"),
+        default: Some("16,16,10"),
+        help: "Layer sizes, the last one being the output layer",
+    },
+    Flag {
+        name: "inputs",
+        value: Some("N"),
+        default: Some("784"),
+        help: "Number of inputs",
+    },
+    Flag {
+        name: "learning-rate",
+        value: Some("RATE"),
+        default: Some("0.03"),
+        help: "Learning rate",
+    },
+    Flag {
+        name: "force",
+        value: None,
+        default: None,
+        help: "Overwrite an existing model file",
+    },
+    HELP_FLAG,
+];
+
+const SERVE_FLAGS: [Flag; 7] = [
+    MODEL_FLAG,
+    Flag {
+        name: "address",
+        value: Some("HOST:PORT"),
+        default: Some("127.0.0.1:8080"),
+        help: "Address to listen on",
+    },
+    Flag {
+        name: "threads",
+        value: Some("N"),
+        default: Some("4"),
+        help: "Number of requests handled at the same time",
+    },
+    Flag {
+        name: "top-k",
+        value: Some("K"),
+        default: Some("3"),
+        help: "Classes returned when a request doesn't set top_k",
+    },
+    Flag {
+        name: "invert",
+        value: Some("MODE"),
+        default: Some("auto"),
+        help: "Invert posted images to light on dark: auto, always or never",
+    },
+    Flag {
+        name: "no-center",
+        value: None,
+        default: None,
+        help: "Only resize posted images instead of centering them like MNIST digits",
+    },
+    HELP_FLAG,
+];
+
+struct Subcommand {
+    name: &'static str,
+    usage: &'static str,
+    about: &'static str,
+    flags: &'static [Flag],
+}
+
+const SUBCOMMANDS: [Subcommand; 7] = [
+    Subcommand {
+        name: "train",
+        usage: "train [FLAGS]",
+        about: "Train the model, saving it and its resolved config whenever test accuracy improves",
+        flags: &TRAIN_FLAGS,
+    },
+    Subcommand {
+        name: "eval",
+        usage: "eval [FLAGS]",
+        about: "Report accuracy, per-class metrics and the confusion matrix of the model on a \
+                dataset, or RMSE, MAE and R² of regression models",
+        flags: &EVAL_FLAGS,
+    },
+    Subcommand {
+        name: "predict",
+        usage: "predict [FLAGS] [IMAGE|DIR]...",
+        about: "Print the model's predictions for image files, or for dataset samples without any",
+        flags: &PREDICT_FLAGS,
+    },
+    Subcommand {
+        name: "serve",
+        usage: "serve [FLAGS]",
+        about: "Serve predictions over HTTP, reloading the model when its file changes. \
+                GET /health and /model, POST /predict with {\"inputs\": [...]} or image bytes",
+        flags: &SERVE_FLAGS,
+    },
+    Subcommand {
+        name: "inspect",
+        usage: "inspect [FLAGS]",
+        about: "Print the architecture and preprocessing of a model file",
+        flags: &INSPECT_FLAGS,
+    },
+    Subcommand {
+        name: "convert",
+        usage: "convert [FLAGS] INPUT OUTPUT",
+        about: "Convert an IDX file between gzipped and raw, or export a dataset to \
+                OUTPUT-images-idx3-ubyte and OUTPUT-labels-idx1-ubyte",
+        flags: &CONVERT_FLAGS,
+    },
+    Subcommand {
+        name: "init",
+        usage: "init [FLAGS]",
+        about: "Create a new randomly initialized model file",
+        flags: &INIT_FLAGS,
+    },
+];
+
+const DATASETS_HELP: &str = "DATASETS:
+    mnist, fashion-mnist, kmnist, emnist-balanced, emnist-byclass, emnist-bymerge,
+    emnist-letters, emnist-digits, emnist-mnist
+                          Preset read from --data-dir, the split depends on the command
+    csv:PATH              CSV or TSV file, see --label-column and --missing-values
+    images:DIR            Directory with one sub directory of images per class";
+
+#[derive(Debug, Clone)]
+pub struct DataArgs {
+    pub data_dir: String,
+    pub storage: Storage,
+    pub label_column: String,
+    // empty for every column except the labels
+    pub feature_columns: String,
+    pub categorical_columns: String,
+    pub missing_values: MissingValues,
+    pub has_header: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrainArgs {
+    pub config: Box<TrainingConfig>,
+    pub print_config: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct EvalArgs {
+    pub model: String,
+    pub test_data: DataSpec,
+    pub data: DataArgs,
+    pub top_k: Vec<usize>,
+    pub json: Option<String>,
+    pub misclassified: Option<String>,
+    pub export: ExportOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct PredictArgs {
+    pub model: String,
+    pub test_data: DataSpec,
+    pub data: DataArgs,
+    pub indices: Vec<usize>,
+    pub top_k: usize,
+    // image files or directories of them, predicted instead of the dataset
+    pub images: Vec<String>,
+    pub digit: DigitOptions,
+    pub json: bool,
+    pub stdio: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServeArgs {
+    pub model: String,
+    pub options: ServerOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectArgs {
+    pub model: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConvertArgs {
+    pub input: String,
+    pub output: String,
+    pub split: Split,
+    pub gzip: bool,
+    pub data: DataArgs,
+}
+
+#[derive(Debug, Clone)]
+pub struct InitArgs {
+    pub model: String,
+    pub layers: Vec<usize>,
+    pub inputs: usize,
+    pub learning_rate: f64,
+    pub force: bool,
+}
+
+pub enum Command {
+    Train(TrainArgs),
+    Eval(EvalArgs),
+    Predict(PredictArgs),
+    Serve(ServeArgs),
+    Inspect(InspectArgs),
+    Convert(ConvertArgs),
+    Init(InitArgs),
+    Help(String),
+}
+
+struct Flags {
+    values: HashMap<&'static str, String>,
+    positional: Vec<String>,
+}
+
+impl Flags {
+    fn parse(args: &[String], spec: &'static [Flag]) -> Result<Flags, String> {
+        let mut values: HashMap<&'static str, String> = HashMap::new();
+        let mut positional: Vec<String> = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                if arg == "-h" {
+                    values.insert("help", String::new());
+                } else {
+                    positional.push(arg.clone());
+                }
+                continue;
+            };
+            let (name, inline_value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (flag, None),
+            };
+            let flag = spec
+                .iter()
+                .find(|f| f.name == name)
+                .ok_or_else(|| format!("Unknown flag --{}", name))?;
+            let value = match (flag.value, inline_value) {
+                (None, None) => String::new(),
+                (None, Some(_)) => return Err(format!("--{} doesn't take a value", name)),
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("--{} needs a value", name))?,
+            };
+            values.insert(flag.name, value);
+        }
+        for flag in spec.iter() {
+            if let Some(default) = flag.default {
+                values
+                    .entry(flag.name)
+                    .or_insert_with(|| default.to_string());
+            }
+        }
+        Ok(Flags { values, positional })
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    fn optional(&self, name: &str) -> Option<String> {
+        self.values.get(name).cloned()
+    }
+
+    fn string(&self, name: &str) -> String {
+        self.values.get(name).cloned().unwrap_or_default()
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Result<T, String> {
+        let value = self.string(name);
+        value
+            .parse()
+            .map_err(|_| format!("Invalid value {:?} for --{}", value, name))
+    }
+
+    fn list<T: std::str::FromStr>(&self, name: &str) -> Result<Vec<T>, String> {
+        let value = self.string(name);
+        value
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| {
+                s.trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid value {:?} in --{}", s, name))
+            })
+            .collect()
+    }
+
+    fn data_args(&self) -> Result<DataArgs, String> {
+        Ok(DataArgs {
+            data_dir: self.string("data-dir"),
+            storage: self.string("storage").parse()?,
+            label_column: self.string("label-column"),
+            feature_columns: String::new(),
+            categorical_columns: String::new(),
+            missing_values: self.string("missing-values").parse()?,
+            has_header: !self.has("no-header"),
+        })
+    }
+}
+
+pub fn parse_preprocessing(steps: &str) -> Result<Vec<Preprocessing>, String> {
+    steps
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|step| match step.trim().split_once(':') {
+            None if step.trim() == "min-max" => Ok(Preprocessing::MinMax),
+            None if step.trim() == "standardize" => Ok(Preprocessing::Standardize),
+            None if step.trim() == "mean-image" => Ok(Preprocessing::MeanImage),
+            None if step.trim() == "pca" => Ok(Preprocessing::PcaWhitening {
+                components: None,
+                epsilon: 1e-5,
+            }),
+            Some(("pca", components)) => Ok(Preprocessing::PcaWhitening {
+                components: Some(
+                    components
+                        .parse()
+                        .map_err(|_| format!("Invalid PCA component count {:?}", components))?,
+                ),
+                epsilon: 1e-5,
+            }),
+            _ => Err(format!("Unknown preprocessing step {:?}", step)),
+        })
+        .collect()
+}
+
+// args excludes the program name
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let Some((name, rest)) = args.split_first() else {
+        // without a subcommand, report the accuracy of the default model like before
+        return parse(&["eval".to_string()]);
+    };
+    if name == "help" || name == "--help" || name == "-h" {
+        return Ok(Command::Help(match rest.first() {
+            Some(name) => subcommand_help(name)?,
+            None => help(),
+        }));
+    }
+    let subcommand = SUBCOMMANDS
+        .iter()
+        .find(|s| s.name == name)
+        .ok_or_else(|| format!("Unknown command {:?}, see --help", name))?;
+    let flags = Flags::parse(rest, subcommand.flags)?;
+    if flags.has("help") {
+        return Ok(Command::Help(subcommand_help(name)?));
+    }
+    let takes_positional = subcommand.name == "convert" || subcommand.name == "predict";
+    if !takes_positional && !flags.positional.is_empty() {
+        return Err(format!("Unexpected argument {:?}", flags.positional[0]));
+    }
+
+    Ok(match subcommand.name {
+        "train" => {
+            let mut config = if flags.has("config") {
+                TrainingConfig::load(flags.string("config")).map_err(|e| e.to_string())?
+            } else {
+                TrainingConfig::default()
+            };
+            apply_train_flags(&flags, &mut config)?;
+            Command::Train(TrainArgs {
+                config: Box::new(config),
+                print_config: flags.has("print-config"),
+            })
+        }
+        "eval" => Command::Eval(EvalArgs {
+            model: flags.string("model"),
+            test_data: flags.string("test-data").parse()?,
+            data: flags.data_args()?,
+            top_k: flags.list("top-k")?,
+            json: flags.optional("json"),
+            misclassified: flags.optional("misclassified"),
+            export: ExportOptions {
+                format: match flags.string("image-format").as_str() {
+                    "png" => ImageFormat::Png,
+                    "pgm" => ImageFormat::Pgm,
+                    other => return Err(format!("Unknown image format {:?}", other)),
+                },
+                contact_sheet: flags.number("contact-sheet")?,
+            },
+        }),
+        "predict" => Command::Predict(PredictArgs {
+            model: flags.string("model"),
+            test_data: flags.string("test-data").parse()?,
+            data: flags.data_args()?,
+            indices: flags.list("index")?,
+            top_k: flags.number("top-k")?,
+            images: flags.positional.clone(),
+            digit: DigitOptions {
+                invert: flags.string("invert").parse()?,
+                center: !flags.has("no-center"),
+                ..DigitOptions::default()
+            },
+            json: flags.has("json"),
+            stdio: flags.has("stdio"),
+        }),
+        "serve" => Command::Serve(ServeArgs {
+            model: flags.string("model"),
+            options: ServerOptions {
+                address: flags.string("address"),
+                threads: flags.number("threads")?,
+                top_k: flags.number("top-k")?,
+                digit: DigitOptions {
+                    invert: flags.string("invert").parse()?,
+                    center: !flags.has("no-center"),
+                    ..DigitOptions::default()
+                },
+            },
+        }),
+        "inspect" => Command::Inspect(InspectArgs {
+            model: flags.string("model"),
+        }),
+        "convert" => {
+            let [input, output] = flags.positional.as_slice() else {
+                return Err("convert needs an INPUT and an OUTPUT".to_string());
+            };
+            Command::Convert(ConvertArgs {
+                input: input.clone(),
+                output: output.clone(),
+                split: match flags.string("split").as_str() {
+                    "train" => Split::Train,
+                    "test" => Split::Test,
+                    other => return Err(format!("Unknown split {:?}", other)),
+                },
+                gzip: flags.has("gzip") || output.ends_with(".gz"),
+                data: DataArgs {
+                    data_dir: flags.string("data-dir"),
+                    storage: Storage::Memory,
+                    label_column: flags.string("label-column"),
+                    feature_columns: String::new(),
+                    categorical_columns: String::new(),
+                    missing_values: flags.string("missing-values").parse()?,
+                    has_header: !flags.has("no-header"),
+                },
+            })
+        }
+        "init" => Command::Init(InitArgs {
+            model: flags.string("model"),
+            layers: flags.list("layers")?,
+            inputs: flags.number("inputs")?,
+            learning_rate: flags.number("learning-rate")?,
+            force: flags.has("force"),
+        }),
+        _ => unreachable!("Every subcommand is handled."),
+    })
+}
+
+fn apply_train_flags(flags: &Flags, config: &mut TrainingConfig) -> Result<(), String> {
+    let dataset = &mut config.dataset;
+    if flags.has("train-data") {
+        dataset.train = flags.string("train-data").parse()?;
+    }
+    if flags.has("test-data") {
+        dataset.test = flags.string("test-data").parse()?;
+    }
+    if flags.has("data-dir") {
+        dataset.data_dir = flags.string("data-dir");
+    }
+    if flags.has("storage") {
+        dataset.storage = flags.string("storage").parse()?;
+    }
+    if flags.has("label-column") {
+        dataset.label_column = flags.string("label-column");
+    }
+    if flags.has("feature-columns") {
+        dataset.feature_columns = flags.string("feature-columns");
+    }
+    if flags.has("categorical-columns") {
+        dataset.categorical_columns = flags.string("categorical-columns");
+    }
+    if flags.has("missing-values") {
+        dataset.missing_values = flags.string("missing-values").parse()?;
+    }
+    if flags.has("no-header") {
+        dataset.has_header = false;
+    }
+    if flags.has("task") {
+        config.task = flags.string("task").parse()?;
+    }
+    if flags.has("layers") {
+        config.layers = flags.list("layers")?;
+    }
+    if flags.has("preprocess") {
+        config.preprocessing = parse_preprocessing(&flags.string("preprocess"))?;
+    }
+    if flags.has("l1") {
+        config.regularization.l1 = flags.number("l1")?;
+    }
+    if flags.has("l2") {
+        config.regularization.l2 = flags.number("l2")?;
+    }
+    if flags.has("regularize-biases") {
+        config.regularization.biases = true;
+    }
+    if flags.has("loss") {
+        config.loss = flags.string("loss").parse()?;
+    }
+    if flags.has("no-standardize-targets") {
+        config.standardize_targets = false;
+    }
+    if flags.has("optimizer") {
+        let learning_rate = config.optimizer.learning_rate();
+        config.optimizer = match flags.string("optimizer").as_str() {
+            "sgd" => Optimizer::Sgd { learning_rate },
+            "adam" => Optimizer::adam(learning_rate),
+            other => return Err(format!("Unknown optimizer {:?}", other)),
+        };
+    }
+    if flags.has("learning-rate") {
+        let rate = flags.number("learning-rate")?;
+        match &mut config.optimizer {
+            Optimizer::Sgd { learning_rate } | Optimizer::Adam { learning_rate, .. } => {
+                *learning_rate = rate
+            }
+        }
+    }
+    if flags.has("weight-decay") {
+        match &mut config.optimizer {
+            Optimizer::Adam { weight_decay, .. } => *weight_decay = flags.number("weight-decay")?,
+            Optimizer::Sgd { .. } => return Err("--weight-decay needs the adam optimizer".into()),
+        }
+    }
+    if flags.has("clip-value") {
+        config.gradient_clipping.value = Some(flags.number("clip-value")?);
+    }
+    if flags.has("clip-norm") {
+        config.gradient_clipping.norm = Some(flags.number("clip-norm")?);
+    }
+    config.gradient_clipping.validate()?;
+    if flags.has("rollback") {
+        config.on_non_finite = NonFiniteAction::Rollback;
+    }
+    if flags.has("batch-size") {
+        config.batch_size = flags.number("batch-size")?;
+    }
+    if flags.has("epochs") {
+        config.epochs = flags.number("epochs")?;
+    }
+    if flags.has("seed") {
+        config.seed = Some(flags.number("seed")?);
+    }
+    if flags.has("model") {
+        config.checkpoint.model = flags.string("model");
+    }
+    if flags.has("output") {
+        config.checkpoint.output = Some(flags.string("output"));
+    }
+    if flags.has("fresh") {
+        config.checkpoint.resume = false;
+    }
+    if flags.has("log-file") {
+        config.logging.file = Some(flags.string("log-file"));
+    }
+    Ok(())
+}
+
+pub fn help() -> String {
+    let mut text = String::from(
+        "Trains and runs a neural network on MNIST-like datasets.\n\n\
+         USAGE:\n    neural-network [COMMAND] [FLAGS]\n\n\
+         Without a command, evaluates the default model on the MNIST test set.\n\nCOMMANDS:\n",
+    );
+    for subcommand in SUBCOMMANDS.iter() {
+        text += &format!("    {:<10}{}\n", subcommand.name, subcommand.about);
+    }
+    text += "    help      Print help for a command\n\n";
+    text += "See neural-network help COMMAND for its flags.\n";
+    text
+}
+
+fn subcommand_help(name: &str) -> Result<String, String> {
+    let subcommand = SUBCOMMANDS
+        .iter()
+        .find(|s| s.name == name)
+        .ok_or_else(|| format!("Unknown command {:?}", name))?;
+    let mut text = format!(
+        "{}\n\nUSAGE:\n    neural-network {}\n\nFLAGS:\n",
+        subcommand.about, subcommand.usage
+    );
+    let names: Vec<String> = subcommand
+        .flags
+        .iter()
+        .map(|flag| match flag.value {
+            Some(value) => format!("--{} {}", flag.name, value),
+            None => format!("--{}", flag.name),
+        })
+        .collect();
+    // at least two spaces between the longest flag and its help
+    let width = names
+        .iter()
+        .map(|name| name.len() + 2)
+        .max()
+        .unwrap_or(0)
+        .max(24);
+    for (flag, name) in subcommand.flags.iter().zip(names) {
+        let default = match flag.default {
+            Some(default) => format!(" [default: {}]", default),
+            None => String::new(),
+        };
+        text += &format!("    {:<width$}{}{}\n", name, flag.help, default);
+    }
+    if subcommand.flags.iter().any(|f| f.value == Some("DATASET")) || name == "convert" {
+        text += "\n";
+        text += DATASETS_HELP;
+        text += "\n";
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neural_network::config::{Loss, Task};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn error(line: &str) -> String {
+        match parse(&args(line)) {
+            Ok(_) => panic!("{:?} parsed", line),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn parses_subcommands_with_their_defaults() {
+        // no subcommand evaluates the default model
+        let Ok(Command::Eval(eval)) = parse(&[]) else {
+            panic!("not eval")
+        };
+        assert_eq!(eval.model, DEFAULT_MODEL);
+        assert_eq!(eval.top_k, vec![3, 5]);
+        assert_eq!(eval.data.storage, Storage::Memory);
+
+        let Ok(Command::Predict(predict)) = parse(&args(
+            "predict a.png --top-k=2 --index 1,4 --no-header --json",
+        )) else {
+            panic!("not predict")
+        };
+        assert_eq!(predict.images, vec!["a.png"]);
+        assert_eq!((predict.top_k, predict.indices), (2, vec![1, 4]));
+        assert!(predict.json && !predict.stdio && !predict.data.has_header);
+
+        let Ok(Command::Convert(convert)) = parse(&args("convert mnist out.gz --split test"))
+        else {
+            panic!("not convert")
+        };
+        assert_eq!(
+            (convert.input.as_str(), convert.output.as_str()),
+            ("mnist", "out.gz")
+        );
+        assert!(convert.gzip && convert.split == Split::Test);
+
+        let Ok(Command::Init(init)) = parse(&args("init --layers 8,2 --inputs 4 --force")) else {
+            panic!("not init")
+        };
+        assert_eq!(
+            (init.layers, init.inputs, init.force),
+            (vec![8, 2], 4, true)
+        );
+
+        assert!(matches!(parse(&args("serve -h")), Ok(Command::Help(_))));
+        assert!(matches!(parse(&args("help train")), Ok(Command::Help(_))));
+    }
+
+    #[test]
+    fn train_flags_override_the_config() {
+        let line = "train --train-data csv:a.csv --label-column y --missing-values fill:0 \
+                    --layers 8,1:identity --task regression --loss huber:0.5 \
+                    --optimizer adam --learning-rate 0.01 --weight-decay 0.1 --clip-norm 5 \
+                    --epochs 3 --seed 7 --model m.json --fresh --rollback --print-config";
+        let Ok(Command::Train(train)) = parse(&args(line)) else {
+            panic!("not train")
+        };
+        let config = train.config;
+        assert!(train.print_config);
+        assert_eq!(config.dataset.train, DataSpec::Csv("a.csv".to_string()));
+        assert_eq!(config.dataset.label_column, "y");
+        assert_eq!(config.dataset.missing_values, MissingValues::Fill(0.0));
+        assert!(config.dataset.has_header);
+        assert_eq!(config.layers.len(), 2);
+        assert_eq!(config.task, Task::Regression);
+        assert_eq!(config.loss, Loss::Huber { delta: 0.5 });
+        let Optimizer::Adam {
+            learning_rate,
+            weight_decay,
+            ..
+        } = config.optimizer
+        else {
+            panic!("not adam")
+        };
+        assert_eq!((learning_rate, weight_decay), (0.01, 0.1));
+        assert_eq!(config.gradient_clipping.norm, Some(5.0));
+        assert_eq!((config.epochs, config.seed), (3, Some(7)));
+        assert_eq!(config.checkpoint.model, "m.json");
+        assert!(!config.checkpoint.resume);
+        assert_eq!(config.on_non_finite, NonFiniteAction::Rollback);
+
+        // flags that aren't given leave the config as it is
+        let Ok(Command::Train(train)) = parse(&args("train")) else {
+            panic!("not train")
+        };
+        assert_eq!(train.config.epochs, TrainingConfig::default().epochs);
+
+        assert!(error("train --weight-decay 0.1").contains("adam"));
+        assert!(error("train --optimizer rmsprop").contains("rmsprop"));
+        assert!(error("train --clip-value -1").contains("-1"));
+    }
+
+    #[test]
+    fn reports_bad_arguments() {
+        assert_eq!(error("eval --frobnicate"), "Unknown flag --frobnicate");
+        assert_eq!(error("eval --top-k"), "--top-k needs a value");
+        assert_eq!(error("train --fresh=yes"), "--fresh doesn't take a value");
+        assert_eq!(error("eval --top-k 3,x"), "Invalid value \"x\" in --top-k");
+        assert_eq!(
+            error("train --epochs ten"),
+            "Invalid value \"ten\" for --epochs"
+        );
+        assert_eq!(error("eval extra"), "Unexpected argument \"extra\"");
+        assert!(error("fit").starts_with("Unknown command \"fit\""));
+        assert!(error("convert mnist").contains("INPUT and an OUTPUT"));
+        assert!(error("eval --test-data nope").contains("nope"));
+        assert!(error("eval --missing-values skip").contains("skip"));
+    }
+
+    #[test]
+    fn help_separates_every_flag_from_its_text() {
+        for subcommand in SUBCOMMANDS.iter() {
+            let help = subcommand_help(subcommand.name).unwrap();
+            for flag in subcommand.flags.iter() {
+                let line = help
+                    .lines()
+                    .find(|line| line.trim_start().starts_with(&format!("--{} ", flag.name)))
+                    .unwrap_or_else(|| panic!("No help for --{}", flag.name));
+                let name = match flag.value {
+                    Some(value) => format!("--{} {}", flag.name, value),
+                    None => format!("--{}", flag.name),
+                };
+                assert!(line.contains(&format!("{}  ", name)), "{}", line);
+            }
+        }
+    }
+}
@@ -0,0 +1,939 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use neural_network::augment::{AugmentedDataset, Augmenter};
+use neural_network::config::{
+    config_path_for, Activation, DataSpec, LayerConfig, LayerKind, NonFiniteAction, Regularization,
+    Storage, Task, TrainingConfig,
+};
+use neural_network::dataset::Dataset;
+use neural_network::datasets::Split;
+use neural_network::evaluation::{evaluate, evaluate_regression};
+use neural_network::helpers::argmax;
+use neural_network::idx::{IdxTensor, IdxValues};
+use neural_network::image::{DigitOptions, GrayImage};
+use neural_network::image_folder::{image_files, load_image_folder, ImageFolderOptions};
+use neural_network::layers::Shape;
+use neural_network::misclassified::{export_misclassified, find_misclassified};
+use neural_network::network::{Network, NetworkData, NonFinite};
+use neural_network::prediction::ClassProbability;
+use neural_network::preprocessing::{Preprocessing, Preprocessor, TargetStandardization};
+use neural_network::server::serve as serve_model;
+use neural_network::tabular::{load_csv, Column, CsvEncoding, CsvOptions, LabelKind};
+
+use crate::cli::{
+    ConvertArgs, DataArgs, EvalArgs, InitArgs, InspectArgs, PredictArgs, ServeArgs, TrainArgs,
+};
+
+// only used for models that are loaded to be evaluated, where it doesn't matter
+const DEFAULT_LEARNING_RATE: f64 = 0.03;
+
+type CommandResult = Result<(), Box<dyn Error>>;
+
+// a dataset and the encoding of its CSV columns, for CSV files only
+type OpenedDataset = (Box<dyn Dataset + Send>, Option<CsvEncoding>);
+
+pub fn train(args: TrainArgs) -> CommandResult {
+    let mut config = *args.config;
+    config.validate()?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let data_args = DataArgs {
+        data_dir: config.dataset.data_dir.clone(),
+        storage: config.dataset.storage,
+        label_column: config.dataset.label_column.clone(),
+        feature_columns: config.dataset.feature_columns.clone(),
+        categorical_columns: config.dataset.categorical_columns.clone(),
+        missing_values: config.dataset.missing_values,
+        has_header: config.dataset.has_header,
+    };
+    let task = config.task;
+
+    // the seed is always recorded so the run can be reproduced from the saved config
+    // TOML integers are signed, so random seeds are kept below i64::MAX
+    let seed = *config
+        .seed
+        .get_or_insert_with(|| rand::random::<u64>() >> 1);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let resume = config.checkpoint.resume && Path::new(&config.checkpoint.model).exists();
+    let existing = if resume {
+        let network = load_model(&config.checkpoint.model, config.learning_rate(0))?;
+        if network.task() != task {
+            return Err(format!(
+                "{} is a {} model, it can't be trained for {}",
+                config.checkpoint.model,
+                network.task().name(),
+                task.name()
+            )
+            .into());
+        }
+        Some(network)
+    } else {
+        None
+    };
+
+    // CSV files are read the way the resumed network's was, or the way the training file is
+    let encoding = existing.as_ref().and_then(|n| n.csv_encoding().cloned());
+    let (training_data, fitted) = open_dataset(
+        &config.dataset.train,
+        Split::Train,
+        &data_args,
+        task,
+        encoding.as_ref(),
+    )?;
+    let encoding = encoding.or(fitted);
+    let (accuracy_data, _) = open_dataset(
+        &config.dataset.test,
+        Split::Test,
+        &data_args,
+        task,
+        encoding.as_ref(),
+    )?;
+
+    match &existing {
+        Some(network) => config.layers = layer_configs(network),
+        None if config.layers.is_empty() => {
+            config.layers = [16, 16, training_data.num_outputs()]
+                .into_iter()
+                .map(|size| LayerConfig::dense(size, Activation::Sigmoid))
+                .collect();
+            if task == Task::Regression {
+                config.layers[2].activation = Some(Activation::Identity);
+            }
+        }
+        None => {}
+    }
+
+    let mut network = match existing {
+        Some(network) => {
+            println!("Continuing training of {}.", config.checkpoint.model);
+            let mut network = network;
+            if network.class_names().is_empty()
+                && network.output_size() == training_data.num_classes()
+            {
+                network.set_class_names(training_data.class_names())?;
+            }
+            network
+        }
+        None => {
+            let mut network = new_network(&config, &training_data, &mut rng)?;
+            network.set_csv_encoding(encoding);
+            network
+        }
+    };
+    network.set_optimizer(config.optimizer.clone());
+    network.set_gradient_clipping(config.gradient_clipping);
+    network.set_loss(config.loss);
+    network.validate(&training_data)?;
+    network.validate(&accuracy_data)?;
+    let training_data: Box<dyn Dataset + Send> = if config.augmentation.is_empty() {
+        training_data
+    } else {
+        let side = (training_data.num_inputs() as f64).sqrt() as usize;
+        if side * side != training_data.num_inputs() {
+            return Err("Augmentation needs square images".into());
+        }
+        let augmenter = Augmenter::new(side, side, config.augmentation.clone());
+        Box::new(AugmentedDataset::new(training_data, augmenter, rng.gen()))
+    };
+    network.set_seed(rng.gen());
+
+    let mut log = match &config.logging.file {
+        Some(path) => Some(open_log(path, task)?),
+        None => None,
+    };
+    // higher is better, the number of correct samples or the negated RMSE
+    let mut before = match task {
+        Task::Classification => {
+            let correct = evaluate(&network, &accuracy_data, &[]).correct;
+            println!(
+                "Before accuracy: {} out of {}",
+                correct,
+                accuracy_data.len()
+            );
+            correct as f64
+        }
+        Task::Regression => {
+            let rmse = evaluate_regression(&network, &accuracy_data).rmse;
+            println!("Before RMSE: {:.5}", rmse);
+            -rmse
+        }
+    };
+
+    // halved on every rollback
+    let mut learning_rate_scale = 1.0;
+    for epoch in 0..config.epochs {
+        let learning_rate = config.learning_rate(epoch) * learning_rate_scale;
+        network.set_learning_rate(learning_rate);
+        println!(
+            "Starting epoch {} with learning rate {}.",
+            epoch + 1,
+            learning_rate
+        );
+        let snapshot = match config.on_non_finite {
+            NonFiniteAction::Abort => None,
+            NonFiniteAction::Rollback => Some(network.snapshot()),
+        };
+        if let Err(e) = network.train(&training_data, config.batch_size, 1) {
+            let e = NonFinite {
+                epoch: epoch + 1,
+                ..e
+            };
+            match snapshot {
+                None => return Err(format!("{}, stopping without saving", e).into()),
+                Some(snapshot) => {
+                    println!(
+                        "{}, rolling back to the start of epoch {} and halving the learning rate.",
+                        e,
+                        epoch + 1
+                    );
+                    network.restore(snapshot)?;
+                    learning_rate_scale /= 2.0;
+                    continue;
+                }
+            }
+        }
+
+        let (after, log_fields) = match task {
+            Task::Classification => {
+                let evaluation = evaluate(&network, &accuracy_data, &[]);
+                println!(
+                    "After accuracy: {} out of {}",
+                    evaluation.correct,
+                    accuracy_data.len()
+                );
+                if evaluation.regularization != 0.0 {
+                    println!(
+                        "Loss: {:.5} ({:.5} error + {:.5} regularization)",
+                        evaluation.total_loss(),
+                        evaluation.mean_loss,
+                        evaluation.regularization
+                    );
+                } else {
+                    println!("Loss: {:.5}", evaluation.mean_loss);
+                }
+                let fields = format!("{},{}", evaluation.correct, accuracy_data.len());
+                (evaluation.correct as f64, fields)
+            }
+            Task::Regression => {
+                let evaluation = evaluate_regression(&network, &accuracy_data);
+                println!(
+                    "After RMSE: {:.5}, MAE: {:.5}, R²: {:.4}",
+                    evaluation.rmse, evaluation.mae, evaluation.r2
+                );
+                if evaluation.regularization != 0.0 {
+                    println!("Regularization: {:.5}", evaluation.regularization);
+                }
+                let fields = format!("{},{},{}", evaluation.rmse, evaluation.mae, evaluation.r2);
+                (-evaluation.rmse, fields)
+            }
+        };
+        if let Some(log) = log.as_mut() {
+            writeln!(log, "{},{},{}", epoch + 1, learning_rate, log_fields)?;
+        }
+
+        if after > before {
+            println!("Model improved, saving to file.");
+            save_model(&network, config.output())?;
+            config.save(config_path_for(config.output()))?;
+            before = after;
+        } else {
+            let metric = match task {
+                Task::Classification => "Accuracy",
+                Task::Regression => "RMSE",
+            };
+            if config.checkpoint.stop_early {
+                println!("{} didn't improve, stopping early without saving.", metric);
+                break;
+            }
+            println!("{} didn't improve, not saving to file.", metric);
+        }
+    }
+    Ok(())
+}
+
+pub fn eval(args: EvalArgs) -> CommandResult {
+    let network = load_model(&args.model, DEFAULT_LEARNING_RATE)?;
+    let (accuracy_data, _) = open_dataset(
+        &args.test_data,
+        Split::Test,
+        &args.data,
+        network.task(),
+        network.csv_encoding(),
+    )?;
+    network.validate(&accuracy_data)?;
+    if network.task() == Task::Regression {
+        return eval_regression(&network, &accuracy_data, &args);
+    }
+
+    let evaluation = evaluate(&network, &accuracy_data, &args.top_k);
+    print!("{}", evaluation);
+    if let Some(path) = args.json {
+        create_parent_dir(&path)?;
+        std::fs::write(&path, serde_json::to_string_pretty(&evaluation)?)?;
+        println!("\nWrote {}", path);
+    }
+    if let Some(dir) = args.misclassified {
+        let misclassified = find_misclassified(&network, &accuracy_data);
+        export_misclassified(
+            &dir,
+            &misclassified,
+            &accuracy_data.class_names(),
+            &args.export,
+        )?;
+        println!(
+            "Wrote {} misclassified samples to {}",
+            misclassified.len(),
+            dir
+        );
+    }
+    Ok(())
+}
+
+// top-k and misclassified samples don't apply to regression
+fn eval_regression<D: Dataset + ?Sized>(
+    network: &Network,
+    data: &D,
+    args: &EvalArgs,
+) -> CommandResult {
+    if args.misclassified.is_some() {
+        return Err("--misclassified needs a classification model".into());
+    }
+    let evaluation = evaluate_regression(network, data);
+    print!("{}", evaluation);
+    if let Some(path) = &args.json {
+        create_parent_dir(path)?;
+        std::fs::write(path, serde_json::to_string_pretty(&evaluation)?)?;
+        println!("\nWrote {}", path);
+    }
+    Ok(())
+}
+
+pub fn predict(args: PredictArgs) -> CommandResult {
+    let network = load_model(&args.model, DEFAULT_LEARNING_RATE)?;
+    if args.stdio {
+        return predict_stdio(&network, &args);
+    }
+    if !args.images.is_empty() {
+        return predict_images(&network, &args);
+    }
+    let (data, _) = open_dataset(
+        &args.test_data,
+        Split::Test,
+        &args.data,
+        network.task(),
+        network.csv_encoding(),
+    )?;
+    network.validate(&data)?;
+    if network.task() == Task::Regression {
+        return predict_regression(&network, &data, &args);
+    }
+
+    let class_names = data.class_names();
+    let mut results: Vec<serde_json::Value> = Vec::new();
+    for index in args.indices {
+        if index >= data.len() {
+            return Err(
+                format!("Index {} is out of range for {} samples", index, data.len()).into(),
+            );
+        }
+        let sample = data.get(index);
+        let actual = class_names
+            .get(sample.classification)
+            .cloned()
+            .unwrap_or_else(|| sample.classification.to_string());
+        let predictions = network.predict_top_k(sample.inputs, args.top_k);
+        if args.json {
+            results.push(json!({
+                "index": index,
+                "actual": actual,
+                "predictions": predictions,
+            }));
+        } else {
+            println!(
+                "Sample {}: predicted {}, actual {}",
+                index,
+                describe(&predictions),
+                actual
+            );
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    Ok(())
+}
+
+fn predict_regression<D: Dataset + ?Sized>(
+    network: &Network,
+    data: &D,
+    args: &PredictArgs,
+) -> CommandResult {
+    let mut results: Vec<serde_json::Value> = Vec::new();
+    for index in args.indices.iter().copied() {
+        if index >= data.len() {
+            return Err(
+                format!("Index {} is out of range for {} samples", index, data.len()).into(),
+            );
+        }
+        let sample = data.get(index);
+        let outputs = network.feed_forward(sample.inputs);
+        if args.json {
+            results.push(json!({
+                "index": index,
+                "actual": sample.target,
+                "outputs": outputs,
+            }));
+        } else {
+            let values: Vec<String> = outputs
+                .iter()
+                .zip(sample.target.iter())
+                .enumerate()
+                .map(|(j, (output, target))| {
+                    format!("{} {:.5} (actual {:.5})", network.label(j), output, target)
+                })
+                .collect();
+            println!("Sample {}: {}", index, values.join(", "));
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    Ok(())
+}
+
+fn predict_images(network: &Network, args: &PredictArgs) -> CommandResult {
+    let side = (network.input_size() as f64).sqrt() as usize;
+    if side * side != network.input_size() {
+        return Err(format!(
+            "Network takes {} inputs, which isn't a square image",
+            network.input_size()
+        )
+        .into());
+    }
+    let options = DigitOptions {
+        size: side,
+        ..args.digit.clone()
+    };
+    let mut files: Vec<PathBuf> = Vec::new();
+    for path in args.images.iter() {
+        if Path::new(path).is_dir() {
+            files.extend(image_files(path)?);
+        } else {
+            files.push(PathBuf::from(path));
+        }
+    }
+
+    let mut results: Vec<serde_json::Value> = Vec::new();
+    for file in files {
+        let inputs = GrayImage::open(&file)?.to_digit(&options).to_inputs();
+        let predictions = network.predict_top_k(inputs, args.top_k);
+        if args.json {
+            results.push(json!({
+                "file": file.display().to_string(),
+                "predictions": predictions,
+            }));
+        } else {
+            println!("{}: {}", file.display(), describe(&predictions));
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StdioRequest {
+    #[serde(default)]
+    id: serde_json::Value,
+    inputs: Option<Vec<f64>>,
+    image: Option<String>,
+    top_k: Option<usize>,
+}
+
+// answers line delimited JSON requests from stdin until it's closed,
+// a bad request gets an error line and doesn't stop the others
+fn predict_stdio(network: &Network, args: &PredictArgs) -> CommandResult {
+    answer_requests(
+        network,
+        args,
+        std::io::stdin().lock(),
+        std::io::stdout().lock(),
+    )
+}
+
+// answers every line of input with a line of output, errors included, until input ends
+fn answer_requests(
+    network: &Network,
+    args: &PredictArgs,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> CommandResult {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // the id is picked out first so even invalid requests can be matched to their error
+        let request: Result<serde_json::Value, String> =
+            serde_json::from_str(&line).map_err(|e| format!("Invalid JSON: {}", e));
+        let id = match &request {
+            Ok(value) => value.get("id").cloned().unwrap_or_default(),
+            Err(_) => serde_json::Value::Null,
+        };
+        let response = match request.and_then(|value| stdio_response(network, args, value)) {
+            Ok(response) => response,
+            Err(e) => json!({ "id": id, "error": e }),
+        };
+        writeln!(output, "{}", response)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn stdio_response(
+    network: &Network,
+    args: &PredictArgs,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let request: StdioRequest =
+        serde_json::from_value(request).map_err(|e| format!("Invalid request: {}", e))?;
+    let inputs = match (request.inputs, request.image) {
+        (Some(inputs), None) => {
+            if inputs.len() != network.input_size() {
+                return Err(format!(
+                    "Model takes {} inputs, got {}",
+                    network.input_size(),
+                    inputs.len()
+                ));
+            }
+            inputs
+        }
+        (None, Some(image)) => {
+            let side = (network.input_size() as f64).sqrt() as usize;
+            if side * side != network.input_size() {
+                return Err("Model doesn't take square images".to_string());
+            }
+            let options = DigitOptions {
+                size: side,
+                ..args.digit.clone()
+            };
+            GrayImage::open(&image)
+                .map_err(|e| format!("Unable to read {}: {}", image, e))?
+                .to_digit(&options)
+                .to_inputs()
+        }
+        _ => return Err("Expected either inputs or image".to_string()),
+    };
+    if network.task() == Task::Regression {
+        return Ok(json!({
+            "id": request.id,
+            "outputs": network.feed_forward(inputs),
+        }));
+    }
+    let probabilities = network.predict_proba(inputs);
+    let predicted = argmax(&probabilities);
+    Ok(json!({
+        "id": request.id,
+        "predicted": network.label(predicted),
+        "predictions": network.top_k(&probabilities, request.top_k.unwrap_or(args.top_k)),
+        "probabilities": probabilities,
+    }))
+}
+
+fn describe(predictions: &[ClassProbability]) -> String {
+    predictions
+        .iter()
+        .map(|c| format!("{} ({:.1}%)", c.label, c.probability * 100.0))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn inspect(args: InspectArgs) -> CommandResult {
+    let network = load_model(&args.model, DEFAULT_LEARNING_RATE)?;
+    println!("Model: {}", args.model);
+    println!("Inputs: {}", network.input_size());
+    if let Some(encoding) = network.csv_encoding() {
+        let categorical = encoding
+            .features
+            .iter()
+            .filter(|f| f.categories.is_some())
+            .count();
+        println!(
+            "CSV features: {}, {} of them categorical",
+            encoding.features.len(),
+            categorical
+        );
+    }
+
+    if !network.preprocessing().is_empty() {
+        println!("Preprocessing:");
+        for preprocessor in network.preprocessing() {
+            println!(
+                "    {:<16}{} -> {}",
+                preprocessor.name(),
+                preprocessor.input_size(),
+                preprocessor.output_size()
+            );
+        }
+    }
+
+    println!("Layers:");
+    let mut parameters = 0;
+    for (i, layer) in network.layers().iter().enumerate() {
+        parameters += layer.parameter_count();
+        println!(
+            "    {:<4}{}, {} parameters",
+            i + 1,
+            layer,
+            layer.parameter_count()
+        );
+    }
+    println!("Outputs: {}", network.output_size());
+    if network.task() == Task::Regression {
+        println!("Task: regression");
+        if !network.class_names().is_empty() {
+            println!("Targets: {}", network.class_names().join(", "));
+        }
+        if let Some(standardization) = network.target_standardization() {
+            println!("Target standardization:");
+            for (j, (mean, std_dev)) in standardization
+                .mean
+                .iter()
+                .zip(standardization.std_dev.iter())
+                .enumerate()
+            {
+                println!(
+                    "    {:<16}mean {:.5}, standard deviation {:.5}",
+                    network.label(j),
+                    mean,
+                    std_dev
+                );
+            }
+        }
+    } else if !network.class_names().is_empty() {
+        println!("Classes: {}", network.class_names().join(", "));
+    }
+    println!("Parameters: {}", parameters);
+    Ok(())
+}
+
+pub fn convert(args: ConvertArgs) -> CommandResult {
+    let output = |name: &str| -> String {
+        if args.gzip && !name.ends_with(".gz") {
+            format!("{}.gz", name)
+        } else {
+            name.to_string()
+        }
+    };
+
+    // a single IDX file only gets (de)compressed
+    if Path::new(&args.input).is_file() {
+        let path = output(&args.output);
+        create_parent_dir(&path)?;
+        IdxTensor::read(&args.input)?.write(&path, args.gzip)?;
+        println!("Wrote {}", path);
+        return Ok(());
+    }
+
+    let spec = args.input.parse::<DataSpec>()?;
+    let (data, _) = open_dataset(&spec, args.split, &args.data, Task::Classification, None)?;
+    if data.is_empty() {
+        return Err(format!("{} has no samples", args.input).into());
+    }
+    let size = data.num_inputs();
+    // square inputs are exported as images, anything else as plain vectors
+    let side = (size as f64).sqrt().round() as usize;
+    let mut dims = if side * side == size {
+        vec![data.len(), side, side]
+    } else {
+        vec![data.len(), size]
+    };
+    let mut pixels: Vec<u8> = Vec::with_capacity(data.len() * size);
+    let mut labels: Vec<i32> = Vec::with_capacity(data.len());
+    for sample in data.iter() {
+        pixels.extend(
+            sample
+                .inputs
+                .iter()
+                .map(|x| (x * 255.0).round().clamp(0.0, 255.0) as u8),
+        );
+        labels.push(sample.classification as i32);
+    }
+    let labels = if data.num_classes() <= 256 {
+        IdxValues::U8(labels.into_iter().map(|l| l as u8).collect())
+    } else {
+        IdxValues::I32(labels)
+    };
+
+    create_parent_dir(&args.output)?;
+    let images_path = output(&format!("{}-images-idx{}-ubyte", args.output, dims.len()));
+    let labels_path = output(&format!("{}-labels-idx1-ubyte", args.output));
+    IdxTensor::new(dims.clone(), IdxValues::U8(pixels))?.write(&images_path, args.gzip)?;
+    dims.truncate(1);
+    IdxTensor::new(dims, labels)?.write(&labels_path, args.gzip)?;
+    println!("Wrote {} and {}", images_path, labels_path);
+    Ok(())
+}
+
+pub fn serve(args: ServeArgs) -> CommandResult {
+    serve_model(&args.model, &args.options)?;
+    Ok(())
+}
+
+pub fn init(args: InitArgs) -> CommandResult {
+    if !args.force && Path::new(&args.model).exists() {
+        return Err(format!("{} already exists, use --force to overwrite it", args.model).into());
+    }
+    if args.layers.is_empty() {
+        return Err("A network needs at least one layer".into());
+    }
+    let network = Network::new(args.layers, args.inputs, args.learning_rate);
+    save_model(&network, &args.model)?;
+    println!("Wrote {}", args.model);
+    Ok(())
+}
+
+fn new_network<D: Dataset, R: Rng>(
+    config: &TrainingConfig,
+    training_data: &D,
+    rng: &mut R,
+) -> Result<Network, String> {
+    let preprocessing = fit_preprocessing(&config.preprocessing, training_data)?;
+    let inputs = preprocessing
+        .last()
+        .map_or(training_data.num_inputs(), |p| p.output_size());
+    // inputs making up a square are taken to be a grayscale image, which convolutions need
+    let side = (inputs as f64).sqrt() as usize;
+    let input = if side * side == inputs {
+        Shape::new(1, side, side)
+    } else {
+        Shape::flat(inputs)
+    };
+    let layers: Vec<LayerConfig> = config
+        .layers
+        .iter()
+        .map(|layer| with_regularization(layer, config.regularization))
+        .collect();
+    let mut network = Network::from_config(&layers, input, config.learning_rate(0), rng)?;
+    network.set_preprocessing(preprocessing);
+    // the output layer has to fit the dataset before it gets the dataset's class names
+    network.validate(training_data)?;
+    network.set_class_names(training_data.class_names())?;
+    if config.task == Task::Regression {
+        // targets can be any number, which bounded activations can't reach
+        match network.output_activation() {
+            Some(Activation::Identity) | None => {}
+            Some(activation) => {
+                return Err(format!(
+                    "Regression needs an identity output layer, not {}, as in 16,1:identity",
+                    activation.name()
+                ))
+            }
+        }
+        network.set_task(Task::Regression);
+        if config.standardize_targets {
+            network.set_target_standardization(Some(TargetStandardization::fit(training_data)));
+        }
+    }
+    Ok(network)
+}
+
+// layers with weights and without a regularization of their own get the config's
+fn with_regularization(layer: &LayerConfig, regularization: Regularization) -> LayerConfig {
+    match layer.kind {
+        LayerKind::Dense | LayerKind::Conv2d if layer.regularization.is_none() => LayerConfig {
+            regularization: Some(regularization).filter(|r| !r.is_none()),
+            ..layer.clone()
+        },
+        LayerKind::Residual => LayerConfig {
+            layers: layer
+                .layers
+                .iter()
+                .map(|layer| with_regularization(layer, regularization))
+                .collect(),
+            ..layer.clone()
+        },
+        _ => layer.clone(),
+    }
+}
+
+fn layer_configs(network: &Network) -> Vec<LayerConfig> {
+    network
+        .layers()
+        .iter()
+        .map(|layer| layer.to_config())
+        .collect()
+}
+
+// appends to an existing log, new logs start with a header
+fn open_log(path: &str, task: Task) -> Result<File, std::io::Error> {
+    create_parent_dir(path)?;
+    let is_new = std::fs::metadata(path).map_or(true, |m| m.len() == 0);
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if is_new {
+        match task {
+            Task::Classification => writeln!(file, "epoch,learning_rate,correct,total")?,
+            Task::Regression => writeln!(file, "epoch,learning_rate,rmse,mae,r2")?,
+        }
+    }
+    Ok(file)
+}
+
+fn fit_preprocessing<D: Dataset>(
+    steps: &[Preprocessing],
+    training_data: &D,
+) -> Result<Vec<Preprocessor>, String> {
+    if steps.is_empty() {
+        return Ok(Vec::new());
+    }
+    println!("Fitting preprocessing.");
+    Preprocessor::fit_all(steps, training_data)
+}
+
+pub fn load_model(path: &str, learning_rate: f64) -> Result<Network, Box<dyn Error>> {
+    let data =
+        std::fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    let data: NetworkData = serde_json::from_str(&data)?;
+    Ok(Network::from_data(data, learning_rate).map_err(|e| format!("{}: {}", path, e))?)
+}
+
+fn save_model(network: &Network, path: &str) -> Result<(), Box<dyn Error>> {
+    create_parent_dir(path)?;
+    let json = serde_json::to_string(&network.output_data())?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+fn create_parent_dir(path: &str) -> Result<(), std::io::Error> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+// CSV labels are classes when classifying and numeric targets for regression,
+// CSV files are read with the encoding when there is one, otherwise with the one fitted
+// on them, which is returned along with the dataset
+fn open_dataset(
+    spec: &DataSpec,
+    split: Split,
+    args: &DataArgs,
+    task: Task,
+    encoding: Option<&CsvEncoding>,
+) -> Result<OpenedDataset, Box<dyn Error>> {
+    let dataset: Result<OpenedDataset, std::io::Error> = match spec {
+        DataSpec::Preset(preset) => match args.storage {
+            Storage::Memory => preset
+                .open(&args.data_dir, split)
+                .map(|d| (Box::new(d) as _, None)),
+            Storage::Mmap => preset
+                .open_mmap(&args.data_dir, split)
+                .map(|d| (Box::new(d) as _, None)),
+            Storage::Stream => preset
+                .open_streaming(&args.data_dir, split)
+                .map(|d| (Box::new(d) as _, None)),
+        },
+        DataSpec::Csv(path) => {
+            let columns = |columns: &str| -> Vec<Column> {
+                columns
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|column| !column.is_empty())
+                    .map(|column| match column.parse() {
+                        Ok(index) => Column::Index(index),
+                        Err(_) => Column::Name(column.to_string()),
+                    })
+                    .collect()
+            };
+            let mut options = CsvOptions::for_file(path, Column::Index(0));
+            options.label_columns = columns(&args.label_column);
+            if task == Task::Regression {
+                options.label_kind = LabelKind::Numeric;
+            }
+            if !args.feature_columns.trim().is_empty() {
+                options.feature_columns = Some(columns(&args.feature_columns));
+            }
+            options.categorical_columns = columns(&args.categorical_columns);
+            options.missing_values = args.missing_values;
+            options.has_header = args.has_header;
+            options.encoding = encoding.cloned();
+            load_csv(path, &options).map(|(d, encoding)| (Box::new(d) as _, Some(encoding)))
+        }
+        DataSpec::Images(dir) => {
+            load_image_folder(dir, &ImageFolderOptions::default()).map(|d| (Box::new(d) as _, None))
+        }
+    };
+    let name = match spec {
+        DataSpec::Preset(preset) => format!("{} from {}", preset.name(), args.data_dir),
+        DataSpec::Csv(path) | DataSpec::Images(path) => path.clone(),
+    };
+    Ok(dataset.map_err(|e| format!("Unable to load {}: {}", name, e))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{parse, Command};
+
+    #[test]
+    fn answers_every_stdio_request() {
+        let mut network = Network::with_rng(vec![3, 2], 4, 0.1, &mut StdRng::seed_from_u64(1));
+        network
+            .set_class_names(vec!["even".to_string(), "odd".to_string()])
+            .unwrap();
+        let Ok(Command::Predict(args)) = parse(&["predict".to_string(), "--stdio".to_string()])
+        else {
+            panic!("not predict")
+        };
+        let input = [
+            r#"{"id": 1, "inputs": [0.1, 0.2, 0.3, 0.4], "top_k": 1}"#,
+            r#"{"id": 2, "inputs": [0.1, 0.2"#,
+            "",
+            r#"{"id": 3, "inputs": [0.1, 0.2]}"#,
+            r#"{"id": 4}"#,
+            r#"{"id": 5, "inputs": [0.4, 0.3, 0.2, 0.1]}"#,
+        ]
+        .join("\n");
+        let mut output: Vec<u8> = Vec::new();
+        answer_requests(&network, &args, input.as_bytes(), &mut output).unwrap();
+        let responses: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 5);
+
+        let first = &responses[0];
+        assert_eq!(first["id"], 1);
+        assert_eq!(first["predictions"].as_array().unwrap().len(), 1);
+        let probabilities = network.predict_proba(vec![0.1, 0.2, 0.3, 0.4]);
+        assert_eq!(first["probabilities"], json!(probabilities));
+        assert_eq!(first["predicted"], network.label(argmax(&probabilities)));
+
+        // the malformed line can't be matched to its id, the others can
+        assert_eq!(responses[1]["id"], serde_json::Value::Null);
+        assert!(responses[1]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid JSON"));
+        assert_eq!(responses[2]["id"], 3);
+        assert_eq!(responses[2]["error"], "Model takes 4 inputs, got 2");
+        assert_eq!(responses[3]["id"], 4);
+        assert_eq!(responses[3]["error"], "Expected either inputs or image");
+
+        assert_eq!(responses[4]["id"], 5);
+        assert_eq!(responses[4]["predictions"].as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,834 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::augment::Augmentation;
+use crate::datasets::DatasetPreset;
+use crate::idx::invalid_data;
+use crate::layers::Shape;
+pub use crate::layers::{Activation, Regularization};
+use crate::preprocessing::Preprocessing;
+use crate::tabular::MissingValues;
+
+// everything a training run depends on, read from a TOML or JSON file
+// fields left out of the file keep their defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainingConfig {
+    pub dataset: DatasetConfig,
+    pub preprocessing: Vec<Preprocessing>,
+    pub augmentation: Vec<Augmentation>,
+    // hidden and output layers of a new network,
+    // when empty it gets two hidden layers of 16 and one output per class
+    pub layers: Vec<LayerConfig>,
+    // of every dense and convolution layer of a new network without one of its own
+    pub regularization: Regularization,
+    // what the network learns, classes or continuous targets
+    pub task: Task,
+    pub loss: Loss,
+    // regression only, trains on targets with zero mean and unit variance,
+    // the fitted mean and standard deviation are stored with the model
+    pub standardize_targets: bool,
+    pub optimizer: Optimizer,
+    pub gradient_clipping: GradientClipping,
+    // what happens when training comes across a NaN or infinite value
+    pub on_non_finite: NonFiniteAction,
+    pub schedule: Schedule,
+    pub batch_size: usize,
+    pub epochs: usize,
+    // seeds the weight initialization and augmentation, random when not set
+    pub seed: Option<u64>,
+    pub checkpoint: CheckpointConfig,
+    pub logging: LoggingConfig,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        TrainingConfig {
+            dataset: DatasetConfig::default(),
+            preprocessing: Vec::new(),
+            augmentation: Vec::new(),
+            layers: Vec::new(),
+            regularization: Regularization::default(),
+            task: Task::Classification,
+            loss: Loss::MeanSquaredError,
+            standardize_targets: true,
+            optimizer: Optimizer::Sgd {
+                learning_rate: 0.03,
+            },
+            gradient_clipping: GradientClipping::default(),
+            on_non_finite: NonFiniteAction::Abort,
+            schedule: Schedule::Constant,
+            batch_size: 5,
+            epochs: 10,
+            seed: None,
+            checkpoint: CheckpointConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl TrainingConfig {
+    // the format is picked by the extension, .json for JSON and TOML otherwise
+    pub fn load(path: impl AsRef<Path>) -> Result<TrainingConfig, std::io::Error> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let config = if is_json(path) {
+            serde_json::from_str(&contents).map_err(|e| invalid_data(e.to_string()))
+        } else {
+            toml::from_str(&contents).map_err(|e| invalid_data(e.to_string()))
+        };
+        config.map_err(|e| invalid_data(format!("Invalid config {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let contents = if is_json(path.as_ref()) {
+            serde_json::to_string_pretty(self).map_err(|e| invalid_data(e.to_string()))?
+        } else {
+            self.to_toml()?
+        };
+        std::fs::write(path, contents)
+    }
+
+    pub fn to_toml(&self) -> Result<String, std::io::Error> {
+        toml::to_string(self).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // the config gets saved with the model, and TOML integers are signed
+        if let Some(seed) = self.seed.filter(|seed| *seed > i64::MAX as u64) {
+            return Err(format!(
+                "Seed {} is above the maximum of {}",
+                seed,
+                i64::MAX
+            ));
+        }
+        for augmentation in self.augmentation.iter() {
+            augmentation.validate()?;
+        }
+        self.gradient_clipping.validate()?;
+        self.loss.validate()?;
+        Ok(())
+    }
+
+    pub fn learning_rate(&self, epoch: usize) -> f64 {
+        self.schedule
+            .learning_rate(self.optimizer.learning_rate(), epoch)
+    }
+
+    // where the model gets written to
+    pub fn output(&self) -> &str {
+        self.checkpoint
+            .output
+            .as_deref()
+            .unwrap_or(&self.checkpoint.model)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetConfig {
+    pub train: DataSpec,
+    pub test: DataSpec,
+    // directory holding the IDX files of presets
+    pub data_dir: String,
+    pub storage: Storage,
+    // label column of CSV datasets, by header name or 0-based index,
+    // regression takes several comma separated target columns
+    pub label_column: String,
+    // comma separated feature columns of CSV datasets, every other column when empty
+    pub feature_columns: String,
+    // comma separated feature columns holding categories, which get one-hot encoded
+    pub categorical_columns: String,
+    pub missing_values: MissingValues,
+    pub has_header: bool,
+}
+
+impl Default for DatasetConfig {
+    fn default() -> Self {
+        DatasetConfig {
+            train: DataSpec::Preset(DatasetPreset::Mnist),
+            test: DataSpec::Preset(DatasetPreset::Mnist),
+            data_dir: "mnist".to_string(),
+            storage: Storage::Memory,
+            label_column: "label".to_string(),
+            feature_columns: String::new(),
+            categorical_columns: String::new(),
+            missing_values: MissingValues::Error,
+            has_header: true,
+        }
+    }
+}
+
+// where samples come from, written as a preset name, csv:PATH or images:DIR
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DataSpec {
+    Preset(DatasetPreset),
+    Csv(String),
+    Images(String),
+}
+
+impl FromStr for DataSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<DataSpec, String> {
+        if let Some(path) = spec.strip_prefix("csv:") {
+            Ok(DataSpec::Csv(path.to_string()))
+        } else if let Some(dir) = spec.strip_prefix("images:") {
+            Ok(DataSpec::Images(dir.to_string()))
+        } else {
+            DatasetPreset::from_name(spec)
+                .map(DataSpec::Preset)
+                .ok_or_else(|| format!("Unknown dataset {:?}", spec))
+        }
+    }
+}
+
+impl TryFrom<String> for DataSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<DataSpec, String> {
+        spec.parse()
+    }
+}
+
+impl From<DataSpec> for String {
+    fn from(spec: DataSpec) -> String {
+        spec.to_string()
+    }
+}
+
+impl fmt::Display for DataSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataSpec::Preset(preset) => write!(f, "{}", preset.name()),
+            DataSpec::Csv(path) => write!(f, "csv:{}", path),
+            DataSpec::Images(dir) => write!(f, "images:{}", dir),
+        }
+    }
+}
+
+// how presets are read, mmap and stream need the extracted images file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Storage {
+    Memory,
+    Mmap,
+    Stream,
+}
+
+impl FromStr for Storage {
+    type Err = String;
+
+    fn from_str(storage: &str) -> Result<Storage, String> {
+        match storage {
+            "memory" => Ok(Storage::Memory),
+            "mmap" => Ok(Storage::Mmap),
+            "stream" => Ok(Storage::Stream),
+            other => Err(format!("Unknown storage mode {:?}", other)),
+        }
+    }
+}
+
+// a layer of a new network, dense unless type says otherwise
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerConfig {
+    #[serde(rename = "type", default, skip_serializing_if = "LayerKind::is_dense")]
+    pub kind: LayerKind,
+    // outputs of a dense layer, filters of a convolution, window of a pooling layer
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: usize,
+    // of dense layers and convolutions, sigmoid when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activation: Option<Activation>,
+    // convolutions only, defaulting to 3, 1 and 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_size: Option<usize>,
+    // of convolutions and pooling layers, pooling defaults to the window size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stride: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<usize>,
+    // output of a reshape layer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shape: Option<Shape>,
+    // fraction of the inputs a dropout layer zeroes while training
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+    // weight of every batch in the running statistics of batch normalization, 0.1 when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub momentum: Option<f64>,
+    // of dense layers and convolutions, the config's regularization when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regularization: Option<Regularization>,
+    // layers of a residual block
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<LayerConfig>,
+}
+
+impl LayerConfig {
+    pub fn dense(size: usize, activation: Activation) -> Self {
+        LayerConfig {
+            kind: LayerKind::Dense,
+            size,
+            activation: Some(activation),
+            kernel_size: None,
+            stride: None,
+            padding: None,
+            shape: None,
+            rate: None,
+            momentum: None,
+            regularization: None,
+            layers: Vec::new(),
+        }
+    }
+
+    // a layer of the kind with nothing else set
+    pub fn empty(kind: LayerKind) -> Self {
+        LayerConfig {
+            kind,
+            activation: None,
+            ..LayerConfig::dense(0, Activation::default())
+        }
+    }
+}
+
+// a dense layer is written as its size, other layers as
+//   conv:FILTERS:KERNEL[:STRIDE[:PADDING]]
+//   max_pool2d:SIZE[:STRIDE], avg_pool2d:SIZE[:STRIDE]
+//   global_avg_pool, flatten, reshape:CxHxW, dropout:RATE, batch_norm[:MOMENTUM], layer_norm
+//   residual:LAYER/LAYER/..., a block of layers written as above whose inputs are added to
+//   its outputs
+// dense layers and convolutions can end in :ACTIVATION, as in 128:relu
+impl FromStr for LayerConfig {
+    type Err = String;
+
+    fn from_str(layer: &str) -> Result<LayerConfig, String> {
+        if let Some(layers) = layer.strip_prefix("residual:") {
+            return Ok(LayerConfig {
+                layers: layers
+                    .split('/')
+                    .map(|layer| layer.parse())
+                    .collect::<Result<Vec<LayerConfig>, String>>()?,
+                ..LayerConfig::empty(LayerKind::Residual)
+            });
+        }
+        let (spec, activation) = match layer.rsplit_once(':') {
+            Some((spec, name)) if name.parse::<Activation>().is_ok() => (spec, name.parse().ok()),
+            _ => (layer, None),
+        };
+        let mut config = parse_layer(layer, spec)?;
+        if activation.is_some() {
+            if !matches!(config.kind, LayerKind::Dense | LayerKind::Conv2d) {
+                return Err(format!("Invalid layer {:?}, it has no activation", layer));
+            }
+            config.activation = activation;
+        }
+        Ok(config)
+    }
+}
+
+fn parse_layer(layer: &str, spec: &str) -> Result<LayerConfig, String> {
+    let (name, values) = spec.split_once(':').unwrap_or((spec, ""));
+    let invalid = || format!("Invalid layer {:?}", layer);
+    let numbers = |separator: char| {
+        values
+            .split(separator)
+            .map(|value| value.parse::<usize>().map_err(|_| invalid()))
+            .collect::<Result<Vec<usize>, String>>()
+    };
+    let config = LayerConfig::empty;
+    match (name, values) {
+        ("global_avg_pool", "") => Ok(config(LayerKind::GlobalAvgPool)),
+        ("flatten", "") => Ok(config(LayerKind::Flatten)),
+        ("layer_norm", "") => Ok(config(LayerKind::LayerNorm)),
+        ("batch_norm", "") => Ok(config(LayerKind::BatchNorm)),
+        ("batch_norm", _) => match values.parse::<f64>() {
+            Ok(momentum) => Ok(LayerConfig {
+                momentum: Some(momentum),
+                ..config(LayerKind::BatchNorm)
+            }),
+            Err(_) => Err(format!(
+                "Invalid layer {:?}, expected batch_norm[:MOMENTUM]",
+                layer
+            )),
+        },
+        ("dropout", _) => match values.parse::<f64>() {
+            Ok(rate) => Ok(LayerConfig {
+                rate: Some(rate),
+                ..config(LayerKind::Dropout)
+            }),
+            Err(_) => Err(format!("Invalid layer {:?}, expected dropout:RATE", layer)),
+        },
+        ("reshape", _) => match numbers('x')?.as_slice() {
+            [channels, height, width] => Ok(LayerConfig {
+                shape: Some(Shape::new(*channels, *height, *width)),
+                ..config(LayerKind::Reshape)
+            }),
+            _ => Err(format!("Invalid layer {:?}, expected reshape:CxHxW", layer)),
+        },
+        ("conv", _) => match numbers(':')?.as_slice() {
+            [filters, kernel_size, rest @ ..] if rest.len() <= 2 => Ok(LayerConfig {
+                size: *filters,
+                activation: Some(Activation::default()),
+                kernel_size: Some(*kernel_size),
+                stride: rest.first().copied(),
+                padding: rest.get(1).copied(),
+                ..config(LayerKind::Conv2d)
+            }),
+            _ => Err(format!(
+                "Invalid layer {:?}, expected conv:FILTERS:KERNEL[:STRIDE[:PADDING]]",
+                layer
+            )),
+        },
+        ("max_pool2d" | "avg_pool2d", _) => {
+            let kind = if name == "max_pool2d" {
+                LayerKind::MaxPool2d
+            } else {
+                LayerKind::AvgPool2d
+            };
+            match numbers(':')?.as_slice() {
+                [size, rest @ ..] if rest.len() <= 1 => Ok(LayerConfig {
+                    size: *size,
+                    stride: rest.first().copied(),
+                    ..config(kind)
+                }),
+                _ => Err(format!(
+                    "Invalid layer {:?}, expected {}:SIZE[:STRIDE]",
+                    layer, name
+                )),
+            }
+        }
+        (size, "") => Ok(LayerConfig::dense(
+            size.parse().map_err(|_| invalid())?,
+            Activation::default(),
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerKind {
+    #[default]
+    Dense,
+    Conv2d,
+    MaxPool2d,
+    AvgPool2d,
+    GlobalAvgPool,
+    Flatten,
+    Reshape,
+    Dropout,
+    BatchNorm,
+    LayerNorm,
+    Residual,
+}
+
+impl LayerKind {
+    fn is_dense(&self) -> bool {
+        *self == LayerKind::Dense
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Task {
+    // one output per class, the highest one being the prediction
+    #[default]
+    Classification,
+    // one output per continuous target, with a linear output layer
+    Regression,
+}
+
+impl Task {
+    pub fn is_classification(&self) -> bool {
+        *self == Task::Classification
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Task::Classification => "classification",
+            Task::Regression => "regression",
+        }
+    }
+}
+
+impl FromStr for Task {
+    type Err = String;
+
+    fn from_str(task: &str) -> Result<Task, String> {
+        match task {
+            "classification" => Ok(Task::Classification),
+            "regression" => Ok(Task::Regression),
+            other => Err(format!("Unknown task {:?}", other)),
+        }
+    }
+}
+
+// written as a name, or as { huber = { delta = 1.0 } } for huber
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Loss {
+    #[default]
+    MeanSquaredError,
+    MeanAbsoluteError,
+    // squared below delta and absolute above it, so outliers pull less than with squares
+    Huber {
+        #[serde(default = "default_huber_delta")]
+        delta: f64,
+    },
+}
+
+impl Loss {
+    pub fn is_mean_squared_error(&self) -> bool {
+        *self == Loss::MeanSquaredError
+    }
+
+    // the gradient clamps to delta, which panics when it's negative or NaN
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Loss::Huber { delta } if !(delta > 0.0 && delta.is_finite()) => {
+                Err(format!("Huber delta {} is not a positive number", delta))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // averaged over the outputs
+    pub fn value(&self, outputs: &[f64], target: &[f64]) -> f64 {
+        let loss: f64 = outputs
+            .iter()
+            .zip(target)
+            .map(|(output, target)| {
+                let error = output - target;
+                match *self {
+                    Loss::MeanSquaredError => error * error,
+                    Loss::MeanAbsoluteError => error.abs(),
+                    Loss::Huber { delta } if error.abs() <= delta => 0.5 * error * error,
+                    Loss::Huber { delta } => delta * (error.abs() - 0.5 * delta),
+                }
+            })
+            .sum();
+        loss / outputs.len() as f64
+    }
+
+    // derivative of the loss of a single output, for the squared error that of half of it,
+    // output - target, like training has always used
+    pub fn gradient(&self, output: f64, target: f64) -> f64 {
+        let error = output - target;
+        match *self {
+            Loss::MeanSquaredError => error,
+            Loss::MeanAbsoluteError if error == 0.0 => 0.0,
+            Loss::MeanAbsoluteError => error.signum(),
+            Loss::Huber { delta } => error.clamp(-delta, delta),
+        }
+    }
+}
+
+// mse, mae or huber[:DELTA], or the names used in config files
+impl FromStr for Loss {
+    type Err = String;
+
+    fn from_str(loss: &str) -> Result<Loss, String> {
+        match loss.split_once(':') {
+            None if loss == "mse" || loss == "mean_squared_error" => Ok(Loss::MeanSquaredError),
+            None if loss == "mae" || loss == "mean_absolute_error" => Ok(Loss::MeanAbsoluteError),
+            None if loss == "huber" => Ok(Loss::Huber {
+                delta: default_huber_delta(),
+            }),
+            Some(("huber", delta)) => match delta.parse::<f64>() {
+                Ok(delta) if delta > 0.0 && delta.is_finite() => Ok(Loss::Huber { delta }),
+                _ => Err(format!("Invalid Huber delta {:?}", delta)),
+            },
+            _ => Err(format!("Unknown loss {:?}", loss)),
+        }
+    }
+}
+
+fn default_huber_delta() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Optimizer {
+    // plain gradient descent on the gradients summed over each batch
+    Sgd {
+        learning_rate: f64,
+    },
+    // steps scaled by running averages of the gradients and their squares,
+    // weight_decay shrinks the penalized parameters by learning_rate * weight_decay
+    // every step apart from the gradients (AdamW)
+    Adam {
+        learning_rate: f64,
+        #[serde(default = "default_beta1")]
+        beta1: f64,
+        #[serde(default = "default_beta2")]
+        beta2: f64,
+        #[serde(default = "default_epsilon")]
+        epsilon: f64,
+        #[serde(default)]
+        weight_decay: f64,
+    },
+}
+
+impl Optimizer {
+    // the starting learning rate, before any schedule
+    pub fn learning_rate(&self) -> f64 {
+        match *self {
+            Optimizer::Sgd { learning_rate } | Optimizer::Adam { learning_rate, .. } => {
+                learning_rate
+            }
+        }
+    }
+
+    pub fn adam(learning_rate: f64) -> Self {
+        Optimizer::Adam {
+            learning_rate,
+            beta1: default_beta1(),
+            beta2: default_beta2(),
+            epsilon: default_epsilon(),
+            weight_decay: 0.0,
+        }
+    }
+}
+
+fn default_beta1() -> f64 {
+    0.9
+}
+
+fn default_beta2() -> f64 {
+    0.999
+}
+
+fn default_epsilon() -> f64 {
+    1e-8
+}
+
+// limits on the gradients of each batch before the optimizer uses them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GradientClipping {
+    // every gradient is clamped to -value..=value
+    pub value: Option<f64>,
+    // all gradients together are scaled down to this L2 norm when above it, after value
+    pub norm: Option<f64>,
+}
+
+impl GradientClipping {
+    // clamping panics on a negative or NaN bound
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, limit) in [("value", self.value), ("norm", self.norm)] {
+            if let Some(limit) = limit.filter(|limit| !(*limit > 0.0 && limit.is_finite())) {
+                return Err(format!(
+                    "Gradient clipping {} {} is not a positive number",
+                    name, limit
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clip(&self, gradients: &mut [Vec<Vec<f64>>]) {
+        if let Some(value) = self.value {
+            for gradient in gradients.iter_mut().flatten().flatten() {
+                *gradient = gradient.clamp(-value, value);
+            }
+        }
+        if let Some(norm) = self.norm {
+            let total = gradients
+                .iter()
+                .flatten()
+                .flatten()
+                .map(|g| g * g)
+                .sum::<f64>()
+                .sqrt();
+            if total > norm {
+                let scale = norm / total;
+                for gradient in gradients.iter_mut().flatten().flatten() {
+                    *gradient *= scale;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFiniteAction {
+    // stops training, leaving the saved model as it was
+    Abort,
+    // goes back to the network from before the epoch,
+    // halves the learning rate and goes on with the next epoch
+    Rollback,
+}
+
+// learning rate over the epochs, starting at the optimizer's learning rate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Schedule {
+    Constant,
+    // multiplies the learning rate by factor every `every` epochs
+    Step { every: usize, factor: f64 },
+    // multiplies the learning rate by decay after every epoch
+    Exponential { decay: f64 },
+}
+
+impl Schedule {
+    // epoch starts at 0
+    pub fn learning_rate(&self, base: f64, epoch: usize) -> f64 {
+        match self {
+            Schedule::Constant => base,
+            Schedule::Step { every, factor } => {
+                base * factor.powi((epoch / (*every).max(1)) as i32)
+            }
+            Schedule::Exponential { decay } => base * decay.powi(epoch as i32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointConfig {
+    // model to continue training from, and to save to unless output is set
+    pub model: String,
+    pub output: Option<String>,
+    // start from the existing model file instead of a new network
+    pub resume: bool,
+    // the model is only saved when test accuracy improves,
+    // this stops training at the first epoch that doesn't
+    pub stop_early: bool,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            model: "network-data/data.json".to_string(),
+            output: None,
+            resume: true,
+            stop_early: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // CSV file getting a line with the learning rate and test accuracy of every epoch
+    pub file: Option<String>,
+}
+
+// the resolved config of a model is saved next to it, data.json gets data.config.toml
+pub fn config_path_for(model: impl AsRef<Path>) -> PathBuf {
+    model.as_ref().with_extension("config.toml")
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_seeds_toml_cant_store() {
+        let mut config = TrainingConfig {
+            seed: Some(i64::MAX as u64),
+            ..TrainingConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        assert!(config.to_toml().is_ok());
+        config.seed = Some(i64::MAX as u64 + 1);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_clipping_that_isnt_positive() {
+        let clipping = |value, norm| TrainingConfig {
+            gradient_clipping: GradientClipping { value, norm },
+            ..TrainingConfig::default()
+        };
+        assert!(clipping(None, None).validate().is_ok());
+        assert!(clipping(Some(0.5), Some(5.0)).validate().is_ok());
+        for bad in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(clipping(Some(bad), None).validate().is_err());
+            assert!(clipping(None, Some(bad)).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_huber_deltas_that_arent_positive() {
+        let huber = |delta| TrainingConfig {
+            loss: Loss::Huber { delta },
+            ..TrainingConfig::default()
+        };
+        assert!(huber(0.5).validate().is_ok());
+        for bad in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(huber(bad).validate().is_err());
+        }
+        assert!("huber:-1".parse::<Loss>().is_err());
+        assert!("huber:inf".parse::<Loss>().is_err());
+    }
+
+    #[test]
+    fn loss_gradients_match_the_values() {
+        let losses = [
+            Loss::MeanSquaredError,
+            Loss::MeanAbsoluteError,
+            Loss::Huber { delta: 0.5 },
+            Loss::Huber { delta: 2.0 },
+        ];
+        let step = 1e-6;
+        for loss in losses {
+            // the squared error gradient is that of half of it
+            let factor = if loss.is_mean_squared_error() {
+                0.5
+            } else {
+                1.0
+            };
+            for output in [-3.0, -0.7, -0.2, 0.1, 0.4, 1.3, 4.0] {
+                let target = 0.25;
+                let value = |output: f64| factor * loss.value(&[output], &[target]);
+                let numeric = (value(output + step) - value(output - step)) / (2.0 * step);
+                let analytic = loss.gradient(output, target);
+                assert!(
+                    (numeric - analytic).abs() < 1e-6,
+                    "{:?} at {}: numeric {} but gradient {}",
+                    loss,
+                    output,
+                    numeric,
+                    analytic
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn loss_values() {
+        let outputs = [1.0, -1.0, 0.5];
+        let target = [0.0, 0.0, 0.0];
+        assert_eq!(Loss::MeanSquaredError.value(&outputs, &target), 2.25 / 3.0);
+        assert_eq!(Loss::MeanAbsoluteError.value(&outputs, &target), 2.5 / 3.0);
+        // squared below delta, linear above it
+        let huber = Loss::Huber { delta: 0.5 };
+        assert_eq!(
+            huber.value(&outputs, &target),
+            (0.375 + 0.375 + 0.125) / 3.0
+        );
+        assert_eq!(Loss::MeanAbsoluteError.gradient(0.25, 0.25), 0.0);
+        assert_eq!(huber.gradient(3.0, 0.0), 0.5);
+        assert_eq!(huber.gradient(-3.0, 0.0), -0.5);
+    }
+}
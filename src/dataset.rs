@@ -0,0 +1,450 @@
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::idx::{invalid_data, IdxHeader, IdxTensor, IdxType, IdxValues};
+use crate::network::{TrainingData, TrainingSet};
+
+// random access to the samples of a dataset
+// samples are only materialized as TrainingData when they're requested,
+// so implementations are free to keep them compact, on disk, or both
+pub trait Dataset: Sync {
+    fn len(&self) -> usize;
+
+    // panics if index is out of bounds or the sample can't be read
+    fn get(&self, index: usize) -> TrainingData;
+
+    fn class_names(&self) -> Vec<String>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn num_classes(&self) -> usize {
+        self.class_names().len()
+    }
+
+    fn num_inputs(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        self.get(0).inputs.len()
+    }
+
+    // length of the target vectors, the number of classes for classification datasets
+    fn num_outputs(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        self.get(0).target.len()
+    }
+
+    fn batch(&self, range: Range<usize>) -> Vec<TrainingData> {
+        range.map(|i| self.get(i)).collect()
+    }
+
+    fn iter(&self) -> DatasetIter<'_, Self>
+    where
+        Self: Sized,
+    {
+        DatasetIter {
+            dataset: self,
+            range: 0..self.len(),
+        }
+    }
+
+    // consecutive batches of batch_size samples, the last one may be smaller
+    fn batches(&self, batch_size: usize) -> Batches<'_, Self>
+    where
+        Self: Sized,
+    {
+        if batch_size == 0 {
+            panic!("Batch size needs to be at least 1");
+        }
+        Batches {
+            dataset: self,
+            batch_size,
+            next: 0,
+        }
+    }
+}
+
+pub struct DatasetIter<'a, D: Dataset + ?Sized> {
+    dataset: &'a D,
+    range: Range<usize>,
+}
+
+impl<D: Dataset + ?Sized> Iterator for DatasetIter<'_, D> {
+    type Item = TrainingData;
+
+    fn next(&mut self) -> Option<TrainingData> {
+        self.range.next().map(|i| self.dataset.get(i))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl<D: Dataset + ?Sized> ExactSizeIterator for DatasetIter<'_, D> {}
+
+pub struct Batches<'a, D: Dataset + ?Sized> {
+    dataset: &'a D,
+    batch_size: usize,
+    next: usize,
+}
+
+impl<D: Dataset + ?Sized> Iterator for Batches<'_, D> {
+    type Item = Vec<TrainingData>;
+
+    fn next(&mut self) -> Option<Vec<TrainingData>> {
+        if self.next >= self.dataset.len() {
+            return None;
+        }
+        let end = (self.next + self.batch_size).min(self.dataset.len());
+        let batch = self.dataset.batch(self.next..end);
+        self.next = end;
+        Some(batch)
+    }
+}
+
+impl Dataset for TrainingSet {
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn get(&self, index: usize) -> TrainingData {
+        self.samples[index].clone()
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        self.class_names.clone()
+    }
+}
+
+impl<D: Dataset + ?Sized + Send> Dataset for Box<D> {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> TrainingData {
+        (**self).get(index)
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        (**self).class_names()
+    }
+
+    fn batch(&self, range: Range<usize>) -> Vec<TrainingData> {
+        (**self).batch(range)
+    }
+}
+
+// labels and the image layout shared by the IDX backed datasets
+struct IdxLabels {
+    labels: Vec<usize>,
+    class_names: Vec<String>,
+    item_size: usize,
+    // (rows, cols) of images stored column by column
+    transpose: Option<(usize, usize)>,
+}
+
+impl IdxLabels {
+    // without class names, the number of classes is derived from the largest label
+    // label_offset is subtracted from every label, e.g. EMNIST letters are labelled 1 to 26
+    fn read(
+        labels_path: impl AsRef<Path>,
+        images: &IdxHeader,
+        class_names: Option<Vec<String>>,
+        label_offset: usize,
+        transposed: bool,
+    ) -> Result<IdxLabels, std::io::Error> {
+        let label_data = IdxTensor::read(labels_path)?;
+        if label_data.len() != images.dims.first().copied().unwrap_or(0) {
+            return Err(invalid_data(format!(
+                "{} labels but {} images",
+                label_data.len(),
+                images.dims.first().copied().unwrap_or(0)
+            )));
+        }
+        if images.dtype != IdxType::U8 {
+            return Err(invalid_data(format!(
+                "Expected u8 images, got {:?}",
+                images.dtype
+            )));
+        }
+        if label_data.dtype() == IdxType::F32 || label_data.dtype() == IdxType::F64 {
+            return Err(invalid_data(format!(
+                "Expected integer labels, got {:?}",
+                label_data.dtype()
+            )));
+        }
+        let transpose = match (transposed, images.dims.as_slice()) {
+            (false, _) => None,
+            (true, [_, rows, cols]) => Some((*rows, *cols)),
+            (true, dims) => {
+                return Err(invalid_data(format!(
+                    "Only 2-D images can be transposed, got dimensions {:?}",
+                    dims
+                )))
+            }
+        };
+
+        let labels: Vec<usize> = (0..label_data.values.len())
+            .map(|i| {
+                let label = label_data.values.get_f64(i);
+                if label < label_offset as f64 {
+                    return Err(invalid_data(format!(
+                        "Label {} is below the label offset {}",
+                        label, label_offset
+                    )));
+                }
+                Ok(label as usize - label_offset)
+            })
+            .collect::<Result<_, _>>()?;
+        let class_names = class_names.unwrap_or_else(|| {
+            let num_classes = labels.iter().max().map_or(0, |max| max + 1);
+            (0..num_classes).map(|i| i.to_string()).collect()
+        });
+        if let Some(label) = labels.iter().find(|label| **label >= class_names.len()) {
+            return Err(invalid_data(format!(
+                "Label {} is out of range for {} classes",
+                label,
+                class_names.len()
+            )));
+        }
+
+        Ok(IdxLabels {
+            labels,
+            class_names,
+            item_size: images.item_size(),
+            transpose,
+        })
+    }
+
+    // converts one image to network inputs, scaled to 0..1
+    fn sample(&self, index: usize, pixels: &[u8]) -> TrainingData {
+        let inputs: Vec<f64> = match self.transpose {
+            Some((rows, cols)) => (0..self.item_size)
+                .map(|j| pixels[(j % cols) * rows + j / cols] as f64 / 255.)
+                .collect(),
+            None => pixels.iter().map(|x| *x as f64 / 255.).collect(),
+        };
+        let classification = self.labels[index];
+        let mut target = vec![0.0; self.class_names.len()];
+        target[classification] = 1.0;
+        TrainingData {
+            inputs,
+            target,
+            classification,
+        }
+    }
+}
+
+// IDX images held in memory as u8, gzipped or not
+pub struct InMemoryDataset {
+    pixels: Vec<u8>,
+    labels: IdxLabels,
+}
+
+impl InMemoryDataset {
+    pub fn load(
+        labels_path: impl AsRef<Path>,
+        images_path: impl AsRef<Path>,
+        class_names: Option<Vec<String>>,
+        label_offset: usize,
+        transposed: bool,
+    ) -> Result<InMemoryDataset, std::io::Error> {
+        let images = IdxTensor::read(images_path)?;
+        let labels = IdxLabels::read(
+            labels_path,
+            &images.header(),
+            class_names,
+            label_offset,
+            transposed,
+        )?;
+        let pixels = match images.values {
+            IdxValues::U8(pixels) => pixels,
+            _ => unreachable!("Checked when reading the labels."),
+        };
+        Ok(InMemoryDataset { pixels, labels })
+    }
+
+    pub fn to_training_set(&self) -> TrainingSet {
+        TrainingSet::new(self.iter().collect(), self.class_names())
+    }
+}
+
+impl Dataset for InMemoryDataset {
+    fn len(&self) -> usize {
+        self.labels.labels.len()
+    }
+
+    fn get(&self, index: usize) -> TrainingData {
+        let size = self.labels.item_size;
+        self.labels
+            .sample(index, &self.pixels[index * size..(index + 1) * size])
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        self.labels.class_names.clone()
+    }
+}
+
+// IDX images memory-mapped from an uncompressed file, paged in by the OS as needed
+pub struct MmapDataset {
+    mmap: Mmap,
+    data_offset: usize,
+    labels: IdxLabels,
+}
+
+impl MmapDataset {
+    pub fn open(
+        labels_path: impl AsRef<Path>,
+        images_path: impl AsRef<Path>,
+        class_names: Option<Vec<String>>,
+        label_offset: usize,
+        transposed: bool,
+    ) -> Result<MmapDataset, std::io::Error> {
+        let (file, header) = open_raw_images(images_path.as_ref())?;
+        // SAFETY: the mapping is read-only, the file is expected not to be modified while training
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < header.file_size()? {
+            return Err(invalid_data(format!(
+                "{} is truncated",
+                images_path.as_ref().display()
+            )));
+        }
+        let labels = IdxLabels::read(labels_path, &header, class_names, label_offset, transposed)?;
+        Ok(MmapDataset {
+            mmap,
+            data_offset: header.data_offset(),
+            labels,
+        })
+    }
+}
+
+impl Dataset for MmapDataset {
+    fn len(&self) -> usize {
+        self.labels.labels.len()
+    }
+
+    fn get(&self, index: usize) -> TrainingData {
+        let size = self.labels.item_size;
+        let start = self.data_offset + index * size;
+        self.labels.sample(index, &self.mmap[start..start + size])
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        self.labels.class_names.clone()
+    }
+}
+
+// IDX images read from an uncompressed file on every access, nothing but the labels is kept in memory
+pub struct StreamingDataset {
+    file: Mutex<BufReader<File>>,
+    data_offset: usize,
+    labels: IdxLabels,
+}
+
+impl StreamingDataset {
+    pub fn open(
+        labels_path: impl AsRef<Path>,
+        images_path: impl AsRef<Path>,
+        class_names: Option<Vec<String>>,
+        label_offset: usize,
+        transposed: bool,
+    ) -> Result<StreamingDataset, std::io::Error> {
+        let (file, header) = open_raw_images(images_path.as_ref())?;
+        if (file.metadata()?.len() as u128) < header.file_size()? as u128 {
+            return Err(invalid_data(format!(
+                "{} is truncated",
+                images_path.as_ref().display()
+            )));
+        }
+        let labels = IdxLabels::read(labels_path, &header, class_names, label_offset, transposed)?;
+        Ok(StreamingDataset {
+            file: Mutex::new(BufReader::new(file)),
+            data_offset: header.data_offset(),
+            labels,
+        })
+    }
+
+    fn read(&self, range: Range<usize>) -> Vec<u8> {
+        let size = self.labels.item_size;
+        let mut pixels = vec![0u8; range.len() * size];
+        let mut file = self.file.lock().expect("Mutex mess.");
+        file.seek(SeekFrom::Start(
+            (self.data_offset + range.start * size) as u64,
+        ))
+        .and_then(|_| file.read_exact(&mut pixels))
+        .expect("Unable to read images file.");
+        pixels
+    }
+}
+
+impl Dataset for StreamingDataset {
+    fn len(&self) -> usize {
+        self.labels.labels.len()
+    }
+
+    fn get(&self, index: usize) -> TrainingData {
+        self.labels.sample(index, &self.read(index..index + 1))
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        self.labels.class_names.clone()
+    }
+
+    // reads the whole batch in one go
+    fn batch(&self, range: Range<usize>) -> Vec<TrainingData> {
+        let size = self.labels.item_size;
+        let pixels = self.read(range.clone());
+        range
+            .enumerate()
+            .map(|(i, index)| self.labels.sample(index, &pixels[i * size..(i + 1) * size]))
+            .collect()
+    }
+}
+
+// gzipped files can't be read at random offsets, they need to be extracted first
+fn open_raw_images(path: &Path) -> Result<(File, IdxHeader), std::io::Error> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 2];
+    file.read_exact(&mut magic)?;
+    if magic == [0x1f, 0x8b] {
+        return Err(invalid_data(format!(
+            "{} is gzipped, extract it to read it lazily",
+            path.display()
+        )));
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let header = IdxHeader::read(&mut file)?;
+    Ok((file, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_truncated_image_files() {
+        // a header for two 28x28 images followed by only ten bytes of pixels
+        let path = std::env::temp_dir().join(format!("truncated-{}.idx3", std::process::id()));
+        let mut bytes = vec![0, 0, 8, 3, 0, 0, 0, 2, 0, 0, 0, 28, 0, 0, 0, 28];
+        bytes.extend_from_slice(&[0; 10]);
+        std::fs::write(&path, bytes).unwrap();
+        let labels = path.with_extension("missing");
+        let streaming = StreamingDataset::open(&labels, &path, None, 0, false);
+        let mapped = MmapDataset::open(&labels, &path, None, 0, false);
+        std::fs::remove_file(&path).unwrap();
+        for error in [streaming.err(), mapped.err()] {
+            assert!(error.unwrap().to_string().contains("is truncated"));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+
+use crate::dataset::{InMemoryDataset, MmapDataset, StreamingDataset};
+use crate::helpers::load_idx_data;
+use crate::network::TrainingSet;
+
+const FASHION_MNIST_CLASSES: [&str; 10] = [
+    "T-shirt/top",
+    "Trouser",
+    "Pullover",
+    "Dress",
+    "Coat",
+    "Sandal",
+    "Shirt",
+    "Sneaker",
+    "Bag",
+    "Ankle boot",
+];
+
+const KMNIST_CLASSES: [&str; 10] = ["o", "ki", "su", "tsu", "na", "ha", "ma", "ya", "re", "wo"];
+
+// lowercase letters that EMNIST balanced/bymerge keep as separate classes,
+// the others are merged with their uppercase counterpart
+const EMNIST_DISTINCT_LOWERCASE: [char; 11] =
+    ['a', 'b', 'd', 'e', 'f', 'g', 'h', 'n', 'q', 'r', 't'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    Train,
+    Test,
+}
+
+// well known IDX datasets, expected to be downloaded into a local directory
+// with their original file names, gzipped or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatasetPreset {
+    Mnist,
+    FashionMnist,
+    Kmnist,
+    EmnistBalanced,
+    EmnistByClass,
+    EmnistByMerge,
+    EmnistLetters,
+    EmnistDigits,
+    EmnistMnist,
+}
+
+impl DatasetPreset {
+    pub const ALL: [DatasetPreset; 9] = [
+        DatasetPreset::Mnist,
+        DatasetPreset::FashionMnist,
+        DatasetPreset::Kmnist,
+        DatasetPreset::EmnistBalanced,
+        DatasetPreset::EmnistByClass,
+        DatasetPreset::EmnistByMerge,
+        DatasetPreset::EmnistLetters,
+        DatasetPreset::EmnistDigits,
+        DatasetPreset::EmnistMnist,
+    ];
+
+    pub fn from_name(name: &str) -> Option<DatasetPreset> {
+        DatasetPreset::ALL
+            .into_iter()
+            .find(|preset| preset.name() == name.to_lowercase())
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DatasetPreset::Mnist => "mnist",
+            DatasetPreset::FashionMnist => "fashion-mnist",
+            DatasetPreset::Kmnist => "kmnist",
+            DatasetPreset::EmnistBalanced => "emnist-balanced",
+            DatasetPreset::EmnistByClass => "emnist-byclass",
+            DatasetPreset::EmnistByMerge => "emnist-bymerge",
+            DatasetPreset::EmnistLetters => "emnist-letters",
+            DatasetPreset::EmnistDigits => "emnist-digits",
+            DatasetPreset::EmnistMnist => "emnist-mnist",
+        }
+    }
+
+    pub fn num_classes(self) -> usize {
+        match self {
+            DatasetPreset::Mnist
+            | DatasetPreset::FashionMnist
+            | DatasetPreset::Kmnist
+            | DatasetPreset::EmnistDigits
+            | DatasetPreset::EmnistMnist => 10,
+            DatasetPreset::EmnistLetters => 26,
+            DatasetPreset::EmnistBalanced | DatasetPreset::EmnistByMerge => 47,
+            DatasetPreset::EmnistByClass => 62,
+        }
+    }
+
+    pub fn class_names(self) -> Vec<String> {
+        let digits = ('0'..='9').map(String::from);
+        let uppercase = ('A'..='Z').map(String::from);
+        match self {
+            DatasetPreset::Mnist | DatasetPreset::EmnistDigits | DatasetPreset::EmnistMnist => {
+                digits.collect()
+            }
+            DatasetPreset::FashionMnist => FASHION_MNIST_CLASSES.map(String::from).to_vec(),
+            DatasetPreset::Kmnist => KMNIST_CLASSES.map(String::from).to_vec(),
+            DatasetPreset::EmnistLetters => uppercase.collect(),
+            DatasetPreset::EmnistBalanced | DatasetPreset::EmnistByMerge => digits
+                .chain(uppercase)
+                .chain(EMNIST_DISTINCT_LOWERCASE.map(String::from))
+                .collect(),
+            DatasetPreset::EmnistByClass => digits
+                .chain(uppercase)
+                .chain(('a'..='z').map(String::from))
+                .collect(),
+        }
+    }
+
+    // EMNIST images are stored transposed compared to MNIST
+    pub fn transposed(self) -> bool {
+        !matches!(
+            self,
+            DatasetPreset::Mnist | DatasetPreset::FashionMnist | DatasetPreset::Kmnist
+        )
+    }
+
+    // EMNIST letters labels start at 1
+    pub fn label_offset(self) -> usize {
+        match self {
+            DatasetPreset::EmnistLetters => 1,
+            _ => 0,
+        }
+    }
+
+    // returns the label and image file names, without the .gz extension
+    pub fn file_names(self, split: Split) -> (String, String) {
+        let prefix = match (self.transposed(), split) {
+            (false, Split::Train) => "train".to_string(),
+            (false, Split::Test) => "t10k".to_string(),
+            (true, Split::Train) => format!("{}-train", self.name()),
+            (true, Split::Test) => format!("{}-test", self.name()),
+        };
+        (
+            format!("{}-labels-idx1-ubyte", prefix),
+            format!("{}-images-idx3-ubyte", prefix),
+        )
+    }
+
+    pub fn load(self, dir: impl AsRef<Path>, split: Split) -> Result<TrainingSet, std::io::Error> {
+        let (labels, images) = self.file_names(split);
+        load_idx_data(
+            find_file(dir.as_ref(), &labels),
+            find_file(dir.as_ref(), &images),
+            Some(self.class_names()),
+            self.label_offset(),
+            self.transposed(),
+        )
+    }
+
+    // keeps the images as bytes and converts them when they're accessed
+    pub fn open(
+        self,
+        dir: impl AsRef<Path>,
+        split: Split,
+    ) -> Result<InMemoryDataset, std::io::Error> {
+        let (labels, images) = self.file_names(split);
+        InMemoryDataset::load(
+            find_file(dir.as_ref(), &labels),
+            find_file(dir.as_ref(), &images),
+            Some(self.class_names()),
+            self.label_offset(),
+            self.transposed(),
+        )
+    }
+
+    // needs the extracted images file, the labels may stay gzipped
+    pub fn open_mmap(
+        self,
+        dir: impl AsRef<Path>,
+        split: Split,
+    ) -> Result<MmapDataset, std::io::Error> {
+        let (labels, images) = self.file_names(split);
+        MmapDataset::open(
+            find_file(dir.as_ref(), &labels),
+            find_extracted_file(dir.as_ref(), &images),
+            Some(self.class_names()),
+            self.label_offset(),
+            self.transposed(),
+        )
+    }
+
+    // needs the extracted images file, the labels may stay gzipped
+    pub fn open_streaming(
+        self,
+        dir: impl AsRef<Path>,
+        split: Split,
+    ) -> Result<StreamingDataset, std::io::Error> {
+        let (labels, images) = self.file_names(split);
+        StreamingDataset::open(
+            find_file(dir.as_ref(), &labels),
+            find_extracted_file(dir.as_ref(), &images),
+            Some(self.class_names()),
+            self.label_offset(),
+            self.transposed(),
+        )
+    }
+}
+
+// prefers the extracted file, the gzipped one only gets picked to report it needs extracting
+fn find_extracted_file(dir: &Path, name: &str) -> PathBuf {
+    let extracted = dir.join(name);
+    if extracted.exists() {
+        extracted
+    } else {
+        dir.join(format!("{}.gz", name))
+    }
+}
+
+// prefers the gzipped file as distributed, falls back to an already extracted one
+fn find_file(dir: &Path, name: &str) -> PathBuf {
+    let gzipped = dir.join(format!("{}.gz", name));
+    if gzipped.exists() {
+        gzipped
+    } else {
+        dir.join(name)
+    }
+}
@@ -0,0 +1,565 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::config::Loss;
+use crate::dataset::Dataset;
+use crate::helpers::argmax;
+use crate::network::{Network, TrainingData};
+
+// classification metrics of a network on a dataset, see RegressionEvaluation for regression
+#[derive(Debug, Clone, Serialize)]
+pub struct Evaluation {
+    pub samples: usize,
+    pub correct: usize,
+    pub accuracy: f64,
+    // mean of the loss the network was trained with, over the samples
+    pub mean_loss: f64,
+    // penalties of the network's layers, which training minimizes along with the error
+    pub regularization: f64,
+    pub top_k: Vec<TopK>,
+    pub classes: Vec<ClassMetrics>,
+    pub macro_average: Averages,
+    pub micro_average: Averages,
+    // averaged over the classes weighted by their support
+    pub weighted_average: Averages,
+    // rows are the actual classes, columns the predicted ones
+    pub confusion_matrix: Vec<Vec<usize>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopK {
+    pub k: usize,
+    pub correct: usize,
+    pub accuracy: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassMetrics {
+    pub name: String,
+    // number of samples actually belonging to the class
+    pub support: usize,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Averages {
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+// evaluates every sample of data, counting a sample as top-k correct
+// when its class is among the k highest outputs
+// there is a class for every output of the network, even those the data has no samples of,
+// named by the network, or by the data when the network has no names
+pub fn evaluate<D: Dataset + ?Sized>(network: &Network, data: &D, top_k: &[usize]) -> Evaluation {
+    let data_names = data.class_names();
+    let class_names: Vec<String> =
+        if network.class_names().is_empty() && data_names.len() == network.output_size() {
+            data_names
+        } else {
+            (0..network.output_size())
+                .map(|class| network.label(class))
+                .collect()
+        };
+    let mut evaluator = Evaluator::new(class_names, top_k, network.loss());
+    for i in 0..data.len() {
+        let sample = data.get(i);
+        let outputs = network.feed_forward(sample.inputs.clone());
+        evaluator.add(&outputs, &sample);
+    }
+    Evaluation {
+        regularization: network.penalty(),
+        ..evaluator.finish()
+    }
+}
+
+// collects outputs one sample at a time, for callers that need the outputs themselves too
+pub struct Evaluator {
+    class_names: Vec<String>,
+    top_k: Vec<usize>,
+    top_k_correct: Vec<usize>,
+    confusion_matrix: Vec<Vec<usize>>,
+    loss: Loss,
+    total_loss: f64,
+    samples: usize,
+}
+
+impl Evaluator {
+    pub fn new(class_names: Vec<String>, top_k: &[usize], loss: Loss) -> Self {
+        let num_classes = class_names.len();
+        if num_classes == 0 {
+            panic!("Evaluation needs a dataset with classes");
+        }
+        Evaluator {
+            class_names,
+            top_k: top_k.to_vec(),
+            top_k_correct: vec![0; top_k.len()],
+            confusion_matrix: vec![vec![0; num_classes]; num_classes],
+            loss,
+            total_loss: 0.0,
+            samples: 0,
+        }
+    }
+
+    pub fn add(&mut self, outputs: &[f64], sample: &TrainingData) {
+        let predicted = argmax(outputs);
+        self.confusion_matrix[sample.classification][predicted] += 1;
+        self.total_loss += self.loss.value(outputs, &sample.target);
+        self.samples += 1;
+
+        // the rank of the actual class is the number of outputs above it
+        let actual = outputs[sample.classification];
+        let rank = outputs.iter().filter(|x| **x > actual).count();
+        for (k, correct) in self.top_k.iter().zip(self.top_k_correct.iter_mut()) {
+            if rank < *k {
+                *correct += 1;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Evaluation {
+        let num_classes = self.class_names.len();
+        let ratio = |a: usize, b: usize| if b == 0 { 0.0 } else { a as f64 / b as f64 };
+        let f1 = |precision: f64, recall: f64| {
+            if precision + recall == 0.0 {
+                0.0
+            } else {
+                2.0 * precision * recall / (precision + recall)
+            }
+        };
+
+        let mut classes: Vec<ClassMetrics> = Vec::with_capacity(num_classes);
+        let (mut true_positives, mut predictions) = (0, 0);
+        for (class, name) in self.class_names.into_iter().enumerate() {
+            let correct = self.confusion_matrix[class][class];
+            let support: usize = self.confusion_matrix[class].iter().sum();
+            let predicted: usize = self.confusion_matrix.iter().map(|row| row[class]).sum();
+            true_positives += correct;
+            predictions += predicted;
+            let precision = ratio(correct, predicted);
+            let recall = ratio(correct, support);
+            classes.push(ClassMetrics {
+                name,
+                support,
+                precision,
+                recall,
+                f1: f1(precision, recall),
+            });
+        }
+
+        let average = |weight: &dyn Fn(&ClassMetrics) -> f64| {
+            let total: f64 = classes.iter().map(weight).sum();
+            let mean = |metric: fn(&ClassMetrics) -> f64| {
+                if total == 0.0 {
+                    0.0
+                } else {
+                    classes.iter().map(|c| metric(c) * weight(c)).sum::<f64>() / total
+                }
+            };
+            Averages {
+                precision: mean(|c| c.precision),
+                recall: mean(|c| c.recall),
+                f1: mean(|c| c.f1),
+            }
+        };
+        let macro_average = average(&|_| 1.0);
+        let weighted_average = average(&|c| c.support as f64);
+        // every sample is predicted as exactly one class, so false positives and
+        // false negatives both add up to the misclassified samples
+        let micro_precision = ratio(true_positives, predictions);
+        let micro_recall = ratio(true_positives, self.samples);
+        let micro_average = Averages {
+            precision: micro_precision,
+            recall: micro_recall,
+            f1: f1(micro_precision, micro_recall),
+        };
+
+        let top_k = self
+            .top_k
+            .iter()
+            .zip(self.top_k_correct.iter())
+            .map(|(k, correct)| TopK {
+                k: *k,
+                correct: *correct,
+                accuracy: ratio(*correct, self.samples),
+            })
+            .collect();
+
+        Evaluation {
+            samples: self.samples,
+            correct: true_positives,
+            accuracy: ratio(true_positives, self.samples),
+            mean_loss: if self.samples == 0 {
+                0.0
+            } else {
+                self.total_loss / self.samples as f64
+            },
+            regularization: 0.0,
+            top_k,
+            classes,
+            macro_average,
+            micro_average,
+            weighted_average,
+            confusion_matrix: self.confusion_matrix,
+        }
+    }
+}
+
+impl Evaluation {
+    pub fn total_loss(&self) -> f64 {
+        self.mean_loss + self.regularization
+    }
+}
+
+// the confusion matrix is left out of the terminal output above this many classes
+const MAX_PRINTED_CLASSES: usize = 20;
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Accuracy: {:.2}% ({} out of {})",
+            self.accuracy * 100.0,
+            self.correct,
+            self.samples
+        )?;
+        for top_k in self.top_k.iter() {
+            writeln!(
+                f,
+                "Top-{} accuracy: {:.2}% ({} out of {})",
+                top_k.k,
+                top_k.accuracy * 100.0,
+                top_k.correct,
+                self.samples
+            )?;
+        }
+        writeln!(f, "Mean loss: {:.5}", self.mean_loss)?;
+        if self.regularization != 0.0 {
+            writeln!(f, "Regularization: {:.5}", self.regularization)?;
+            writeln!(f, "Total loss: {:.5}", self.total_loss())?;
+        }
+
+        let width = self
+            .classes
+            .iter()
+            .map(|c| c.name.chars().count())
+            .chain(Some("Weighted avg".len()))
+            .max()
+            .unwrap_or(0)
+            + 2;
+        writeln!(
+            f,
+            "\n{:<width$}{:>10}{:>10}{:>10}{:>10}",
+            "Class", "Precision", "Recall", "F1", "Support"
+        )?;
+        for class in self.classes.iter() {
+            writeln!(
+                f,
+                "{:<width$}{:>10.4}{:>10.4}{:>10.4}{:>10}",
+                class.name, class.precision, class.recall, class.f1, class.support
+            )?;
+        }
+        for (name, average) in [
+            ("Macro avg", &self.macro_average),
+            ("Micro avg", &self.micro_average),
+            ("Weighted avg", &self.weighted_average),
+        ] {
+            writeln!(
+                f,
+                "{:<width$}{:>10.4}{:>10.4}{:>10.4}{:>10}",
+                name, average.precision, average.recall, average.f1, self.samples
+            )?;
+        }
+
+        if self.classes.len() > MAX_PRINTED_CLASSES {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "\nConfusion matrix, rows are actual classes, columns predicted:"
+        )?;
+        let cell = self
+            .confusion_matrix
+            .iter()
+            .flatten()
+            .map(|n| n.to_string().len())
+            .chain(self.classes.iter().map(|c| c.name.chars().count()))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let label_width = width - 2;
+        write!(f, "{:<label_width$}", "")?;
+        for class in self.classes.iter() {
+            write!(f, "{:>cell$}", class.name)?;
+        }
+        writeln!(f)?;
+        for (class, row) in self.classes.iter().zip(self.confusion_matrix.iter()) {
+            write!(f, "{:<label_width$}", class.name)?;
+            for n in row {
+                write!(f, "{:>cell$}", n)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// regression metrics of a network on a dataset, in the units of the targets
+#[derive(Debug, Clone, Serialize)]
+pub struct RegressionEvaluation {
+    pub samples: usize,
+    // over every output of every sample
+    pub rmse: f64,
+    pub mae: f64,
+    // averaged over the outputs
+    pub r2: f64,
+    pub regularization: f64,
+    pub outputs: Vec<OutputMetrics>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputMetrics {
+    pub name: String,
+    pub rmse: f64,
+    pub mae: f64,
+    // 1 minus the squared error over the variance of the targets, 0 for predicting the mean
+    pub r2: f64,
+}
+
+pub fn evaluate_regression<D: Dataset + ?Sized>(
+    network: &Network,
+    data: &D,
+) -> RegressionEvaluation {
+    let names: Vec<String> = (0..network.output_size())
+        .map(|output| network.label(output))
+        .collect();
+    let mut evaluator = RegressionEvaluator::new(names);
+    for i in 0..data.len() {
+        let sample = data.get(i);
+        let outputs = network.feed_forward(sample.inputs.clone());
+        evaluator.add(&outputs, &sample.target);
+    }
+    RegressionEvaluation {
+        regularization: network.penalty(),
+        ..evaluator.finish()
+    }
+}
+
+// sums over the samples for every output, from which the metrics follow
+pub struct RegressionEvaluator {
+    names: Vec<String>,
+    squared_errors: Vec<f64>,
+    absolute_errors: Vec<f64>,
+    targets: Vec<f64>,
+    squared_targets: Vec<f64>,
+    samples: usize,
+}
+
+impl RegressionEvaluator {
+    pub fn new(names: Vec<String>) -> Self {
+        let outputs = names.len();
+        RegressionEvaluator {
+            names,
+            squared_errors: vec![0.0; outputs],
+            absolute_errors: vec![0.0; outputs],
+            targets: vec![0.0; outputs],
+            squared_targets: vec![0.0; outputs],
+            samples: 0,
+        }
+    }
+
+    pub fn add(&mut self, outputs: &[f64], target: &[f64]) {
+        for (j, (output, target)) in outputs.iter().zip(target).enumerate() {
+            let error = output - target;
+            self.squared_errors[j] += error * error;
+            self.absolute_errors[j] += error.abs();
+            self.targets[j] += target;
+            self.squared_targets[j] += target * target;
+        }
+        self.samples += 1;
+    }
+
+    pub fn finish(self) -> RegressionEvaluation {
+        let samples = self.samples.max(1) as f64;
+        let outputs: Vec<OutputMetrics> = self
+            .names
+            .into_iter()
+            .enumerate()
+            .map(|(j, name)| {
+                let mean = self.targets[j] / samples;
+                let total = self.squared_targets[j] - samples * mean * mean;
+                OutputMetrics {
+                    name,
+                    rmse: (self.squared_errors[j] / samples).sqrt(),
+                    mae: self.absolute_errors[j] / samples,
+                    // constant targets have no variance to explain
+                    r2: if total > 0.0 {
+                        1.0 - self.squared_errors[j] / total
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+        let values = samples * outputs.len().max(1) as f64;
+        let mean = |metric: fn(&OutputMetrics) -> f64| {
+            outputs.iter().map(metric).sum::<f64>() / outputs.len().max(1) as f64
+        };
+        RegressionEvaluation {
+            samples: self.samples,
+            rmse: (self.squared_errors.iter().sum::<f64>() / values).sqrt(),
+            mae: self.absolute_errors.iter().sum::<f64>() / values,
+            r2: mean(|o| o.r2),
+            regularization: 0.0,
+            outputs,
+        }
+    }
+}
+
+impl fmt::Display for RegressionEvaluation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Samples: {}", self.samples)?;
+        writeln!(f, "RMSE: {:.5}", self.rmse)?;
+        writeln!(f, "MAE: {:.5}", self.mae)?;
+        writeln!(f, "R²: {:.4}", self.r2)?;
+        if self.regularization != 0.0 {
+            writeln!(f, "Regularization: {:.5}", self.regularization)?;
+        }
+        if self.outputs.len() < 2 {
+            return Ok(());
+        }
+
+        let width = self
+            .outputs
+            .iter()
+            .map(|o| o.name.chars().count())
+            .chain(Some("Output".len()))
+            .max()
+            .unwrap_or(0)
+            + 2;
+        writeln!(
+            f,
+            "\n{:<width$}{:>12}{:>12}{:>10}",
+            "Output", "RMSE", "MAE", "R²"
+        )?;
+        for output in self.outputs.iter() {
+            writeln!(
+                f,
+                "{:<width$}{:>12.5}{:>12.5}{:>10.4}",
+                output.name, output.rmse, output.mae, output.r2
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} instead of {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn classification_metrics_by_hand() {
+        let names = ["a", "b", "c"].map(String::from).to_vec();
+        let mut evaluator = Evaluator::new(names, &[1, 2, 3], Loss::MeanSquaredError);
+        let samples: [(usize, [f64; 3]); 7] = [
+            (0, [0.9, 0.05, 0.05]),
+            (0, [0.8, 0.15, 0.05]),
+            (0, [0.3, 0.6, 0.1]),
+            (0, [0.1, 0.2, 0.7]),
+            (1, [0.1, 0.8, 0.1]),
+            (1, [0.5, 0.4, 0.1]),
+            (2, [0.2, 0.1, 0.7]),
+        ];
+        for (class, outputs) in samples {
+            let mut target = vec![0.0; 3];
+            target[class] = 1.0;
+            let sample = TrainingData {
+                inputs: Vec::new(),
+                target,
+                classification: class,
+            };
+            evaluator.add(&outputs, &sample);
+        }
+        let evaluation = evaluator.finish();
+
+        assert_eq!(
+            evaluation.confusion_matrix,
+            vec![vec![2, 1, 1], vec![1, 1, 0], vec![0, 0, 1]]
+        );
+        assert_eq!((evaluation.correct, evaluation.samples), (4, 7));
+        // the actual class ranks 0, 0, 1, 2, 0, 1 and 0 among the outputs
+        let top_k: Vec<(usize, usize)> =
+            evaluation.top_k.iter().map(|t| (t.k, t.correct)).collect();
+        assert_eq!(top_k, vec![(1, 4), (2, 6), (3, 7)]);
+
+        // a: 2 of 3 predictions right, 2 of 4 samples found
+        // b: 1 of 2 and 1 of 2, c: 1 of 2 and 1 of 1
+        let expected = [
+            (4, 2.0 / 3.0, 0.5, 4.0 / 7.0),
+            (2, 0.5, 0.5, 0.5),
+            (1, 0.5, 1.0, 2.0 / 3.0),
+        ];
+        for (class, (support, precision, recall, f1)) in evaluation.classes.iter().zip(expected) {
+            assert_eq!(class.support, support);
+            assert_close(class.precision, precision);
+            assert_close(class.recall, recall);
+            assert_close(class.f1, f1);
+        }
+
+        let averages = [
+            (
+                &evaluation.macro_average,
+                5.0 / 9.0,
+                2.0 / 3.0,
+                73.0 / 126.0,
+            ),
+            (&evaluation.micro_average, 4.0 / 7.0, 4.0 / 7.0, 4.0 / 7.0),
+            (
+                &evaluation.weighted_average,
+                25.0 / 42.0,
+                4.0 / 7.0,
+                83.0 / 147.0,
+            ),
+        ];
+        for (average, precision, recall, f1) in averages {
+            assert_close(average.precision, precision);
+            assert_close(average.recall, recall);
+            assert_close(average.f1, f1);
+        }
+    }
+
+    #[test]
+    fn reports_the_loss_the_network_was_trained_with() {
+        let sample = TrainingData {
+            inputs: Vec::new(),
+            target: vec![0.0, 1.0],
+            classification: 1,
+        };
+        let outputs = [0.5, 0.0];
+        for loss in [
+            Loss::MeanSquaredError,
+            Loss::MeanAbsoluteError,
+            Loss::Huber { delta: 0.25 },
+        ] {
+            let names = vec!["a".to_string(), "b".to_string()];
+            let mut evaluator = Evaluator::new(names, &[1], loss);
+            evaluator.add(&outputs, &sample);
+            evaluator.add(&outputs, &sample);
+            let evaluation = evaluator.finish();
+            assert_eq!(evaluation.mean_loss, loss.value(&outputs, &sample.target));
+            assert_eq!(evaluation.correct, 0);
+        }
+    }
+}
@@ -1,12 +1,9 @@
-use byteorder::{BigEndian, ReadBytesExt};
-use flate2::read::GzDecoder;
 use rulinalg::matrix::Matrix;
-use std::{
-    fs::File,
-    io::{Cursor, Read},
-};
+use std::path::Path;
 
-use crate::network::TrainingData;
+use crate::dataset::InMemoryDataset;
+use crate::datasets::DatasetPreset;
+use crate::network::TrainingSet;
 
 pub fn sigmoid(x: f64) -> f64 {
     1.0 / (1.0 + std::f64::consts::E.powf(-x))
@@ -17,101 +14,245 @@ pub fn d_sigmoid(y: f64) -> f64 {
     y * (1.0 - y)
 }
 
-// TODO: use softmax in relu
 pub fn softmax(z: Vec<f64>) -> Vec<f64> {
     let exp: Vec<f64> = z.into_iter().map(|x| std::f64::consts::E.powf(x)).collect();
     let sum: f64 = exp.iter().sum();
     exp.into_iter().map(|x| x / sum).collect()
 }
 
-// pub fn relu(x: f64) -> f64 {
-//     f64::max(x, 0.0)
-// }
-
-// pub fn d_relu(y: f64) -> f64 {
-//     if y == 0.0 {
-//         return 0.0;
-//     }
-//     1.0
-// }
-
-pub fn get_weight_delta(m1: &Matrix<f64>, m2: &Matrix<f64>) -> Matrix<f64> {
-    let m1 = m1.clone().into_vec();
-    let m2 = m2.clone().into_vec();
-    let mut result_arr: Vec<f64> = Vec::with_capacity(m1.len() * m2.len());
-    (0..m2.len()).for_each(|i| {
-        (0..m1.len()).for_each(|j| {
-            result_arr.push(m2[i] * m1[j]);
-        });
-    });
-
-    Matrix::new(m2.len(), m1.len(), result_arr)
+// index of the largest value, the first one on ties
+pub fn argmax(values: &[f64]) -> usize {
+    let mut guess: usize = 0;
+    for i in 0..values.len() {
+        if values[i] > values[guess] {
+            guess = i;
+        }
+    }
+    guess
 }
 
-#[derive(Debug)]
-pub struct MnistData {
-    sizes: Vec<i32>,
-    data: Vec<u8>,
+// the MNIST digits, always all ten classes so both splits have the same class count
+pub fn load_data(dataset_name: &str) -> Result<TrainingSet, std::io::Error> {
+    load_idx_data(
+        format!("{}-labels-idx1-ubyte.gz", dataset_name),
+        format!("{}-images-idx3-ubyte.gz", dataset_name),
+        Some(DatasetPreset::Mnist.class_names()),
+        0,
+        false,
+    )
 }
 
-impl MnistData {
-    fn new(f: &File) -> Result<MnistData, std::io::Error> {
-        let mut gz = GzDecoder::new(f);
-        let mut contents: Vec<u8> = Vec::new();
-        gz.read_to_end(&mut contents)?;
-        let mut r = Cursor::new(&contents);
-
-        let magic_number = r.read_i32::<BigEndian>()?;
+// loads an IDX label file and the matching IDX image file
+// without class names, the number of classes is derived from the largest label
+// label_offset is subtracted from every label, e.g. EMNIST letters are labelled 1 to 26
+// transposed images are stored column by column and get flipped back to row-major order
+pub fn load_idx_data(
+    labels_path: impl AsRef<Path>,
+    images_path: impl AsRef<Path>,
+    class_names: Option<Vec<String>>,
+    label_offset: usize,
+    transposed: bool,
+) -> Result<TrainingSet, std::io::Error> {
+    Ok(InMemoryDataset::load(
+        labels_path,
+        images_path,
+        class_names,
+        label_offset,
+        transposed,
+    )?
+    .to_training_set())
+}
 
-        let mut sizes: Vec<i32> = Vec::new();
-        let mut data: Vec<u8> = Vec::new();
+// eigenvalues and eigenvectors of a symmetric n x n matrix stored row by row,
+// using Householder tridiagonalization followed by the implicit QL algorithm (tred2/tql2)
+// returns the eigenvalues in no particular order and the eigenvectors as the matching columns
+// rulinalg 0.4's eigendecomp can't stand in: its eigenvectors are wrong from 4x4 on and
+// it doesn't converge from about 50x50, its svd works but takes minutes on MNIST's covariance
+pub fn symmetric_eigen(matrix: Vec<f64>, n: usize) -> (Vec<f64>, Matrix<f64>) {
+    let mut v = matrix;
+    let mut d: Vec<f64> = vec![0.0; n];
+    let mut e: Vec<f64> = vec![0.0; n];
+    if n == 0 {
+        return (d, Matrix::new(0, 0, v));
+    }
 
-        match magic_number {
-            2049 => {
-                sizes.push(r.read_i32::<BigEndian>()?);
+    // reduce to tridiagonal form
+    d.copy_from_slice(&v[(n - 1) * n..n * n]);
+    for i in (1..n).rev() {
+        let mut h = 0.0;
+        let scale: f64 = d[..i].iter().map(|x| x.abs()).sum();
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+                v[j * n + i] = 0.0;
             }
-            2051 => {
-                sizes.push(r.read_i32::<BigEndian>()?);
-                sizes.push(r.read_i32::<BigEndian>()?);
-                sizes.push(r.read_i32::<BigEndian>()?);
+        } else {
+            for x in d[..i].iter_mut() {
+                *x /= scale;
+                h += *x * *x;
+            }
+            let f = d[i - 1];
+            let g = if f > 0.0 { -h.sqrt() } else { h.sqrt() };
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            e[..i].iter_mut().for_each(|x| *x = 0.0);
+            for j in 0..i {
+                let f = d[j];
+                v[j * n + i] = f;
+                let mut g = e[j] + v[j * n + j] * f;
+                for k in j + 1..i {
+                    g += v[k * n + j] * d[k];
+                    e[k] += v[k * n + j] * f;
+                }
+                e[j] = g;
+            }
+            let mut f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                let (f, g) = (d[j], e[j]);
+                for k in j..i {
+                    v[k * n + j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
             }
-            _ => panic!(),
         }
-
-        r.read_to_end(&mut data)?;
-
-        Ok(MnistData { sizes, data })
+        d[i] = h;
     }
-}
+    for i in 0..n - 1 {
+        v[(n - 1) * n + i] = v[i * n + i];
+        v[i * n + i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k * n + i + 1] / h;
+            }
+            for j in 0..=i {
+                let g: f64 = (0..=i).map(|k| v[k * n + i + 1] * v[k * n + j]).sum();
+                for k in 0..=i {
+                    v[k * n + j] -= g * d[k];
+                }
+            }
+        }
+        for k in 0..=i {
+            v[k * n + i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[(n - 1) * n + j];
+        v[(n - 1) * n + j] = 0.0;
+    }
+    v[n * n - 1] = 1.0;
 
-pub fn load_data(dataset_name: &str) -> Result<Vec<TrainingData>, std::io::Error> {
-    let filename = format!("{}-labels-idx1-ubyte.gz", dataset_name);
-    let label_data = &MnistData::new(&(File::open(filename))?)?;
-    let filename = format!("{}-images-idx3-ubyte.gz", dataset_name);
-    let images_data = &MnistData::new(&(File::open(filename))?)?;
-    let mut images: Vec<Vec<f64>> = Vec::new();
-    let image_shape = (images_data.sizes[1] * images_data.sizes[2]) as usize;
+    // diagonalize the tridiagonal matrix
+    for i in 1..n {
+        e[i - 1] = e[i];
+    }
+    e[n - 1] = 0.0;
+    let mut f = 0.0;
+    let mut tst1: f64 = 0.0;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n - 1 && e[m].abs() > eps * tst1 {
+            m += 1;
+        }
+        if m > l {
+            loop {
+                let g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let h = g - d[l];
+                for x in d[l + 2..].iter_mut() {
+                    *x -= h;
+                }
+                f += h;
 
-    for i in 0..images_data.sizes[0] as usize {
-        let start = i * image_shape;
-        let image_data = images_data.data[start..start + image_shape].to_vec();
-        let image_data: Vec<f64> = image_data.into_iter().map(|x| x as f64 / 255.).collect();
-        images.push(image_data);
+                p = d[m];
+                let (mut c, mut c2, mut c3) = (1.0, 1.0, 1.0);
+                let el1 = e[l + 1];
+                let (mut s, mut s2) = (0.0, 0.0);
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    let g = c * e[i];
+                    let h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    for k in 0..n {
+                        let h = v[k * n + i + 1];
+                        v[k * n + i + 1] = s * v[k * n + i] + c * h;
+                        v[k * n + i] = c * v[k * n + i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
     }
 
-    let classifications: Vec<u8> = label_data.data.clone();
+    (d, Matrix::new(n, n, v))
+}
 
-    let mut ret: Vec<TrainingData> = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for (image, classification) in images.into_iter().zip(classifications.into_iter()) {
-        let mut target = vec![0.0; 10];
-        target[classification as usize] = 1.0;
-        ret.push(TrainingData {
-            inputs: image,
-            target,
-            classification,
-        })
+    #[test]
+    fn symmetric_eigen_decomposes() {
+        for n in [1, 2, 4, 60] {
+            // a fixed xorshift sequence keeps the test reproducible
+            let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 1000) as f64 / 1000.0 - 0.5
+            };
+            let mut matrix = vec![0.0; n * n];
+            for j in 0..n {
+                for k in j..n {
+                    matrix[j * n + k] = next();
+                    matrix[k * n + j] = matrix[j * n + k];
+                }
+            }
+            let (values, vectors) = symmetric_eigen(matrix.clone(), n);
+            for (c, value) in values.iter().enumerate() {
+                let norm: f64 = (0..n).map(|j| vectors[[j, c]].powi(2)).sum();
+                assert!((norm - 1.0).abs() < 1e-9);
+                for j in 0..n {
+                    let product: f64 = (0..n).map(|k| matrix[j * n + k] * vectors[[k, c]]).sum();
+                    assert!((product - value * vectors[[j, c]]).abs() < 1e-9);
+                }
+            }
+        }
     }
-
-    Ok(ret)
 }
@@ -0,0 +1,461 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// element type of an IDX file, encoded in the third byte of the magic number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdxType {
+    U8,
+    I8,
+    I16,
+    I32,
+    F32,
+    F64,
+}
+
+impl IdxType {
+    pub fn from_code(code: u8) -> Option<IdxType> {
+        match code {
+            0x08 => Some(IdxType::U8),
+            0x09 => Some(IdxType::I8),
+            0x0B => Some(IdxType::I16),
+            0x0C => Some(IdxType::I32),
+            0x0D => Some(IdxType::F32),
+            0x0E => Some(IdxType::F64),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            IdxType::U8 => 0x08,
+            IdxType::I8 => 0x09,
+            IdxType::I16 => 0x0B,
+            IdxType::I32 => 0x0C,
+            IdxType::F32 => 0x0D,
+            IdxType::F64 => 0x0E,
+        }
+    }
+
+    // size of a single element in bytes
+    pub fn size(self) -> usize {
+        match self {
+            IdxType::U8 | IdxType::I8 => 1,
+            IdxType::I16 => 2,
+            IdxType::I32 | IdxType::F32 => 4,
+            IdxType::F64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdxHeader {
+    pub dtype: IdxType,
+    pub dims: Vec<usize>,
+}
+
+impl IdxHeader {
+    pub fn read<R: Read>(r: &mut R) -> Result<IdxHeader, io::Error> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if magic[0] != 0 || magic[1] != 0 {
+            return Err(invalid_data(format!(
+                "Not an IDX file, magic number is {:?}",
+                magic
+            )));
+        }
+        let dtype = IdxType::from_code(magic[2])
+            .ok_or_else(|| invalid_data(format!("Unknown IDX data type 0x{:02x}", magic[2])))?;
+
+        let mut dims: Vec<usize> = Vec::with_capacity(magic[3] as usize);
+        for _ in 0..magic[3] {
+            let dim = r.read_i32::<BigEndian>()?;
+            if dim < 0 {
+                return Err(invalid_data(format!("Negative IDX dimension {}", dim)));
+            }
+            dims.push(dim as usize);
+        }
+        Ok(IdxHeader { dtype, dims })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        if self.dims.len() > u8::MAX as usize {
+            return Err(invalid_data(format!(
+                "IDX files support at most 255 dimensions, got {}",
+                self.dims.len()
+            )));
+        }
+        w.write_all(&[0, 0, self.dtype.code(), self.dims.len() as u8])?;
+        for dim in self.dims.iter() {
+            let dim = i32::try_from(*dim)
+                .map_err(|_| invalid_data(format!("IDX dimension {} is too large", dim)))?;
+            w.write_i32::<BigEndian>(dim)?;
+        }
+        Ok(())
+    }
+
+    // length of the header in bytes, i.e. the offset of the first element
+    pub fn data_offset(&self) -> usize {
+        4 + 4 * self.dims.len()
+    }
+
+    // number of elements in the whole file, an error when the dimensions overflow
+    // the item size is checked on its own, so item_size can't overflow with zero items either
+    pub fn element_count(&self) -> Result<usize, io::Error> {
+        self.dims
+            .iter()
+            .skip(1)
+            .try_fold(1usize, |count, dim| count.checked_mul(*dim))
+            .and_then(|item_size| item_size.checked_mul(self.dims.first().copied().unwrap_or(1)))
+            .ok_or_else(|| invalid_data(format!("IDX dimensions {:?} are too large", self.dims)))
+    }
+
+    // length of the header and the elements in bytes
+    pub fn file_size(&self) -> Result<usize, io::Error> {
+        self.element_count()?
+            .checked_mul(self.dtype.size())
+            .and_then(|size| size.checked_add(self.data_offset()))
+            .ok_or_else(|| invalid_data(format!("IDX dimensions {:?} are too large", self.dims)))
+    }
+
+    // number of elements in one item along the first dimension, e.g. 28 * 28 for an image file
+    pub fn item_size(&self) -> usize {
+        self.dims.iter().skip(1).product()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdxValues {
+    U8(Vec<u8>),
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+impl IdxValues {
+    pub fn dtype(&self) -> IdxType {
+        match self {
+            IdxValues::U8(_) => IdxType::U8,
+            IdxValues::I8(_) => IdxType::I8,
+            IdxValues::I16(_) => IdxType::I16,
+            IdxValues::I32(_) => IdxType::I32,
+            IdxValues::F32(_) => IdxType::F32,
+            IdxValues::F64(_) => IdxType::F64,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            IdxValues::U8(v) => v.len(),
+            IdxValues::I8(v) => v.len(),
+            IdxValues::I16(v) => v.len(),
+            IdxValues::I32(v) => v.len(),
+            IdxValues::F32(v) => v.len(),
+            IdxValues::F64(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get_f64(&self, i: usize) -> f64 {
+        match self {
+            IdxValues::U8(v) => v[i] as f64,
+            IdxValues::I8(v) => v[i] as f64,
+            IdxValues::I16(v) => v[i] as f64,
+            IdxValues::I32(v) => v[i] as f64,
+            IdxValues::F32(v) => v[i] as f64,
+            IdxValues::F64(v) => v[i],
+        }
+    }
+
+    // the bytes are read before they're converted, so a header claiming more elements than
+    // the file holds fails without allocating for all of them
+    fn read<R: Read>(r: &mut R, dtype: IdxType, count: usize) -> Result<IdxValues, io::Error> {
+        let size = count
+            .checked_mul(dtype.size())
+            .ok_or_else(|| invalid_data(format!("{} IDX elements are too many", count)))?;
+        let mut bytes: Vec<u8> = Vec::new();
+        r.take(size as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("IDX data ends after {} of {} bytes", bytes.len(), size),
+            ));
+        }
+        let mut r = bytes.as_slice();
+        Ok(match dtype {
+            IdxType::U8 => IdxValues::U8(bytes),
+            IdxType::I8 => {
+                let mut v = vec![0i8; count];
+                r.read_i8_into(&mut v)?;
+                IdxValues::I8(v)
+            }
+            IdxType::I16 => {
+                let mut v = vec![0i16; count];
+                r.read_i16_into::<BigEndian>(&mut v)?;
+                IdxValues::I16(v)
+            }
+            IdxType::I32 => {
+                let mut v = vec![0i32; count];
+                r.read_i32_into::<BigEndian>(&mut v)?;
+                IdxValues::I32(v)
+            }
+            IdxType::F32 => {
+                let mut v = vec![0f32; count];
+                r.read_f32_into::<BigEndian>(&mut v)?;
+                IdxValues::F32(v)
+            }
+            IdxType::F64 => {
+                let mut v = vec![0f64; count];
+                r.read_f64_into::<BigEndian>(&mut v)?;
+                IdxValues::F64(v)
+            }
+        })
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        match self {
+            IdxValues::U8(v) => w.write_all(v)?,
+            IdxValues::I8(v) => v.iter().try_for_each(|x| w.write_i8(*x))?,
+            IdxValues::I16(v) => v.iter().try_for_each(|x| w.write_i16::<BigEndian>(*x))?,
+            IdxValues::I32(v) => v.iter().try_for_each(|x| w.write_i32::<BigEndian>(*x))?,
+            IdxValues::F32(v) => v.iter().try_for_each(|x| w.write_f32::<BigEndian>(*x))?,
+            IdxValues::F64(v) => v.iter().try_for_each(|x| w.write_f64::<BigEndian>(*x))?,
+        }
+        Ok(())
+    }
+}
+
+// contents of an IDX file: a dense n-dimensional array stored in row-major order
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdxTensor {
+    pub dims: Vec<usize>,
+    pub values: IdxValues,
+}
+
+impl IdxTensor {
+    pub fn new(dims: Vec<usize>, values: IdxValues) -> Result<IdxTensor, io::Error> {
+        let tensor = IdxTensor { dims, values };
+        let expected = tensor.header().element_count()?;
+        if expected != tensor.values.len() {
+            return Err(invalid_data(format!(
+                "IDX dimensions {:?} need {} values, got {}",
+                tensor.dims,
+                expected,
+                tensor.values.len()
+            )));
+        }
+        Ok(tensor)
+    }
+
+    // reads an IDX file, gzipped or not
+    // uncompressed files are checked against the size the header claims before reading
+    pub fn read(path: impl AsRef<Path>) -> Result<IdxTensor, io::Error> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let (mut r, gzip) = decompress(BufReader::new(file))?;
+        let header = IdxHeader::read(&mut r)?;
+        let needed = header.file_size()?;
+        if !gzip && file_size < needed as u64 {
+            return Err(invalid_data(format!(
+                "{} is truncated, it has {} bytes but its header needs {}",
+                path.display(),
+                file_size,
+                needed
+            )));
+        }
+        IdxTensor::from_parts(header, r)
+    }
+
+    pub fn from_reader<R: Read>(mut r: R) -> Result<IdxTensor, io::Error> {
+        let header = IdxHeader::read(&mut r)?;
+        IdxTensor::from_parts(header, r)
+    }
+
+    fn from_parts<R: Read>(header: IdxHeader, mut r: R) -> Result<IdxTensor, io::Error> {
+        let values = IdxValues::read(&mut r, header.dtype, header.element_count()?)?;
+        Ok(IdxTensor {
+            dims: header.dims,
+            values,
+        })
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>, gzip: bool) -> Result<(), io::Error> {
+        let file = BufWriter::new(File::create(path)?);
+        if gzip {
+            let mut gz = GzEncoder::new(file, Compression::default());
+            self.to_writer(&mut gz)?;
+            gz.finish()?.flush()
+        } else {
+            let mut file = file;
+            self.to_writer(&mut file)?;
+            file.flush()
+        }
+    }
+
+    pub fn to_writer<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.header().write(w)?;
+        self.values.write(w)
+    }
+
+    pub fn header(&self) -> IdxHeader {
+        IdxHeader {
+            dtype: self.values.dtype(),
+            dims: self.dims.clone(),
+        }
+    }
+
+    pub fn dtype(&self) -> IdxType {
+        self.values.dtype()
+    }
+
+    // number of items along the first dimension
+    pub fn len(&self) -> usize {
+        self.dims.first().copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn item_size(&self) -> usize {
+        self.dims.iter().skip(1).product()
+    }
+
+    // the i-th item along the first dimension, converted to f64, None when out of range
+    pub fn item(&self, i: usize) -> Option<Vec<f64>> {
+        let size = self.item_size();
+        let end = i.checked_add(1)?.checked_mul(size)?;
+        if i >= self.len() || end > self.values.len() {
+            return None;
+        }
+        Some((i * size..end).map(|j| self.values.get_f64(j)).collect())
+    }
+
+    pub fn to_f64(&self) -> Vec<f64> {
+        (0..self.values.len())
+            .map(|i| self.values.get_f64(i))
+            .collect()
+    }
+
+    pub fn as_u8(&self) -> Option<&[u8]> {
+        match &self.values {
+            IdxValues::U8(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+// opens a file for reading, transparently decompressing it if it starts with the gzip magic bytes
+pub fn open(path: impl AsRef<Path>) -> Result<Box<dyn Read>, io::Error> {
+    decompress(BufReader::new(File::open(path)?)).map(|(r, _)| r)
+}
+
+// the reader along with whether it's gzipped
+fn decompress(mut r: BufReader<File>) -> Result<(Box<dyn Read>, bool), io::Error> {
+    if r.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok((Box::new(GzDecoder::new(r)), true))
+    } else {
+        Ok((Box::new(r), false))
+    }
+}
+
+pub fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("idx-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn every_type_round_trips_plain_and_gzipped() {
+        let dims = vec![2, 3];
+        let tensors = [
+            IdxValues::U8(vec![0, 1, 2, 127, 128, 255]),
+            IdxValues::I8(vec![-128, -1, 0, 1, 2, 127]),
+            IdxValues::I16(vec![i16::MIN, -300, 0, 1, 300, i16::MAX]),
+            IdxValues::I32(vec![i32::MIN, -70000, 0, 1, 70000, i32::MAX]),
+            IdxValues::F32(vec![-1.5, 0.0, 0.1, 1e-30, 3.25, f32::MAX]),
+            IdxValues::F64(vec![-1.5, 0.0, 0.1, 1e-300, 3.25, f64::MAX]),
+        ]
+        .map(|values| IdxTensor::new(dims.clone(), values).unwrap());
+        for tensor in tensors.iter() {
+            for gzip in [false, true] {
+                let path = temp_path(&format!("{:?}-{}", tensor.dtype(), gzip));
+                tensor.write(&path, gzip).unwrap();
+                let mut magic = [0u8; 2];
+                File::open(&path).unwrap().read_exact(&mut magic).unwrap();
+                assert_eq!(magic == GZIP_MAGIC, gzip);
+                let read = IdxTensor::read(&path);
+                std::fs::remove_file(&path).unwrap();
+                assert_eq!(&read.unwrap(), tensor);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_headers_that_dont_fit() {
+        let header = IdxHeader {
+            dtype: IdxType::F64,
+            dims: vec![i32::MAX as usize; 3],
+        };
+        assert!(header.element_count().is_err());
+        let mut bytes: Vec<u8> = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert!(IdxTensor::from_reader(bytes.as_slice()).is_err());
+
+        // a plain file shorter than its header says fails before reading, a gzipped one
+        // once its data runs out
+        let tensor = IdxTensor::new(vec![4, 2], IdxValues::I16(vec![7; 8])).unwrap();
+        for gzip in [false, true] {
+            let path = temp_path(&format!("short-{}", gzip));
+            let mut header = tensor.header();
+            header.dims[0] = 5;
+            let mut w: Box<dyn Write> = if gzip {
+                Box::new(GzEncoder::new(
+                    File::create(&path).unwrap(),
+                    Compression::default(),
+                ))
+            } else {
+                Box::new(File::create(&path).unwrap())
+            };
+            header.write(&mut w).unwrap();
+            tensor.values.write(&mut w).unwrap();
+            drop(w);
+            let error = IdxTensor::read(&path).err().unwrap();
+            std::fs::remove_file(&path).unwrap();
+            if gzip {
+                assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+            } else {
+                assert!(error.to_string().contains("is truncated"), "{}", error);
+            }
+        }
+
+        assert!(IdxTensor::new(vec![3, 2], IdxValues::U8(vec![0; 5])).is_err());
+    }
+
+    #[test]
+    fn items_are_checked() {
+        let tensor = IdxTensor::new(vec![3, 2], IdxValues::U8(vec![1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!(tensor.item(0), Some(vec![1.0, 2.0]));
+        assert_eq!(tensor.item(2), Some(vec![5.0, 6.0]));
+        assert_eq!(tensor.item(3), None);
+        assert_eq!(tensor.item(usize::MAX), None);
+    }
+}
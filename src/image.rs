@@ -0,0 +1,668 @@
+use byteorder::{ByteOrder, LittleEndian};
+use std::{path::Path, str::FromStr};
+
+use crate::idx::invalid_data;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Invert {
+    // inverts images whose border is mostly light, like dark ink on paper
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Invert {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Invert, String> {
+        match mode {
+            "auto" => Ok(Invert::Auto),
+            "always" => Ok(Invert::Always),
+            "never" => Ok(Invert::Never),
+            other => Err(format!("Unknown invert mode {:?}", other)),
+        }
+    }
+}
+
+// how arbitrary images get turned into MNIST-like digits
+#[derive(Debug, Clone)]
+pub struct DigitOptions {
+    // width and height of the result
+    pub size: usize,
+    pub invert: Invert,
+    // crop, scale and center the digit the way MNIST was made,
+    // otherwise the whole image is only resized
+    pub center: bool,
+}
+
+impl Default for DigitOptions {
+    fn default() -> Self {
+        DigitOptions {
+            size: 28,
+            invert: Invert::Auto,
+            center: true,
+        }
+    }
+}
+
+// 8-bit grayscale image, pixels stored row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrayImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl GrayImage {
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Result<GrayImage, std::io::Error> {
+        if width == 0 || height == 0 {
+            return Err(invalid_data(format!(
+                "Invalid image size {}x{}",
+                width, height
+            )));
+        }
+        if width.checked_mul(height) != Some(pixels.len()) {
+            return Err(invalid_data(format!(
+                "A {}x{} image needs {} pixels, got {}",
+                width,
+                height,
+                width as u128 * height as u128,
+                pixels.len()
+            )));
+        }
+        Ok(GrayImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<GrayImage, std::io::Error> {
+        let bytes = std::fs::read(path.as_ref())?;
+        GrayImage::decode(&bytes)
+            .map_err(|e| invalid_data(format!("{}: {}", path.as_ref().display(), e)))
+    }
+
+    // decodes a PNG, BMP or PGM/PPM image, detected from its magic bytes
+    pub fn decode(bytes: &[u8]) -> Result<GrayImage, std::io::Error> {
+        if bytes.starts_with(b"\x89PNG") {
+            decode_png(bytes)
+        } else if bytes.starts_with(b"BM") {
+            decode_bmp(bytes)
+        } else if bytes.len() > 1 && bytes[0] == b'P' && b"2356".contains(&bytes[1]) {
+            decode_pnm(bytes)
+        } else {
+            Err(invalid_data("Unsupported image format".to_string()))
+        }
+    }
+
+    // network inputs scaled to 0..1, values outside that range are clamped
+    pub fn from_inputs(
+        width: usize,
+        height: usize,
+        inputs: &[f64],
+    ) -> Result<GrayImage, std::io::Error> {
+        let pixels = inputs
+            .iter()
+            .map(|x| (x * 255.0).round().clamp(0.0, 255.0) as u8)
+            .collect();
+        GrayImage::new(width, height, pixels)
+    }
+
+    // writes a PGM file for .pgm paths and a PNG file otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let is_pgm = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("pgm"));
+        let bytes = if is_pgm {
+            self.encode_pgm()
+        } else {
+            self.encode_png()?
+        };
+        std::fs::write(path, bytes)
+    }
+
+    pub fn encode_pgm(&self) -> Vec<u8> {
+        let mut bytes = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        bytes.extend_from_slice(&self.pixels);
+        bytes
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|e| invalid_data(format!("Unable to encode PNG: {}", e)))?;
+        Ok(bytes)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn invert(&self) -> GrayImage {
+        GrayImage {
+            width: self.width,
+            height: self.height,
+            pixels: self.pixels.iter().map(|p| 255 - p).collect(),
+        }
+    }
+
+    // resamples to the given size, averaging when shrinking and interpolating when enlarging
+    pub fn resize(&self, width: usize, height: usize) -> GrayImage {
+        if width == self.width && height == self.height {
+            return self.clone();
+        }
+        let x_weights = resample_weights(self.width, width);
+        let y_weights = resample_weights(self.height, height);
+
+        // resample rows first, then columns
+        let mut rows: Vec<f64> = vec![0.0; width * self.height];
+        for y in 0..self.height {
+            for (x, weights) in x_weights.iter().enumerate() {
+                rows[y * width + x] = weights
+                    .iter()
+                    .map(|(src, w)| self.get(*src, y) as f64 * w)
+                    .sum();
+            }
+        }
+        let mut pixels: Vec<u8> = vec![0; width * height];
+        for (y, weights) in y_weights.iter().enumerate() {
+            for x in 0..width {
+                let value: f64 = weights
+                    .iter()
+                    .map(|(src, w)| rows[src * width + x] * w)
+                    .sum();
+                pixels[y * width + x] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        GrayImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    // average of the outermost pixels
+    pub fn border_mean(&self) -> f64 {
+        let mut sum = 0.0;
+        let mut count = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if x == 0 || y == 0 || x == self.width - 1 || y == self.height - 1 {
+                    sum += self.get(x, y) as f64;
+                    count += 1;
+                }
+            }
+        }
+        if count == 0 {
+            0.0
+        } else {
+            sum / count as f64
+        }
+    }
+
+    // light on dark digit of options.size pixels squared
+    pub fn to_digit(&self, options: &DigitOptions) -> GrayImage {
+        let invert = match options.invert {
+            Invert::Auto => self.border_mean() > 127.0,
+            Invert::Always => true,
+            Invert::Never => false,
+        };
+        let image = if invert { self.invert() } else { self.clone() };
+        if options.center {
+            image.center_digit(options.size)
+        } else {
+            image.resize(options.size, options.size)
+        }
+    }
+
+    // MNIST digits were cropped to their bounding box, scaled to fit 20x20 keeping
+    // their aspect ratio and placed in a 28x28 image so their center of mass is in the middle
+    // size scales those numbers, faint pixels are ignored when finding the bounding box
+    pub fn center_digit(&self, size: usize) -> GrayImage {
+        const INK_THRESHOLD: u8 = 32;
+        let mut blank = GrayImage {
+            width: size,
+            height: size,
+            pixels: vec![0; size * size],
+        };
+        let (mut left, mut top, mut right, mut bottom) = (self.width, self.height, 0, 0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.get(x, y) > INK_THRESHOLD {
+                    (left, top) = (left.min(x), top.min(y));
+                    (right, bottom) = (right.max(x + 1), bottom.max(y + 1));
+                }
+            }
+        }
+        if left >= right {
+            return blank;
+        }
+
+        let (crop_width, crop_height) = (right - left, bottom - top);
+        let mut crop: Vec<u8> = Vec::with_capacity(crop_width * crop_height);
+        for y in top..bottom {
+            crop.extend_from_slice(&self.pixels[y * self.width + left..y * self.width + right]);
+        }
+        let fit = (size * 20).div_ceil(28);
+        let scale = fit as f64 / crop_width.max(crop_height) as f64;
+        let digit = GrayImage {
+            width: crop_width,
+            height: crop_height,
+            pixels: crop,
+        }
+        .resize(
+            ((crop_width as f64 * scale).round() as usize).clamp(1, fit),
+            ((crop_height as f64 * scale).round() as usize).clamp(1, fit),
+        );
+
+        // center of mass of the scaled digit, relative to its top left corner
+        let (mut mass, mut mass_x, mut mass_y) = (0.0, 0.0, 0.0);
+        for y in 0..digit.height {
+            for x in 0..digit.width {
+                let value = digit.get(x, y) as f64;
+                mass += value;
+                mass_x += value * (x as f64 + 0.5);
+                mass_y += value * (y as f64 + 0.5);
+            }
+        }
+        if mass == 0.0 {
+            return blank;
+        }
+        let center = size as f64 / 2.0;
+        let offset = |mass_center: f64, len: usize| -> usize {
+            ((center - mass_center).round().max(0.0) as usize).min(size - len)
+        };
+        let (offset_x, offset_y) = (
+            offset(mass_x / mass, digit.width),
+            offset(mass_y / mass, digit.height),
+        );
+        for y in 0..digit.height {
+            let start = (offset_y + y) * size + offset_x;
+            blank.pixels[start..start + digit.width]
+                .copy_from_slice(&digit.pixels[y * digit.width..(y + 1) * digit.width]);
+        }
+        blank
+    }
+
+    // network inputs, scaled to 0..1 the same way load_data does
+    pub fn to_inputs(&self) -> Vec<f64> {
+        self.pixels.iter().map(|x| *x as f64 / 255.).collect()
+    }
+}
+
+// for every destination index, the source indices and their weights
+// an empty source leaves every destination pixel at zero
+fn resample_weights(src_len: usize, dst_len: usize) -> Vec<Vec<(usize, f64)>> {
+    if src_len == 0 {
+        return vec![Vec::new(); dst_len];
+    }
+    let scale = src_len as f64 / dst_len as f64;
+    (0..dst_len)
+        .map(|i| {
+            if scale > 1.0 {
+                // area average over the source pixels covered by [start, end)
+                let start = i as f64 * scale;
+                let end = start + scale;
+                let mut weights: Vec<(usize, f64)> = Vec::new();
+                let mut src = start.floor() as usize;
+                while (src as f64) < end && src < src_len {
+                    let covered = (end.min(src as f64 + 1.0) - start.max(src as f64)).max(0.0);
+                    weights.push((src, covered / scale));
+                    src += 1;
+                }
+                weights
+            } else {
+                // linear interpolation between the two nearest pixel centers
+                let center = ((i as f64 + 0.5) * scale - 0.5).max(0.0);
+                let left = (center.floor() as usize).min(src_len - 1);
+                let right = (left + 1).min(src_len - 1);
+                let t = center - left as f64;
+                vec![(left, 1.0 - t), (right, t)]
+            }
+        })
+        .collect()
+}
+
+fn luminance(r: u8, g: u8, b: u8) -> f64 {
+    0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+}
+
+// transparent pixels are composited over a white background
+fn blend_alpha(value: f64, alpha: u8) -> u8 {
+    let alpha = alpha as f64 / 255.;
+    (value * alpha + 255. * (1. - alpha)).round() as u8
+}
+
+fn decode_png(bytes: &[u8]) -> Result<GrayImage, std::io::Error> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| invalid_data(format!("Invalid PNG: {}", e)))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| invalid_data(format!("Invalid PNG: {}", e)))?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channels = info.color_type.samples();
+    let pixels = (0..width * height)
+        .map(|i| {
+            let px = &buf[i * channels..(i + 1) * channels];
+            match info.color_type {
+                png::ColorType::Grayscale | png::ColorType::Indexed => px[0],
+                png::ColorType::GrayscaleAlpha => blend_alpha(px[0] as f64, px[1]),
+                png::ColorType::Rgb => luminance(px[0], px[1], px[2]).round() as u8,
+                png::ColorType::Rgba => blend_alpha(luminance(px[0], px[1], px[2]), px[3]),
+            }
+        })
+        .collect();
+    GrayImage::new(width, height, pixels)
+}
+
+// uncompressed BMP with 1, 4, 8, 24 or 32 bits per pixel
+// every size is checked against the file before anything is allocated for it
+fn decode_bmp(bytes: &[u8]) -> Result<GrayImage, std::io::Error> {
+    let truncated = || invalid_data("Truncated BMP file".to_string());
+    if bytes.len() < 54 {
+        return Err(truncated());
+    }
+    let data_offset = LittleEndian::read_u32(&bytes[10..14]) as usize;
+    let header_size = LittleEndian::read_u32(&bytes[14..18]) as usize;
+    let width = LittleEndian::read_i32(&bytes[18..22]);
+    let height = LittleEndian::read_i32(&bytes[22..26]);
+    let bits_per_pixel = LittleEndian::read_u16(&bytes[28..30]) as usize;
+    let compression = LittleEndian::read_u32(&bytes[30..34]);
+    let colors_used = LittleEndian::read_u32(&bytes[46..50]) as usize;
+    if ![1, 4, 8, 24, 32].contains(&bits_per_pixel) {
+        return Err(invalid_data(format!(
+            "Unsupported BMP bit depth {}",
+            bits_per_pixel
+        )));
+    }
+    // 3 is BI_BITFIELDS, the red, green and blue masks follow the 40 byte header
+    if compression == 3 && bits_per_pixel == 32 {
+        let masks = bytes.get(54..66).ok_or_else(truncated)?;
+        let masks = [
+            LittleEndian::read_u32(&masks[0..4]),
+            LittleEndian::read_u32(&masks[4..8]),
+            LittleEndian::read_u32(&masks[8..12]),
+        ];
+        if masks != [0x00ff_0000, 0x0000_ff00, 0x0000_00ff] {
+            return Err(invalid_data(format!(
+                "Only BGRA channel masks are supported, got {:08x}, {:08x}, {:08x}",
+                masks[0], masks[1], masks[2]
+            )));
+        }
+    } else if compression != 0 {
+        return Err(invalid_data(format!(
+            "Compressed BMP files are not supported (compression {})",
+            compression
+        )));
+    }
+    if width <= 0 || height == 0 {
+        return Err(invalid_data(format!(
+            "Invalid BMP size {}x{}",
+            width, height
+        )));
+    }
+    let (width, top_down) = (width as usize, height < 0);
+    let height = height.unsigned_abs() as usize;
+
+    let palette: Vec<u8> = if bits_per_pixel <= 8 {
+        let count = if colors_used == 0 {
+            1 << bits_per_pixel
+        } else {
+            colors_used
+        };
+        let start = header_size.checked_add(14).ok_or_else(truncated)?;
+        let end = count
+            .checked_mul(4)
+            .and_then(|size| size.checked_add(start))
+            .ok_or_else(truncated)?;
+        let table = bytes.get(start..end).ok_or_else(truncated)?;
+        table
+            .chunks(4)
+            .map(|c| luminance(c[2], c[1], c[0]).round() as u8)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let row_size = (bits_per_pixel as u128 * width as u128).div_ceil(32) * 4;
+    if data_offset as u128 + row_size * height as u128 > bytes.len() as u128 {
+        return Err(truncated());
+    }
+    // both fit in the file, so neither overflows
+    let row_size = row_size as usize;
+    let mut pixels: Vec<u8> = vec![0; width * height];
+    for row in 0..height {
+        let start = data_offset + row * row_size;
+        let data = &bytes[start..start + row_size];
+        let y = if top_down { row } else { height - 1 - row };
+        for x in 0..width {
+            let value = match bits_per_pixel {
+                1 | 4 | 8 => {
+                    let bit = x * bits_per_pixel;
+                    let shift = 8 - bits_per_pixel - bit % 8;
+                    let index = (data[bit / 8] >> shift) as usize & ((1 << bits_per_pixel) - 1);
+                    *palette.get(index).ok_or_else(truncated)?
+                }
+                24 => luminance(data[x * 3 + 2], data[x * 3 + 1], data[x * 3]).round() as u8,
+                _ => {
+                    let px = &data[x * 4..x * 4 + 4];
+                    luminance(px[2], px[1], px[0]).round() as u8
+                }
+            };
+            pixels[y * width + x] = value;
+        }
+    }
+    GrayImage::new(width, height, pixels)
+}
+
+// PGM (P2/P5) and PPM (P3/P6), plain text or binary
+fn decode_pnm(bytes: &[u8]) -> Result<GrayImage, std::io::Error> {
+    let format = bytes[1];
+    let mut pos = 2;
+    let next_token = |pos: &mut usize| -> Result<usize, std::io::Error> {
+        loop {
+            match bytes.get(*pos) {
+                Some(b'#') => {
+                    while bytes.get(*pos).is_some_and(|b| *b != b'\n') {
+                        *pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => *pos += 1,
+                Some(_) => break,
+                None => return Err(invalid_data("Truncated PNM file".to_string())),
+            }
+        }
+        let start = *pos;
+        while bytes.get(*pos).is_some_and(|b| b.is_ascii_digit()) {
+            *pos += 1;
+        }
+        std::str::from_utf8(&bytes[start..*pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid_data("Invalid number in PNM header".to_string()))
+    };
+    let width = next_token(&mut pos)?;
+    let height = next_token(&mut pos)?;
+    let max_value = next_token(&mut pos)?;
+    if max_value == 0 || max_value > 65535 {
+        return Err(invalid_data(format!("Invalid PNM max value {}", max_value)));
+    }
+    let channels = if format == b'3' || format == b'6' {
+        3
+    } else {
+        1
+    };
+    if width == 0 || height == 0 {
+        return Err(invalid_data(format!(
+            "Invalid PNM size {}x{}",
+            width, height
+        )));
+    }
+    let sample_size = if max_value > 255 { 2 } else { 1 };
+    // every sample takes at least one byte, in text files too
+    let count = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(channels))
+        .filter(|count| count.saturating_mul(sample_size) <= bytes.len())
+        .ok_or_else(|| invalid_data("Truncated PNM file".to_string()))?;
+
+    let samples: Vec<usize> = if format == b'2' || format == b'3' {
+        (0..count)
+            .map(|_| next_token(&mut pos))
+            .collect::<Result<_, _>>()?
+    } else {
+        // a single whitespace character separates the header from the binary data
+        pos += 1;
+        let data = bytes
+            .get(pos..pos + count * sample_size)
+            .ok_or_else(|| invalid_data("Truncated PNM file".to_string()))?;
+        data.chunks(sample_size)
+            .map(|c| c.iter().fold(0, |acc, b| (acc << 8) | *b as usize))
+            .collect()
+    };
+    let scale = |sample: usize| (sample.min(max_value) * 255 / max_value) as u8;
+    let pixels = samples
+        .chunks(channels)
+        .map(|px| {
+            if channels == 3 {
+                luminance(scale(px[0]), scale(px[1]), scale(px[2])).round() as u8
+            } else {
+                scale(px[0])
+            }
+        })
+        .collect();
+    GrayImage::new(width, height, pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a bottom-up BMP with the given header fields and pixel data
+    fn bmp(width: i32, height: i32, bits_per_pixel: u16, compression: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 54];
+        bytes[0..2].copy_from_slice(b"BM");
+        LittleEndian::write_u32(&mut bytes[10..14], 54);
+        LittleEndian::write_u32(&mut bytes[14..18], 40);
+        LittleEndian::write_i32(&mut bytes[18..22], width);
+        LittleEndian::write_i32(&mut bytes[22..26], height);
+        LittleEndian::write_u16(&mut bytes[28..30], bits_per_pixel);
+        LittleEndian::write_u32(&mut bytes[30..34], compression);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn decodes_24_bit_bmp() {
+        // rows are padded to 4 bytes and stored bottom row first
+        let data = [0, 0, 0, 255, 255, 255, 0, 0, 255, 255, 255, 0, 0, 0, 0, 0];
+        let image = GrayImage::decode(&bmp(2, 2, 24, 0, &data)).unwrap();
+        assert_eq!(image.pixels, vec![255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn rejects_unsupported_bmp_bit_depths() {
+        for bits_per_pixel in [0, 2, 16, 64] {
+            assert!(GrayImage::decode(&bmp(2, 2, bits_per_pixel, 0, &[0; 64])).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_bmp() {
+        assert!(GrayImage::decode(&bmp(2, 2, 24, 0, &[0; 10])).is_err());
+        assert!(GrayImage::decode(&bmp(i32::MAX, i32::MAX, 32, 0, &[0; 16])).is_err());
+        assert!(GrayImage::decode(&bmp(0, 2, 24, 0, &[0; 16])).is_err());
+    }
+
+    #[test]
+    fn checks_bmp_bitfield_masks() {
+        let masks = |r: u32, g: u32, b: u32| {
+            let mut data = vec![0u8; 12];
+            LittleEndian::write_u32(&mut data[0..4], r);
+            LittleEndian::write_u32(&mut data[4..8], g);
+            LittleEndian::write_u32(&mut data[8..12], b);
+            data
+        };
+        // the pixel data starts after the masks
+        let mut bgra = bmp(1, 1, 32, 3, &masks(0xff0000, 0xff00, 0xff));
+        LittleEndian::write_u32(&mut bgra[10..14], 66);
+        bgra.extend_from_slice(&[255, 255, 255, 255]);
+        assert_eq!(GrayImage::decode(&bgra).unwrap().pixels, vec![255]);
+
+        let mut rgba = bmp(1, 1, 32, 3, &masks(0xff, 0xff00, 0xff0000));
+        LittleEndian::write_u32(&mut rgba[10..14], 66);
+        rgba.extend_from_slice(&[255, 255, 255, 255]);
+        assert!(GrayImage::decode(&rgba).is_err());
+    }
+
+    #[test]
+    fn decodes_pgm() {
+        let image = GrayImage::decode(b"P2\n# comment\n2 1\n10\n0 10\n").unwrap();
+        assert_eq!(image.pixels, vec![0, 255]);
+        let image = GrayImage::decode(b"P5\n2 1\n255\n\x01\x02").unwrap();
+        assert_eq!(image.pixels, vec![1, 2]);
+    }
+
+    #[test]
+    fn rejects_malformed_pnm() {
+        assert!(GrayImage::decode(b"P5\n0 1\n255\n").is_err());
+        assert!(GrayImage::decode(b"P5\n2 2\n255\n\x01").is_err());
+        let huge = format!("P6\n{} {}\n255\n", usize::MAX / 2, 3);
+        assert!(GrayImage::decode(huge.as_bytes()).is_err());
+        assert!(GrayImage::decode(b"P2\n2 1\n255\n1").is_err());
+    }
+
+    #[test]
+    fn mutated_images_never_panic() {
+        let valid = [
+            bmp(3, 2, 8, 0, &[0; 1024 + 8]),
+            bmp(3, 2, 24, 0, &[7; 16]),
+            b"P5\n3 2\n255\nabcdef".to_vec(),
+            b"P3\n1 1\n255\n1 2 3\n".to_vec(),
+        ];
+        // a fixed xorshift sequence keeps the test reproducible
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for bytes in valid.iter() {
+            for _ in 0..2000 {
+                let mut bytes = bytes.clone();
+                for _ in 0..(next() % 4 + 1) {
+                    let i = next() as usize % bytes.len();
+                    bytes[i] = next() as u8;
+                }
+                bytes.truncate(next() as usize % (bytes.len() + 1));
+                if let Ok(image) = GrayImage::decode(&bytes) {
+                    image.to_digit(&DigitOptions::default());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resizes_without_panicking_on_empty_images() {
+        let empty = GrayImage {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+        };
+        assert_eq!(empty.resize(2, 2).pixels, vec![0; 4]);
+        assert!(GrayImage::new(0, 3, Vec::new()).is_err());
+    }
+}
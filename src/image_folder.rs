@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+use crate::idx::invalid_data;
+use crate::image::GrayImage;
+use crate::network::{TrainingData, TrainingSet};
+
+const IMAGE_EXTENSIONS: [&str; 4] = ["png", "pgm", "ppm", "bmp"];
+
+#[derive(Debug, Clone)]
+pub struct ImageFolderOptions {
+    pub width: usize,
+    pub height: usize,
+    // MNIST digits are light on dark, scans and drawings usually the other way around
+    pub invert: bool,
+}
+
+impl Default for ImageFolderOptions {
+    fn default() -> Self {
+        ImageFolderOptions {
+            width: 28,
+            height: 28,
+            invert: false,
+        }
+    }
+}
+
+// loads a directory laid out as class_name/image.png, one sub directory per class
+// classes are numbered in the lexicographic order of their directory names,
+// so a directory named 10 comes before one named 2
+// images are resized to the options' size and scaled from 0..255 to 0..1
+pub fn load_image_folder(
+    dir: impl AsRef<Path>,
+    options: &ImageFolderOptions,
+) -> Result<TrainingSet, std::io::Error> {
+    let mut class_dirs: Vec<PathBuf> = std::fs::read_dir(dir.as_ref())?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .collect();
+    class_dirs.sort();
+    if class_dirs.is_empty() {
+        return Err(invalid_data(format!(
+            "No class directories found in {}",
+            dir.as_ref().display()
+        )));
+    }
+
+    let num_classes = class_dirs.len();
+    let mut class_names: Vec<String> = Vec::with_capacity(num_classes);
+    let mut samples: Vec<TrainingData> = Vec::new();
+    for (classification, class_dir) in class_dirs.iter().enumerate() {
+        class_names.push(
+            class_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        );
+        for path in image_files(class_dir)? {
+            let mut image = GrayImage::open(&path)?.resize(options.width, options.height);
+            if options.invert {
+                image = image.invert();
+            }
+            let mut target = vec![0.0; num_classes];
+            target[classification] = 1.0;
+            samples.push(TrainingData {
+                inputs: image.to_inputs(),
+                target,
+                classification,
+            });
+        }
+    }
+
+    Ok(TrainingSet::new(samples, class_names))
+}
+
+// image files directly inside dir, sorted by name
+pub fn image_files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.is_file() && is_image_file(path))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+pub fn is_image_file(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| {
+            IMAGE_EXTENSIONS
+                .iter()
+                .any(|ext| e.eq_ignore_ascii_case(ext))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_classes_in_directory_name_order() {
+        let dir = std::env::temp_dir().join(format!("image-folder-{}", std::process::id()));
+        let image = |class: &str, file: &str, width: usize, pixels: Vec<u8>| {
+            std::fs::create_dir_all(dir.join(class)).unwrap();
+            let height = pixels.len() / width;
+            GrayImage::new(width, height, pixels)
+                .unwrap()
+                .save(dir.join(class).join(file))
+                .unwrap();
+        };
+        image("2", "a.png", 4, vec![51; 16]);
+        image("10", "a.pgm", 2, vec![0, 255, 255, 0]);
+        image("cat", "b.png", 4, [0, 0, 255, 255].repeat(4));
+        image("cat", "a.png", 2, vec![255; 4]);
+        std::fs::write(dir.join("cat").join("notes.txt"), "not an image").unwrap();
+        std::fs::write(dir.join("readme.txt"), "not a class").unwrap();
+
+        let options = ImageFolderOptions {
+            width: 2,
+            height: 2,
+            invert: false,
+        };
+        let set = load_image_folder(&dir, &options);
+        let inverted = load_image_folder(
+            &dir,
+            &ImageFolderOptions {
+                invert: true,
+                ..options
+            },
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (set, inverted) = (set.unwrap(), inverted.unwrap());
+
+        assert_eq!(set.class_names, vec!["10", "2", "cat"]);
+        let samples: Vec<(usize, Vec<f64>)> = set
+            .samples
+            .iter()
+            .map(|s| (s.classification, s.inputs.clone()))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                (0, vec![0.0, 1.0, 1.0, 0.0]),
+                (1, vec![0.2; 4]),
+                (2, vec![1.0; 4]),
+                (2, vec![0.0, 1.0, 0.0, 1.0]),
+            ]
+        );
+        assert_eq!(set.samples[3].target, vec![0.0, 0.0, 1.0]);
+        assert_eq!(inverted.samples[0].inputs, vec![1.0, 0.0, 0.0, 1.0]);
+    }
+}
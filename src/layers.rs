@@ -0,0 +1,2130 @@
+use rand::{rngs::StdRng, Rng};
+use rulinalg::matrix::{BaseMatrix, Matrix};
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::Range, str::FromStr};
+
+use crate::config::{LayerConfig, LayerKind};
+use crate::helpers::{d_sigmoid, sigmoid, softmax};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Activation {
+    #[default]
+    Sigmoid,
+    Relu,
+    // passes the weighted sums on as they are
+    Identity,
+    // turns the outputs into probabilities summing up to 1
+    Softmax,
+}
+
+impl Activation {
+    pub fn activate(self, z: Vec<f64>) -> Vec<f64> {
+        match self {
+            Activation::Sigmoid => z.into_iter().map(sigmoid).collect(),
+            Activation::Relu => z.into_iter().map(|x| x.max(0.0)).collect(),
+            Activation::Identity => z,
+            Activation::Softmax => {
+                // shifted by the largest value so exp can't overflow
+                let max = z.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                softmax(z.into_iter().map(|x| x - max).collect())
+            }
+        }
+    }
+
+    // gradient of the loss by the weighted sums, given the activated outputs
+    // and the gradient of the loss by them
+    pub fn backward(self, outputs: &[f64], output_gradient: Vec<f64>) -> Vec<f64> {
+        match self {
+            Activation::Sigmoid => outputs
+                .iter()
+                .zip(output_gradient)
+                .map(|(y, g)| g * d_sigmoid(*y))
+                .collect(),
+            Activation::Relu => outputs
+                .iter()
+                .zip(output_gradient)
+                .map(|(y, g)| if *y > 0.0 { g } else { 0.0 })
+                .collect(),
+            Activation::Identity => output_gradient,
+            // every output depends on every sum, dy_i/dz_j = y_i * ([i == j] - y_j)
+            Activation::Softmax => {
+                let dot: f64 = outputs
+                    .iter()
+                    .zip(output_gradient.iter())
+                    .map(|(y, g)| y * g)
+                    .sum();
+                outputs
+                    .iter()
+                    .zip(output_gradient)
+                    .map(|(y, g)| y * (g - dot))
+                    .collect()
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Activation::Sigmoid => "sigmoid",
+            Activation::Relu => "relu",
+            Activation::Identity => "identity",
+            Activation::Softmax => "softmax",
+        }
+    }
+}
+
+impl FromStr for Activation {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Activation, String> {
+        match name {
+            "sigmoid" => Ok(Activation::Sigmoid),
+            "relu" => Ok(Activation::Relu),
+            "identity" => Ok(Activation::Identity),
+            "softmax" => Ok(Activation::Softmax),
+            other => Err(format!("Unknown activation {:?}", other)),
+        }
+    }
+}
+
+// layout of the values passed between layers, stored channel by channel and row by row
+// dense layers output channels of a single value
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Shape {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl Shape {
+    pub fn new(channels: usize, height: usize, width: usize) -> Self {
+        Shape {
+            channels,
+            height,
+            width,
+        }
+    }
+
+    // a flat vector of values
+    pub fn flat(size: usize) -> Self {
+        Shape::new(size, 1, 1)
+    }
+
+    pub fn size(&self) -> usize {
+        self.channels * self.height * self.width
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.height == 1 && self.width == 1 {
+            write!(f, "{}", self.channels)
+        } else {
+            write!(f, "{}x{}x{}", self.channels, self.height, self.width)
+        }
+    }
+}
+
+// penalties on the weights of a layer added to the loss,
+// l1 * sum(|w|) + l2 / 2 * sum(w^2), biases only count when biases is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Regularization {
+    pub l1: f64,
+    pub l2: f64,
+    pub biases: bool,
+}
+
+impl Regularization {
+    pub fn is_none(&self) -> bool {
+        self.l1 == 0.0 && self.l2 == 0.0
+    }
+
+    pub fn penalty(&self, parameters: &[f64]) -> f64 {
+        parameters
+            .iter()
+            .map(|w| self.l1 * w.abs() + self.l2 / 2.0 * w * w)
+            .sum()
+    }
+
+    // adds scale times the gradient of the penalty
+    pub fn add_gradients(&self, parameters: &[f64], gradients: &mut [f64], scale: f64) {
+        for (gradient, w) in gradients.iter_mut().zip(parameters) {
+            let sign = if *w == 0.0 { 0.0 } else { w.signum() };
+            *gradient += scale * (self.l1 * sign + self.l2 * w);
+        }
+    }
+}
+
+// a step of a Sequential model, taking and producing flat vectors laid out as its shapes say
+// inference goes one sample at a time, training a batch at a time
+pub trait Layer: fmt::Debug + fmt::Display + Send + Sync {
+    fn input_shape(&self) -> Shape;
+
+    fn output_shape(&self) -> Shape;
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64>;
+
+    // layers that act differently while training override this,
+    // remembering whatever backward needs
+    fn forward_train(&mut self, inputs: &[Vec<f64>], _rng: &mut StdRng) -> Vec<Vec<f64>> {
+        inputs.iter().map(|x| self.forward(x)).collect()
+    }
+
+    // adds the gradients of the parameters, summed over the batch, to gradients and returns
+    // the gradients of the inputs, given the inputs and outputs of forward_train
+    // and the gradients of the loss by the outputs
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>>;
+
+    fn parameters(&self) -> Vec<&[f64]> {
+        Vec::new()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [f64]> {
+        Vec::new()
+    }
+
+    // None for layers that pass their results on as they are
+    fn activation(&self) -> Option<Activation> {
+        None
+    }
+
+    // penalties on the weights, layers without weights have none
+    fn regularization(&self) -> Regularization {
+        Regularization::default()
+    }
+
+    fn set_regularization(&mut self, _regularization: Regularization) -> Result<(), String> {
+        Err(format!("{} has no weights to regularize", self))
+    }
+
+    // which of parameters() the penalties and weight decay apply to
+    fn penalized(&self) -> Vec<bool> {
+        vec![false; self.parameters().len()]
+    }
+
+    // the regularization term the layer adds to the loss
+    fn penalty(&self) -> f64 {
+        let regularization = self.regularization();
+        self.parameters()
+            .iter()
+            .zip(self.penalized())
+            .filter(|(_, penalized)| *penalized)
+            .map(|(parameters, _)| regularization.penalty(parameters))
+            .sum()
+    }
+
+    // adds scale times the gradients of penalty() to gradients
+    fn add_penalty_gradients(&self, gradients: &mut [Vec<f64>], scale: f64) {
+        let regularization = self.regularization();
+        if regularization.is_none() {
+            return;
+        }
+        let parameters = self.parameters();
+        for ((parameters, gradients), penalized) in
+            parameters.iter().zip(gradients).zip(self.penalized())
+        {
+            if penalized {
+                regularization.add_gradients(parameters, gradients, scale);
+            }
+        }
+    }
+
+    // the config that builds a layer of the same shape
+    fn to_config(&self) -> LayerConfig;
+
+    // the form the layer is saved in
+    fn to_data(&self) -> LayerData;
+
+    fn input_size(&self) -> usize {
+        self.input_shape().size()
+    }
+
+    fn output_size(&self) -> usize {
+        self.output_shape().size()
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.parameters().iter().map(|p| p.len()).sum()
+    }
+
+    // zeroed buffers matching parameters(), for backward to add to
+    fn zero_gradients(&self) -> Vec<Vec<f64>> {
+        self.parameters()
+            .iter()
+            .map(|p| vec![0.0; p.len()])
+            .collect()
+    }
+}
+
+// every kind of layer as it's saved in model files
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayerData {
+    Dense(Dense),
+    Conv2d(Conv2d),
+    MaxPool2d(Pool2d),
+    AvgPool2d(Pool2d),
+    GlobalAvgPool(GlobalAvgPool),
+    Flatten(Reshape),
+    Reshape(Reshape),
+    Dropout(Dropout),
+    BatchNorm(BatchNorm),
+    LayerNorm(LayerNorm),
+    Residual(ResidualData),
+}
+
+impl LayerData {
+    // checks that the stored parameters fit the shape of the layer
+    pub fn into_layer(self) -> Result<Box<dyn Layer>, String> {
+        Ok(match self {
+            // dense layers are checked when they are read
+            LayerData::Dense(dense) => Box::new(dense),
+            LayerData::Conv2d(conv) => {
+                conv.check()?;
+                Box::new(conv)
+            }
+            LayerData::MaxPool2d(pool) => {
+                pool.check()?;
+                Box::new(MaxPool2d(pool))
+            }
+            LayerData::AvgPool2d(pool) => {
+                pool.check()?;
+                Box::new(AvgPool2d(pool))
+            }
+            LayerData::GlobalAvgPool(pool) => Box::new(pool),
+            LayerData::Flatten(reshape) | LayerData::Reshape(reshape) => {
+                reshape.check()?;
+                Box::new(reshape)
+            }
+            LayerData::Dropout(dropout) => {
+                dropout.check()?;
+                Box::new(dropout)
+            }
+            LayerData::BatchNorm(norm) => {
+                norm.check()?;
+                Box::new(norm)
+            }
+            LayerData::LayerNorm(norm) => {
+                norm.check()?;
+                Box::new(norm)
+            }
+            LayerData::Residual(residual) => Box::new(residual.into_residual()?),
+        })
+    }
+}
+
+// runs backward of a layer that handles every sample on its own
+fn backward_samples(
+    inputs: &[Vec<f64>],
+    outputs: &[Vec<f64>],
+    output_gradients: Vec<Vec<f64>>,
+    mut backward: impl FnMut(&[f64], &[f64], Vec<f64>) -> Vec<f64>,
+) -> Vec<Vec<f64>> {
+    inputs
+        .iter()
+        .zip(outputs.iter())
+        .zip(output_gradients)
+        .map(|((x, y), g)| backward(x, y, g))
+        .collect()
+}
+
+fn describe(f: &mut fmt::Formatter, layer: &dyn Layer, name: fmt::Arguments) -> fmt::Result {
+    write!(
+        f,
+        "{} {} -> {}",
+        name,
+        layer.input_shape(),
+        layer.output_shape()
+    )?;
+    if let Some(activation) = layer.activation() {
+        write!(f, ", {}", activation.name())?;
+    }
+    Ok(())
+}
+
+// fully connected layer, outputs = activation(weights * inputs + biases)
+#[derive(Debug, Clone)]
+pub struct Dense {
+    weights: Matrix<f64>,
+    biases: Matrix<f64>,
+    activation: Activation,
+    regularization: Regularization,
+}
+
+impl Dense {
+    // weights and biases drawn from -1 to 1
+    pub fn new<R: Rng>(inputs: usize, outputs: usize, activation: Activation, rng: &mut R) -> Self {
+        let mut init = |_, _| rng.gen::<f64>() - rng.gen::<f64>();
+        Dense {
+            weights: Matrix::from_fn(outputs, inputs, &mut init),
+            biases: Matrix::from_fn(outputs, 1, &mut init),
+            activation,
+            regularization: Regularization::default(),
+        }
+    }
+}
+
+impl Layer for Dense {
+    fn input_shape(&self) -> Shape {
+        Shape::flat(self.weights.cols())
+    }
+
+    fn output_shape(&self) -> Shape {
+        Shape::flat(self.weights.rows())
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        let inputs = Matrix::new(inputs.len(), 1, inputs.to_vec());
+        self.activation
+            .activate((&self.weights * inputs + &self.biases).into_vec())
+    }
+
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let cols = self.weights.cols();
+        backward_samples(inputs, outputs, output_gradients, |inputs, outputs, g| {
+            let deltas = self.activation.backward(outputs, g);
+            for (row, delta) in deltas.iter().enumerate() {
+                let weight_gradients = &mut gradients[0][row * cols..(row + 1) * cols];
+                for (gradient, input) in weight_gradients.iter_mut().zip(inputs.iter()) {
+                    *gradient += delta * input;
+                }
+                gradients[1][row] += delta;
+            }
+            let deltas = Matrix::new(deltas.len(), 1, deltas);
+            (self.weights.transpose() * deltas).into_vec()
+        })
+    }
+
+    fn parameters(&self) -> Vec<&[f64]> {
+        vec![self.weights.data(), self.biases.data()]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [f64]> {
+        vec![self.weights.mut_data(), self.biases.mut_data()]
+    }
+
+    fn activation(&self) -> Option<Activation> {
+        Some(self.activation)
+    }
+
+    fn regularization(&self) -> Regularization {
+        self.regularization
+    }
+
+    fn set_regularization(&mut self, regularization: Regularization) -> Result<(), String> {
+        self.regularization = regularization;
+        Ok(())
+    }
+
+    fn penalized(&self) -> Vec<bool> {
+        vec![true, self.regularization.biases]
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig {
+            regularization: Some(self.regularization).filter(|r| !r.is_none()),
+            ..LayerConfig::dense(self.weights.rows(), self.activation)
+        }
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::Dense(self.clone())
+    }
+}
+
+impl fmt::Display for Dense {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self, format_args!("dense"))
+    }
+}
+
+// saved the same way as the weights and biases of models without layers
+#[derive(Serialize, Deserialize)]
+pub(crate) struct WeightData {
+    pub rows: usize,
+    pub cols: usize,
+    pub data: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BiasData {
+    pub rows: usize,
+    // cols is always 1
+    pub data: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DenseData {
+    #[serde(default)]
+    activation: Activation,
+    #[serde(default, skip_serializing_if = "Regularization::is_none")]
+    regularization: Regularization,
+    weights: WeightData,
+    biases: BiasData,
+}
+
+impl Dense {
+    pub(crate) fn from_data(
+        weights: WeightData,
+        biases: BiasData,
+        activation: Activation,
+    ) -> Result<Self, String> {
+        if weights.data.len() != weights.rows * weights.cols {
+            return Err(format!(
+                "Dense layer has {} weights but needs {}x{}",
+                weights.data.len(),
+                weights.rows,
+                weights.cols
+            ));
+        }
+        if biases.rows != weights.rows || biases.data.len() != biases.rows {
+            return Err(format!(
+                "Dense layer has {} outputs but {} biases",
+                weights.rows,
+                biases.data.len()
+            ));
+        }
+        Ok(Dense {
+            weights: Matrix::new(weights.rows, weights.cols, weights.data),
+            biases: Matrix::new(biases.rows, 1, biases.data),
+            activation,
+            regularization: Regularization::default(),
+        })
+    }
+}
+
+impl TryFrom<DenseData> for Dense {
+    type Error = String;
+
+    fn try_from(data: DenseData) -> Result<Dense, String> {
+        let mut dense = Dense::from_data(data.weights, data.biases, data.activation)?;
+        dense.regularization = data.regularization;
+        Ok(dense)
+    }
+}
+
+impl From<Dense> for DenseData {
+    fn from(dense: Dense) -> DenseData {
+        DenseData {
+            activation: dense.activation,
+            regularization: dense.regularization,
+            weights: WeightData {
+                rows: dense.weights.rows(),
+                cols: dense.weights.cols(),
+                data: dense.weights.into_vec(),
+            },
+            biases: BiasData {
+                rows: dense.biases.rows(),
+                data: dense.biases.into_vec(),
+            },
+        }
+    }
+}
+
+impl Serialize for Dense {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DenseData::from(self.clone()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dense {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Dense::try_from(DenseData::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+// 2-D convolution of every filter over all input channels,
+// each filter producing one output channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conv2d {
+    input: Shape,
+    filters: usize,
+    kernel_size: usize,
+    stride: usize,
+    // zeros added around every side of the inputs
+    padding: usize,
+    activation: Activation,
+    #[serde(default, skip_serializing_if = "Regularization::is_none")]
+    regularization: Regularization,
+    // filters x channels x kernel_size x kernel_size
+    weights: Vec<f64>,
+    biases: Vec<f64>,
+}
+
+impl Conv2d {
+    pub fn new<R: Rng>(
+        input: Shape,
+        filters: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+        activation: Activation,
+        rng: &mut R,
+    ) -> Result<Self, String> {
+        // drawn from -1 to 1 like the weights of dense layers
+        let fan_in = input.channels * kernel_size * kernel_size;
+        let weights = (0..filters * fan_in)
+            .map(|_| rng.gen::<f64>() - rng.gen::<f64>())
+            .collect();
+        let biases = (0..filters)
+            .map(|_| rng.gen::<f64>() - rng.gen::<f64>())
+            .collect();
+        let conv = Conv2d {
+            input,
+            filters,
+            kernel_size,
+            stride,
+            padding,
+            activation,
+            regularization: Regularization::default(),
+            weights,
+            biases,
+        };
+        conv.check()?;
+        Ok(conv)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let fan_in = self.input.channels * self.kernel_size * self.kernel_size;
+        if self.kernel_size == 0 || self.stride == 0 {
+            return Err("Kernel size and stride of a convolution need to be at least 1".into());
+        }
+        let (height, width) = (self.input.height, self.input.width);
+        if self.kernel_size > height + 2 * self.padding
+            || self.kernel_size > width + 2 * self.padding
+        {
+            return Err(format!(
+                "Kernel size {} is larger than the {}x{} inputs with padding {}",
+                self.kernel_size, height, width, self.padding
+            ));
+        }
+        if self.weights.len() != self.filters * fan_in || self.biases.len() != self.filters {
+            return Err(format!(
+                "Convolution with {} filters of {}x{}x{} has {} weights and {} biases",
+                self.filters,
+                self.input.channels,
+                self.kernel_size,
+                self.kernel_size,
+                self.weights.len(),
+                self.biases.len()
+            ));
+        }
+        Ok(())
+    }
+
+    // calls f with the weight index, first output index, first input index and length of
+    // every row of outputs that a kernel position connects to inputs, consecutive outputs
+    // of a row take inputs stride apart
+    fn for_each_row(&self, mut f: impl FnMut(usize, usize, usize, usize)) {
+        let output = self.output_shape();
+        let (height, width) = (self.input.height, self.input.width);
+        let k = self.kernel_size;
+        for filter in 0..self.filters {
+            for channel in 0..self.input.channels {
+                for ky in 0..k {
+                    for kx in 0..k {
+                        let w = ((filter * self.input.channels + channel) * k + ky) * k + kx;
+                        let columns = self.valid_outputs(width, output.width, kx);
+                        if columns.is_empty() {
+                            continue;
+                        }
+                        let ix = columns.start * self.stride + kx - self.padding;
+                        for oy in self.valid_outputs(height, output.height, ky) {
+                            let iy = oy * self.stride + ky - self.padding;
+                            f(
+                                w,
+                                (filter * output.height + oy) * output.width + columns.start,
+                                (channel * height + iy) * width + ix,
+                                columns.len(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // outputs along one axis for which the kernel offset lands inside the inputs,
+    // so that 0 <= output * stride + offset - padding < inputs
+    fn valid_outputs(&self, inputs: usize, outputs: usize, offset: usize) -> Range<usize> {
+        let first = self.padding.saturating_sub(offset).div_ceil(self.stride);
+        let end = if inputs + self.padding > offset {
+            ((inputs + self.padding - offset - 1) / self.stride + 1).min(outputs)
+        } else {
+            0
+        };
+        first..end.max(first)
+    }
+}
+
+impl Layer for Conv2d {
+    fn input_shape(&self) -> Shape {
+        self.input
+    }
+
+    fn output_shape(&self) -> Shape {
+        let side = |size: usize| (size + 2 * self.padding - self.kernel_size) / self.stride + 1;
+        Shape::new(
+            self.filters,
+            side(self.input.height),
+            side(self.input.width),
+        )
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        let output = self.output_shape();
+        let area = output.height * output.width;
+        let mut sums: Vec<f64> = (0..output.size()).map(|o| self.biases[o / area]).collect();
+        self.for_each_row(|w, o, i, len| {
+            let weight = self.weights[w];
+            let inputs = inputs[i..].iter().step_by(self.stride);
+            for (sum, input) in sums[o..o + len].iter_mut().zip(inputs) {
+                *sum += weight * input;
+            }
+        });
+        self.activation.activate(sums)
+    }
+
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let area = self.output_size() / self.filters;
+        backward_samples(inputs, outputs, output_gradients, |inputs, outputs, g| {
+            let deltas = self.activation.backward(outputs, g);
+            for (o, delta) in deltas.iter().enumerate() {
+                gradients[1][o / area] += delta;
+            }
+            let mut input_gradient = vec![0.0; inputs.len()];
+            let weight_gradients = &mut gradients[0];
+            self.for_each_row(|w, o, i, len| {
+                let weight = self.weights[w];
+                let deltas = &deltas[o..o + len];
+                let inputs = inputs[i..].iter().step_by(self.stride);
+                weight_gradients[w] += deltas.iter().zip(inputs).map(|(d, x)| d * x).sum::<f64>();
+                let input_gradient = input_gradient[i..].iter_mut().step_by(self.stride);
+                for (gradient, delta) in input_gradient.zip(deltas) {
+                    *gradient += delta * weight;
+                }
+            });
+            input_gradient
+        })
+    }
+
+    fn parameters(&self) -> Vec<&[f64]> {
+        vec![&self.weights, &self.biases]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [f64]> {
+        vec![&mut self.weights, &mut self.biases]
+    }
+
+    fn activation(&self) -> Option<Activation> {
+        Some(self.activation)
+    }
+
+    fn regularization(&self) -> Regularization {
+        self.regularization
+    }
+
+    fn set_regularization(&mut self, regularization: Regularization) -> Result<(), String> {
+        self.regularization = regularization;
+        Ok(())
+    }
+
+    fn penalized(&self) -> Vec<bool> {
+        vec![true, self.regularization.biases]
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig {
+            kind: LayerKind::Conv2d,
+            size: self.filters,
+            activation: Some(self.activation),
+            kernel_size: Some(self.kernel_size),
+            stride: Some(self.stride),
+            padding: Some(self.padding),
+            regularization: Some(self.regularization).filter(|r| !r.is_none()),
+            ..LayerConfig::empty(LayerKind::Conv2d)
+        }
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::Conv2d(self.clone())
+    }
+}
+
+impl fmt::Display for Conv2d {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(
+            f,
+            self,
+            format_args!(
+                "conv2d {}x{} stride {} padding {}",
+                self.kernel_size, self.kernel_size, self.stride, self.padding
+            ),
+        )
+    }
+}
+
+// size x size windows of every channel, shared by max and average pooling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pool2d {
+    input: Shape,
+    size: usize,
+    stride: usize,
+}
+
+impl Pool2d {
+    pub fn new(input: Shape, size: usize, stride: usize) -> Result<Self, String> {
+        let pool = Pool2d {
+            input,
+            size,
+            stride,
+        };
+        pool.check()?;
+        Ok(pool)
+    }
+
+    fn output_shape(&self) -> Shape {
+        let side = |size: usize| (size - self.size) / self.stride + 1;
+        Shape::new(
+            self.input.channels,
+            side(self.input.height),
+            side(self.input.width),
+        )
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.size == 0 || self.stride == 0 {
+            return Err("Window size and stride of a pooling layer need to be at least 1".into());
+        }
+        if self.size > self.input.height || self.size > self.input.width {
+            return Err(format!(
+                "Pooling window {} is larger than the {}x{} inputs",
+                self.size, self.input.height, self.input.width
+            ));
+        }
+        Ok(())
+    }
+
+    // input indices of the window of every output
+    fn windows(&self) -> impl Iterator<Item = Vec<usize>> + '_ {
+        let output = self.output_shape();
+        let (height, width) = (self.input.height, self.input.width);
+        (0..output.size()).map(move |o| {
+            let channel = o / (output.height * output.width);
+            let oy = o / output.width % output.height;
+            let ox = o % output.width;
+            let mut window = Vec::with_capacity(self.size * self.size);
+            for y in oy * self.stride..oy * self.stride + self.size {
+                let row = (channel * height + y) * width;
+                window.extend(row + ox * self.stride..row + ox * self.stride + self.size);
+            }
+            window
+        })
+    }
+
+    fn to_config(&self, kind: LayerKind) -> LayerConfig {
+        LayerConfig {
+            kind,
+            size: self.size,
+            stride: Some(self.stride),
+            ..LayerConfig::empty(kind)
+        }
+    }
+}
+
+// downsamples every channel to the largest value of each window
+#[derive(Debug, Clone)]
+pub struct MaxPool2d(pub Pool2d);
+
+impl Layer for MaxPool2d {
+    fn input_shape(&self) -> Shape {
+        self.0.input
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.0.output_shape()
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        self.0
+            .windows()
+            .map(|window| {
+                window
+                    .into_iter()
+                    .map(|i| inputs[i])
+                    .fold(f64::NEG_INFINITY, f64::max)
+            })
+            .collect()
+    }
+
+    // the whole gradient goes to the largest input of the window, the first one on ties
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        _gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        backward_samples(inputs, outputs, output_gradients, |inputs, _, deltas| {
+            let mut input_gradient = vec![0.0; inputs.len()];
+            for (window, delta) in self.0.windows().zip(deltas) {
+                let largest = window
+                    .into_iter()
+                    .reduce(|a, b| if inputs[b] > inputs[a] { b } else { a })
+                    .expect("Windows aren't empty.");
+                input_gradient[largest] += delta;
+            }
+            input_gradient
+        })
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        self.0.to_config(LayerKind::MaxPool2d)
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::MaxPool2d(self.0.clone())
+    }
+}
+
+impl fmt::Display for MaxPool2d {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pool = &self.0;
+        let name = format_args!(
+            "max_pool2d {}x{} stride {}",
+            pool.size, pool.size, pool.stride
+        );
+        describe(f, self, name)
+    }
+}
+
+// downsamples every channel to the mean value of each window
+#[derive(Debug, Clone)]
+pub struct AvgPool2d(pub Pool2d);
+
+impl Layer for AvgPool2d {
+    fn input_shape(&self) -> Shape {
+        self.0.input
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.0.output_shape()
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        let area = (self.0.size * self.0.size) as f64;
+        self.0
+            .windows()
+            .map(|window| window.into_iter().map(|i| inputs[i]).sum::<f64>() / area)
+            .collect()
+    }
+
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        _gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let area = (self.0.size * self.0.size) as f64;
+        backward_samples(inputs, outputs, output_gradients, |inputs, _, deltas| {
+            let mut input_gradient = vec![0.0; inputs.len()];
+            for (window, delta) in self.0.windows().zip(deltas) {
+                for i in window {
+                    input_gradient[i] += delta / area;
+                }
+            }
+            input_gradient
+        })
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        self.0.to_config(LayerKind::AvgPool2d)
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::AvgPool2d(self.0.clone())
+    }
+}
+
+impl fmt::Display for AvgPool2d {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pool = &self.0;
+        let name = format_args!(
+            "avg_pool2d {}x{} stride {}",
+            pool.size, pool.size, pool.stride
+        );
+        describe(f, self, name)
+    }
+}
+
+// mean of every channel, turning feature maps into one value per channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GlobalAvgPool {
+    input: Shape,
+}
+
+impl GlobalAvgPool {
+    pub fn new(input: Shape) -> Self {
+        GlobalAvgPool { input }
+    }
+}
+
+impl Layer for GlobalAvgPool {
+    fn input_shape(&self) -> Shape {
+        self.input
+    }
+
+    fn output_shape(&self) -> Shape {
+        Shape::flat(self.input.channels)
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        let area = self.input.height * self.input.width;
+        inputs
+            .chunks(area)
+            .map(|channel| channel.iter().sum::<f64>() / area as f64)
+            .collect()
+    }
+
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        _gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let area = self.input.height * self.input.width;
+        backward_samples(inputs, outputs, output_gradients, |_, _, deltas| {
+            deltas
+                .iter()
+                .flat_map(|delta| std::iter::repeat_n(delta / area as f64, area))
+                .collect()
+        })
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig::empty(LayerKind::GlobalAvgPool)
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::GlobalAvgPool(self.clone())
+    }
+}
+
+impl fmt::Display for GlobalAvgPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self, format_args!("global_avg_pool"))
+    }
+}
+
+// changes the shape values are read as without changing the values,
+// flatten turns feature maps into the flat vector dense layers take
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reshape {
+    input: Shape,
+    output: Shape,
+}
+
+impl Reshape {
+    pub fn new(input: Shape, output: Shape) -> Result<Self, String> {
+        let reshape = Reshape { input, output };
+        reshape.check()?;
+        Ok(reshape)
+    }
+
+    pub fn flatten(input: Shape) -> Self {
+        Reshape {
+            input,
+            output: Shape::flat(input.size()),
+        }
+    }
+
+    fn is_flatten(&self) -> bool {
+        self.output == Shape::flat(self.input.size())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.input.size() != self.output.size() {
+            return Err(format!(
+                "Can't reshape {} values into {}",
+                self.input.size(),
+                self.output
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Layer for Reshape {
+    fn input_shape(&self) -> Shape {
+        self.input
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.output
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        inputs.to_vec()
+    }
+
+    fn backward(
+        &self,
+        _inputs: &[Vec<f64>],
+        _outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        _gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        output_gradients
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        if self.is_flatten() {
+            LayerConfig::empty(LayerKind::Flatten)
+        } else {
+            LayerConfig {
+                shape: Some(self.output),
+                ..LayerConfig::empty(LayerKind::Reshape)
+            }
+        }
+    }
+
+    fn to_data(&self) -> LayerData {
+        if self.is_flatten() {
+            LayerData::Flatten(self.clone())
+        } else {
+            LayerData::Reshape(self.clone())
+        }
+    }
+}
+
+impl fmt::Display for Reshape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.is_flatten() {
+            "flatten"
+        } else {
+            "reshape"
+        };
+        describe(f, self, format_args!("{}", name))
+    }
+}
+
+// zeroes every input with probability rate while training and scales the kept ones by
+// 1 / (1 - rate), so that inference can pass the inputs on as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dropout {
+    shape: Shape,
+    rate: f64,
+    // inputs kept in the last training batch
+    #[serde(skip)]
+    masks: Vec<Vec<bool>>,
+}
+
+impl Dropout {
+    pub fn new(shape: Shape, rate: f64) -> Result<Self, String> {
+        let dropout = Dropout {
+            shape,
+            rate,
+            masks: Vec::new(),
+        };
+        dropout.check()?;
+        Ok(dropout)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !(0.0..1.0).contains(&self.rate) {
+            return Err(format!(
+                "Dropout rate needs to be from 0 up to 1, not {}",
+                self.rate
+            ));
+        }
+        Ok(())
+    }
+
+    fn scale(&self) -> f64 {
+        1.0 / (1.0 - self.rate)
+    }
+}
+
+impl Layer for Dropout {
+    fn input_shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        inputs.to_vec()
+    }
+
+    fn forward_train(&mut self, inputs: &[Vec<f64>], rng: &mut StdRng) -> Vec<Vec<f64>> {
+        let rate = self.rate;
+        self.masks = inputs
+            .iter()
+            .map(|x| x.iter().map(|_| rng.gen::<f64>() >= rate).collect())
+            .collect();
+        let scale = self.scale();
+        inputs
+            .iter()
+            .zip(self.masks.iter())
+            .map(|(x, mask)| {
+                x.iter()
+                    .zip(mask)
+                    .map(|(x, keep)| if *keep { x * scale } else { 0.0 })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn backward(
+        &self,
+        _inputs: &[Vec<f64>],
+        _outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        _gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let scale = self.scale();
+        output_gradients
+            .into_iter()
+            .zip(self.masks.iter())
+            .map(|(g, mask)| {
+                g.into_iter()
+                    .zip(mask)
+                    .map(|(g, keep)| if *keep { g * scale } else { 0.0 })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig {
+            rate: Some(self.rate),
+            ..LayerConfig::empty(LayerKind::Dropout)
+        }
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::Dropout(self.clone())
+    }
+}
+
+impl fmt::Display for Dropout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self, format_args!("dropout {}", self.rate))
+    }
+}
+
+// normalizes every channel to zero mean and unit variance over the batch while training,
+// and by the running averages of those in inference, then scales and shifts it by the
+// learned scale and shift, values of flat inputs each being a channel of their own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchNorm {
+    shape: Shape,
+    // weight of every new batch in the running averages
+    momentum: f64,
+    epsilon: f64,
+    scale: Vec<f64>,
+    shift: Vec<f64>,
+    running_mean: Vec<f64>,
+    running_variance: Vec<f64>,
+    // normalized inputs and 1 / standard deviation of every channel in the last training batch
+    #[serde(skip)]
+    normalized: Vec<Vec<f64>>,
+    #[serde(skip)]
+    inverse_deviations: Vec<f64>,
+}
+
+impl BatchNorm {
+    pub fn new(shape: Shape, momentum: f64) -> Result<Self, String> {
+        let norm = BatchNorm {
+            shape,
+            momentum,
+            epsilon: 1e-5,
+            scale: vec![1.0; shape.channels],
+            shift: vec![0.0; shape.channels],
+            running_mean: vec![0.0; shape.channels],
+            running_variance: vec![1.0; shape.channels],
+            normalized: Vec::new(),
+            inverse_deviations: Vec::new(),
+        };
+        norm.check()?;
+        Ok(norm)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.momentum) {
+            return Err(format!(
+                "Batch normalization momentum needs to be from 0 to 1, not {}",
+                self.momentum
+            ));
+        }
+        let channels = self.shape.channels;
+        if [
+            &self.scale,
+            &self.shift,
+            &self.running_mean,
+            &self.running_variance,
+        ]
+        .iter()
+        .any(|values| values.len() != channels)
+        {
+            return Err(format!(
+                "Batch normalization of {} channels needs {} values of every statistic",
+                channels, channels
+            ));
+        }
+        Ok(())
+    }
+
+    fn area(&self) -> usize {
+        self.shape.height * self.shape.width
+    }
+}
+
+impl Layer for BatchNorm {
+    fn input_shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        inputs
+            .chunks(self.area())
+            .enumerate()
+            .flat_map(|(c, channel)| {
+                let inverse_deviation = 1.0 / (self.running_variance[c] + self.epsilon).sqrt();
+                channel.iter().map(move |x| {
+                    self.scale[c] * (x - self.running_mean[c]) * inverse_deviation + self.shift[c]
+                })
+            })
+            .collect()
+    }
+
+    fn forward_train(&mut self, inputs: &[Vec<f64>], _rng: &mut StdRng) -> Vec<Vec<f64>> {
+        let area = self.area();
+        let count = (inputs.len() * area) as f64;
+        let mut means = vec![0.0; self.shape.channels];
+        let mut variances = vec![0.0; self.shape.channels];
+        for x in inputs {
+            for (c, channel) in x.chunks(area).enumerate() {
+                means[c] += channel.iter().sum::<f64>() / count;
+            }
+        }
+        for x in inputs {
+            for (c, channel) in x.chunks(area).enumerate() {
+                variances[c] += channel.iter().map(|x| (x - means[c]).powi(2)).sum::<f64>() / count;
+            }
+        }
+        // the running variance is the unbiased estimate of the batch variances
+        let correction = if count > 1.0 {
+            count / (count - 1.0)
+        } else {
+            1.0
+        };
+        for c in 0..self.shape.channels {
+            self.running_mean[c] += self.momentum * (means[c] - self.running_mean[c]);
+            self.running_variance[c] +=
+                self.momentum * (variances[c] * correction - self.running_variance[c]);
+        }
+        self.inverse_deviations = variances
+            .iter()
+            .map(|variance| 1.0 / (variance + self.epsilon).sqrt())
+            .collect();
+        self.normalized = inputs
+            .iter()
+            .map(|x| {
+                x.chunks(area)
+                    .enumerate()
+                    .flat_map(|(c, channel)| {
+                        let (mean, inverse_deviation) = (means[c], self.inverse_deviations[c]);
+                        channel.iter().map(move |x| (x - mean) * inverse_deviation)
+                    })
+                    .collect()
+            })
+            .collect();
+        let (scale, shift) = (&self.scale, &self.shift);
+        self.normalized
+            .iter()
+            .map(|x| {
+                x.chunks(area)
+                    .enumerate()
+                    .flat_map(|(c, channel)| channel.iter().map(move |x| scale[c] * x + shift[c]))
+                    .collect()
+            })
+            .collect()
+    }
+
+    // the mean and variance depend on every sample of the batch, so every input gets
+    // scale / deviation * (g - mean(g) - normalized * mean(g * normalized))
+    fn backward(
+        &self,
+        _inputs: &[Vec<f64>],
+        _outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let area = self.area();
+        let count = (output_gradients.len() * area) as f64;
+        let mut gradient_sums = vec![0.0; self.shape.channels];
+        let mut products = vec![0.0; self.shape.channels];
+        for (g, normalized) in output_gradients.iter().zip(self.normalized.iter()) {
+            for (c, (g, normalized)) in g.chunks(area).zip(normalized.chunks(area)).enumerate() {
+                gradient_sums[c] += g.iter().sum::<f64>();
+                products[c] += g.iter().zip(normalized).map(|(g, x)| g * x).sum::<f64>();
+            }
+        }
+        for c in 0..self.shape.channels {
+            gradients[0][c] += products[c];
+            gradients[1][c] += gradient_sums[c];
+        }
+        output_gradients
+            .iter()
+            .zip(self.normalized.iter())
+            .map(|(g, normalized)| {
+                g.chunks(area)
+                    .zip(normalized.chunks(area))
+                    .enumerate()
+                    .flat_map(|(c, (g, normalized))| {
+                        let factor = self.scale[c] * self.inverse_deviations[c];
+                        let (mean, product) = (gradient_sums[c] / count, products[c] / count);
+                        g.iter()
+                            .zip(normalized)
+                            .map(move |(g, x)| factor * (g - mean - x * product))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn parameters(&self) -> Vec<&[f64]> {
+        vec![&self.scale, &self.shift]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [f64]> {
+        vec![&mut self.scale, &mut self.shift]
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig {
+            momentum: Some(self.momentum),
+            ..LayerConfig::empty(LayerKind::BatchNorm)
+        }
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::BatchNorm(self.clone())
+    }
+}
+
+impl fmt::Display for BatchNorm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self, format_args!("batch_norm"))
+    }
+}
+
+// normalizes every sample to zero mean and unit variance over all of its values,
+// then scales and shifts every value by its own learned scale and shift
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerNorm {
+    shape: Shape,
+    epsilon: f64,
+    scale: Vec<f64>,
+    shift: Vec<f64>,
+}
+
+impl LayerNorm {
+    pub fn new(shape: Shape) -> Self {
+        LayerNorm {
+            shape,
+            epsilon: 1e-5,
+            scale: vec![1.0; shape.size()],
+            shift: vec![0.0; shape.size()],
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let size = self.shape.size();
+        if self.scale.len() != size || self.shift.len() != size {
+            return Err(format!(
+                "Layer normalization of {} values has {} scales and {} shifts",
+                size,
+                self.scale.len(),
+                self.shift.len()
+            ));
+        }
+        Ok(())
+    }
+
+    // the normalized inputs and 1 / their standard deviation
+    fn normalize(&self, inputs: &[f64]) -> (Vec<f64>, f64) {
+        let count = inputs.len() as f64;
+        let mean = inputs.iter().sum::<f64>() / count;
+        let variance = inputs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / count;
+        let inverse_deviation = 1.0 / (variance + self.epsilon).sqrt();
+        let normalized = inputs
+            .iter()
+            .map(|x| (x - mean) * inverse_deviation)
+            .collect();
+        (normalized, inverse_deviation)
+    }
+}
+
+impl Layer for LayerNorm {
+    fn input_shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        let (normalized, _) = self.normalize(inputs);
+        normalized
+            .iter()
+            .zip(self.scale.iter().zip(self.shift.iter()))
+            .map(|(x, (scale, shift))| scale * x + shift)
+            .collect()
+    }
+
+    // every input gets scale / deviation * (g - mean(g) - normalized * mean(g * normalized))
+    // with g being the gradients times the scales
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        backward_samples(inputs, outputs, output_gradients, |inputs, _, g| {
+            let (normalized, inverse_deviation) = self.normalize(inputs);
+            for (k, (g, x)) in g.iter().zip(normalized.iter()).enumerate() {
+                gradients[0][k] += g * x;
+                gradients[1][k] += g;
+            }
+            let scaled: Vec<f64> = g
+                .iter()
+                .zip(self.scale.iter())
+                .map(|(g, s)| g * s)
+                .collect();
+            let count = scaled.len() as f64;
+            let mean = scaled.iter().sum::<f64>() / count;
+            let product = scaled
+                .iter()
+                .zip(normalized.iter())
+                .map(|(g, x)| g * x)
+                .sum::<f64>()
+                / count;
+            scaled
+                .iter()
+                .zip(normalized.iter())
+                .map(|(g, x)| inverse_deviation * (g - mean - x * product))
+                .collect()
+        })
+    }
+
+    fn parameters(&self) -> Vec<&[f64]> {
+        vec![&self.scale, &self.shift]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [f64]> {
+        vec![&mut self.scale, &mut self.shift]
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig::empty(LayerKind::LayerNorm)
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::LayerNorm(self.clone())
+    }
+}
+
+impl fmt::Display for LayerNorm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self, format_args!("layer_norm"))
+    }
+}
+
+// outputs f(x) + x for a stack of layers f, the inputs going through a linear
+// dense projection first when f changes their size
+#[derive(Debug)]
+pub struct Residual {
+    layers: Vec<Box<dyn Layer>>,
+    projection: Option<Dense>,
+    // inputs of every layer followed by the outputs of the last one,
+    // and the projected inputs, in the last training batch
+    activations: Vec<Vec<Vec<f64>>>,
+    projected: Vec<Vec<f64>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResidualData {
+    layers: Vec<LayerData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    projection: Option<Dense>,
+}
+
+impl Residual {
+    // layers need to take what the one before them outputs
+    pub fn new<R: Rng>(layers: Vec<Box<dyn Layer>>, rng: &mut R) -> Result<Self, String> {
+        let first = layers
+            .first()
+            .ok_or("Residual blocks need at least one layer")?;
+        let inputs = first.input_size();
+        let outputs = layers[layers.len() - 1].output_size();
+        let projection =
+            (inputs != outputs).then(|| Dense::new(inputs, outputs, Activation::Identity, rng));
+        Residual::from_parts(layers, projection)
+    }
+
+    fn from_parts(layers: Vec<Box<dyn Layer>>, projection: Option<Dense>) -> Result<Self, String> {
+        if layers.is_empty() {
+            return Err("Residual blocks need at least one layer".to_string());
+        }
+        for (i, pair) in layers.windows(2).enumerate() {
+            if pair[0].output_size() != pair[1].input_size() {
+                return Err(format!(
+                    "Layer {} of the residual block outputs {} values but layer {} takes {}",
+                    i + 1,
+                    pair[0].output_size(),
+                    i + 2,
+                    pair[1].input_size()
+                ));
+            }
+        }
+        let inputs = layers[0].input_size();
+        let outputs = layers[layers.len() - 1].output_size();
+        let (shortcut_inputs, shortcut_outputs) = projection
+            .as_ref()
+            .map_or((inputs, inputs), |p| (p.input_size(), p.output_size()));
+        if shortcut_inputs != inputs || shortcut_outputs != outputs {
+            return Err(format!(
+                "Residual block takes {} values and outputs {} but its shortcut goes from {} to {}",
+                inputs, outputs, shortcut_inputs, shortcut_outputs
+            ));
+        }
+        Ok(Residual {
+            layers,
+            projection,
+            activations: Vec::new(),
+            projected: Vec::new(),
+        })
+    }
+
+    fn shortcut(&self, inputs: &[f64]) -> Vec<f64> {
+        match &self.projection {
+            Some(projection) => projection.forward(inputs),
+            None => inputs.to_vec(),
+        }
+    }
+
+    // splits the gradients of the block into those of every layer and the projection
+    fn split_gradients<'a>(&self, gradients: &'a mut [Vec<f64>]) -> Vec<&'a mut [Vec<f64>]> {
+        let mut rest = gradients;
+        let mut split = Vec::with_capacity(self.layers.len() + 1);
+        let counts = self
+            .layers
+            .iter()
+            .map(|layer| layer.parameters().len())
+            .chain(self.projection.as_ref().map(|p| p.parameters().len()));
+        for count in counts {
+            let (head, tail) = std::mem::take(&mut rest).split_at_mut(count);
+            split.push(head);
+            rest = tail;
+        }
+        split
+    }
+}
+
+impl ResidualData {
+    fn into_residual(self) -> Result<Residual, String> {
+        let layers = self
+            .layers
+            .into_iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                layer
+                    .into_layer()
+                    .map_err(|e| format!("Layer {} of the residual block: {}", i + 1, e))
+            })
+            .collect::<Result<Vec<Box<dyn Layer>>, String>>()?;
+        Residual::from_parts(layers, self.projection)
+    }
+}
+
+impl Layer for Residual {
+    fn input_shape(&self) -> Shape {
+        self.layers[0].input_shape()
+    }
+
+    fn output_shape(&self) -> Shape {
+        self.layers[self.layers.len() - 1].output_shape()
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        let outputs = self
+            .layers
+            .iter()
+            .fold(inputs.to_vec(), |outputs, layer| layer.forward(&outputs));
+        outputs
+            .iter()
+            .zip(self.shortcut(inputs))
+            .map(|(y, x)| y + x)
+            .collect()
+    }
+
+    fn forward_train(&mut self, inputs: &[Vec<f64>], rng: &mut StdRng) -> Vec<Vec<f64>> {
+        let mut activations = vec![inputs.to_vec()];
+        for layer in self.layers.iter_mut() {
+            let outputs = layer.forward_train(&activations[activations.len() - 1], rng);
+            activations.push(outputs);
+        }
+        self.projected = inputs.iter().map(|x| self.shortcut(x)).collect();
+        let outputs = activations[activations.len() - 1]
+            .iter()
+            .zip(self.projected.iter())
+            .map(|(y, x)| y.iter().zip(x).map(|(y, x)| y + x).collect())
+            .collect();
+        self.activations = activations;
+        outputs
+    }
+
+    // the gradients reach the inputs both through the layers and the shortcut
+    fn backward(
+        &self,
+        inputs: &[Vec<f64>],
+        _outputs: &[Vec<f64>],
+        output_gradients: Vec<Vec<f64>>,
+        gradients: &mut [Vec<f64>],
+    ) -> Vec<Vec<f64>> {
+        let mut split = self.split_gradients(gradients);
+        let shortcut_gradients = match &self.projection {
+            Some(projection) => projection.backward(
+                inputs,
+                &self.projected,
+                output_gradients.clone(),
+                split.pop().expect("The projection has gradients."),
+            ),
+            None => output_gradients.clone(),
+        };
+        let mut layer_gradients = output_gradients;
+        for (i, layer) in self.layers.iter().enumerate().rev() {
+            layer_gradients = layer.backward(
+                &self.activations[i],
+                &self.activations[i + 1],
+                layer_gradients,
+                split[i],
+            );
+        }
+        layer_gradients
+            .into_iter()
+            .zip(shortcut_gradients)
+            .map(|(a, b)| a.iter().zip(b).map(|(a, b)| a + b).collect())
+            .collect()
+    }
+
+    fn parameters(&self) -> Vec<&[f64]> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.parameters())
+            .chain(self.projection.iter().flat_map(|p| p.parameters()))
+            .collect()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [f64]> {
+        self.layers
+            .iter_mut()
+            .flat_map(|layer| layer.parameters_mut())
+            .chain(self.projection.iter_mut().flat_map(|p| p.parameters_mut()))
+            .collect()
+    }
+
+    // applies to every layer with weights and the projection
+    fn set_regularization(&mut self, regularization: Regularization) -> Result<(), String> {
+        let mut regularized = false;
+        for layer in self.layers.iter_mut() {
+            regularized |= layer.set_regularization(regularization).is_ok();
+        }
+        if let Some(projection) = self.projection.as_mut() {
+            regularized |= projection.set_regularization(regularization).is_ok();
+        }
+        if !regularized {
+            return Err(format!("{} has no weights to regularize", self));
+        }
+        Ok(())
+    }
+
+    fn penalized(&self) -> Vec<bool> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.penalized())
+            .chain(self.projection.iter().flat_map(|p| p.penalized()))
+            .collect()
+    }
+
+    fn penalty(&self) -> f64 {
+        self.layers
+            .iter()
+            .map(|layer| layer.penalty())
+            .chain(self.projection.iter().map(|p| p.penalty()))
+            .sum()
+    }
+
+    fn add_penalty_gradients(&self, gradients: &mut [Vec<f64>], scale: f64) {
+        let split = self.split_gradients(gradients);
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| layer.as_ref())
+            .chain(self.projection.iter().map(|p| p as &dyn Layer));
+        for (layer, gradients) in layers.zip(split) {
+            layer.add_penalty_gradients(gradients, scale);
+        }
+    }
+
+    fn to_config(&self) -> LayerConfig {
+        LayerConfig {
+            layers: self.layers.iter().map(|layer| layer.to_config()).collect(),
+            ..LayerConfig::empty(LayerKind::Residual)
+        }
+    }
+
+    fn to_data(&self) -> LayerData {
+        LayerData::Residual(ResidualData {
+            layers: self.layers.iter().map(|layer| layer.to_data()).collect(),
+            projection: self.projection.clone(),
+        })
+    }
+}
+
+impl fmt::Display for Residual {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let layers: Vec<String> = self.layers.iter().map(|l| l.to_string()).collect();
+        let projection = if self.projection.is_some() {
+            " with projection"
+        } else {
+            ""
+        };
+        describe(
+            f,
+            self,
+            format_args!("residual ({}){}", layers.join("; "), projection),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use serde_json::{json, Value};
+
+    // values between -1 and 1 from a fixed xorshift sequence
+    fn values(count: usize, seed: u64) -> Vec<f64> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 2001) as f64 / 1000.0 - 1.0
+            })
+            .collect()
+    }
+
+    // half the squared distance of the outputs of a training pass to fixed targets
+    fn loss(layer: &mut dyn Layer, inputs: &[Vec<f64>]) -> f64 {
+        let outputs = layer.forward_train(inputs, &mut StdRng::seed_from_u64(0));
+        outputs
+            .iter()
+            .enumerate()
+            .map(|(i, outputs)| {
+                let targets = values(outputs.len(), 1000 + i as u64);
+                outputs
+                    .iter()
+                    .zip(targets)
+                    .map(|(y, t)| 0.5 * (y - t) * (y - t))
+                    .sum::<f64>()
+            })
+            .sum()
+    }
+
+    // compares backward to central differences of the loss, for the parameters,
+    // which get random values first, and for the inputs of a batch of four
+    fn check_gradients(mut layer: Box<dyn Layer>) {
+        for (p, parameters) in layer.parameters_mut().into_iter().enumerate() {
+            let random = values(parameters.len(), 100 + p as u64);
+            parameters.copy_from_slice(&random);
+        }
+        let mut inputs: Vec<Vec<f64>> = (0..4)
+            .map(|i| values(layer.input_size(), 200 + i))
+            .collect();
+
+        let outputs = layer.forward_train(&inputs, &mut StdRng::seed_from_u64(0));
+        let output_gradients = outputs
+            .iter()
+            .enumerate()
+            .map(|(i, outputs)| {
+                let targets = values(outputs.len(), 1000 + i as u64);
+                outputs.iter().zip(targets).map(|(y, t)| y - t).collect()
+            })
+            .collect();
+        let mut gradients = layer.zero_gradients();
+        let input_gradients = layer.backward(&inputs, &outputs, output_gradients, &mut gradients);
+
+        let step = 1e-6;
+        let close = |numeric: f64, analytic: f64, what: String| {
+            assert!(
+                (numeric - analytic).abs() <= 1e-5 * numeric.abs().max(1.0),
+                "{}: numeric {} but backward {}",
+                what,
+                numeric,
+                analytic
+            );
+        };
+        let name = layer.to_string();
+        for (p, gradients) in gradients.iter().enumerate() {
+            for (k, analytic) in gradients.iter().enumerate() {
+                layer.parameters_mut()[p][k] += step;
+                let up = loss(layer.as_mut(), &inputs);
+                layer.parameters_mut()[p][k] -= 2.0 * step;
+                let down = loss(layer.as_mut(), &inputs);
+                layer.parameters_mut()[p][k] += step;
+                let numeric = (up - down) / (2.0 * step);
+                close(
+                    numeric,
+                    *analytic,
+                    format!("{} parameter {}[{}]", name, p, k),
+                );
+            }
+        }
+        for i in 0..inputs.len() {
+            for j in 0..inputs[i].len() {
+                inputs[i][j] += step;
+                let up = loss(layer.as_mut(), &inputs);
+                inputs[i][j] -= 2.0 * step;
+                let down = loss(layer.as_mut(), &inputs);
+                inputs[i][j] += step;
+                let numeric = (up - down) / (2.0 * step);
+                close(
+                    numeric,
+                    input_gradients[i][j],
+                    format!("{} input {}[{}]", name, i, j),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dense_gradients() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for activation in [
+            Activation::Sigmoid,
+            Activation::Relu,
+            Activation::Identity,
+            Activation::Softmax,
+        ] {
+            check_gradients(Box::new(Dense::new(5, 3, activation, &mut rng)));
+        }
+    }
+
+    #[test]
+    fn conv2d_gradients() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let input = Shape::new(2, 5, 4);
+        for (kernel, stride, padding) in [(3, 1, 0), (3, 2, 1), (2, 1, 1)] {
+            let conv = Conv2d::new(
+                input,
+                3,
+                kernel,
+                stride,
+                padding,
+                Activation::Sigmoid,
+                &mut rng,
+            );
+            check_gradients(Box::new(conv.unwrap()));
+        }
+    }
+
+    #[test]
+    fn pooling_gradients() {
+        let input = Shape::new(2, 4, 6);
+        for (size, stride) in [(2, 2), (3, 1)] {
+            check_gradients(Box::new(MaxPool2d(
+                Pool2d::new(input, size, stride).unwrap(),
+            )));
+            check_gradients(Box::new(AvgPool2d(
+                Pool2d::new(input, size, stride).unwrap(),
+            )));
+        }
+        check_gradients(Box::new(GlobalAvgPool::new(input)));
+    }
+
+    #[test]
+    fn batch_norm_gradients() {
+        for shape in [Shape::flat(5), Shape::new(2, 3, 3)] {
+            check_gradients(Box::new(BatchNorm::new(shape, 0.1).unwrap()));
+        }
+    }
+
+    #[test]
+    fn layer_norm_gradients() {
+        for shape in [Shape::flat(5), Shape::new(2, 3, 3)] {
+            check_gradients(Box::new(LayerNorm::new(shape)));
+        }
+    }
+
+    #[test]
+    fn residual_gradients() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // the same size on both sides, then a projection from 4 to 3 values
+        for outputs in [4, 3] {
+            let layers: Vec<Box<dyn Layer>> = vec![
+                Box::new(Dense::new(4, 6, Activation::Sigmoid, &mut rng)),
+                Box::new(LayerNorm::new(Shape::flat(6))),
+                Box::new(Dense::new(6, outputs, Activation::Identity, &mut rng)),
+            ];
+            check_gradients(Box::new(Residual::new(layers, &mut rng).unwrap()));
+        }
+    }
+
+    // saves and loads a layer, which needs to come back with the same data and outputs
+    fn round_trip(layer: Box<dyn Layer>) -> String {
+        let saved = serde_json::to_string(&layer.to_data()).unwrap();
+        let loaded = serde_json::from_str::<LayerData>(&saved)
+            .unwrap()
+            .into_layer()
+            .unwrap();
+        assert_eq!(serde_json::to_string(&loaded.to_data()).unwrap(), saved);
+        let inputs = values(layer.input_size(), 7);
+        assert_eq!(loaded.forward(&inputs), layer.forward(&inputs));
+        let saved: Value = serde_json::from_str(&saved).unwrap();
+        saved["type"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn every_layer_round_trips() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let image = Shape::new(2, 4, 4);
+        let mut batch_norm = BatchNorm::new(image, 0.1).unwrap();
+        // moves the running averages away from their initial values
+        let batch: Vec<Vec<f64>> = (0..3).map(|i| values(image.size(), i)).collect();
+        batch_norm.forward_train(&batch, &mut rng);
+        let residual = Residual::new(
+            vec![Box::new(Dense::new(5, 3, Activation::Relu, &mut rng)) as Box<dyn Layer>],
+            &mut rng,
+        )
+        .unwrap();
+        let layers: Vec<Box<dyn Layer>> = vec![
+            Box::new(Dense::new(5, 3, Activation::Softmax, &mut rng)),
+            Box::new(Conv2d::new(image, 3, 3, 1, 1, Activation::Relu, &mut rng).unwrap()),
+            Box::new(MaxPool2d(Pool2d::new(image, 2, 2).unwrap())),
+            Box::new(AvgPool2d(Pool2d::new(image, 2, 1).unwrap())),
+            Box::new(GlobalAvgPool::new(image)),
+            Box::new(Reshape::new(image, Shape::flat(image.size())).unwrap()),
+            Box::new(Reshape::new(Shape::flat(32), image).unwrap()),
+            Box::new(Dropout::new(image, 0.3).unwrap()),
+            Box::new(batch_norm),
+            Box::new(LayerNorm::new(image)),
+            Box::new(residual),
+        ];
+        let mut kinds: Vec<String> = layers.into_iter().map(round_trip).collect();
+        kinds.sort();
+        kinds.dedup();
+        // one of every LayerData variant
+        assert_eq!(kinds.len(), 11, "{:?}", kinds);
+    }
+
+    // a saved 3x3 convolution over 4x4 inputs, loaded again with one field replaced
+    fn tampered_conv(field: &str, value: Value) -> Result<Box<dyn Layer>, String> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let conv =
+            Conv2d::new(Shape::new(1, 4, 4), 2, 3, 1, 0, Activation::Relu, &mut rng).unwrap();
+        let mut data = serde_json::to_value(conv.to_data()).unwrap();
+        data[field] = value;
+        serde_json::from_value::<LayerData>(data)
+            .unwrap()
+            .into_layer()
+    }
+
+    #[test]
+    fn conv2d_rejects_kernels_larger_than_the_padded_inputs() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let input = Shape::new(1, 4, 4);
+        assert!(Conv2d::new(input, 2, 5, 1, 0, Activation::Relu, &mut rng).is_err());
+        assert!(Conv2d::new(input, 2, 5, 1, 1, Activation::Relu, &mut rng).is_ok());
+        assert!(Conv2d::new(input, 2, 3, 0, 1, Activation::Relu, &mut rng).is_err());
+
+        assert!(tampered_conv("padding", json!(1)).is_ok());
+        assert!(tampered_conv("stride", json!(0)).is_err());
+        // the weights still fit, only the inputs are too small for the kernel
+        let input = json!({ "channels": 1, "height": 2, "width": 2 });
+        assert!(tampered_conv("input", input).is_err());
+    }
+
+    #[test]
+    fn dropout_passes_inputs_on_at_inference() {
+        let dropout = Dropout::new(Shape::flat(50), 0.5).unwrap();
+        let inputs = values(50, 1);
+        assert_eq!(dropout.forward(&inputs), inputs);
+    }
+
+    #[test]
+    fn dropout_scales_the_kept_inputs_while_training() {
+        let mut dropout = Dropout::new(Shape::flat(1000), 0.25).unwrap();
+        let batch: Vec<Vec<f64>> = (0..4).map(|i| values(1000, i)).collect();
+        let outputs = dropout.forward_train(&batch, &mut StdRng::seed_from_u64(2));
+        let gradients = dropout.backward(&batch, &outputs, vec![vec![1.0; 1000]; 4], &mut []);
+        let scale = 1.0 / (1.0 - 0.25);
+        let mut kept = 0;
+        for ((inputs, outputs), gradients) in batch.iter().zip(&outputs).zip(&gradients) {
+            for ((x, y), g) in inputs.iter().zip(outputs).zip(gradients) {
+                if *g == 0.0 {
+                    assert_eq!(*y, 0.0);
+                } else {
+                    assert_eq!(*y, x * scale);
+                    assert_eq!(*g, scale);
+                    kept += 1;
+                }
+            }
+        }
+        // 3000 of 4000 expected, the standard deviation is about 27
+        assert!((2850..=3150).contains(&kept), "{} kept", kept);
+    }
+
+    #[test]
+    fn dropout_masks_follow_the_seed() {
+        let mut dropout = Dropout::new(Shape::flat(100), 0.5).unwrap();
+        let batch = vec![vec![1.0; 100]; 2];
+        let mut train = |seed| dropout.forward_train(&batch, &mut StdRng::seed_from_u64(seed));
+        let first = train(5);
+        assert_eq!(train(5), first);
+        assert_ne!(train(6), first);
+    }
+}
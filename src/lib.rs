@@ -0,0 +1,18 @@
+pub mod augment;
+pub mod config;
+pub mod dataset;
+pub mod datasets;
+pub mod evaluation;
+pub mod helpers;
+pub mod idx;
+pub mod image;
+pub mod image_folder;
+pub mod layers;
+pub mod misclassified;
+pub mod network;
+pub mod optimizer;
+pub mod prediction;
+pub mod preprocessing;
+pub mod sequential;
+pub mod server;
+pub mod tabular;
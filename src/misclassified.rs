@@ -0,0 +1,181 @@
+use std::{fmt::Write as _, path::Path};
+
+use crate::dataset::Dataset;
+use crate::helpers::argmax;
+use crate::idx::invalid_data;
+use crate::image::GrayImage;
+use crate::network::Network;
+
+// a sample the network got wrong, with the raw inputs it was given
+#[derive(Debug, Clone)]
+pub struct Misclassified {
+    pub index: usize,
+    pub actual: usize,
+    pub predicted: usize,
+    // probability of the predicted class
+    pub confidence: f64,
+    pub inputs: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Pgm,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Pgm => "pgm",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ImageFormat,
+    // number of the most confident mistakes put on a contact sheet, 0 for none
+    pub contact_sheet: usize,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            format: ImageFormat::Png,
+            contact_sheet: 0,
+        }
+    }
+}
+
+// misclassified samples, the most confident mistakes first
+pub fn find_misclassified<D: Dataset + ?Sized>(network: &Network, data: &D) -> Vec<Misclassified> {
+    let mut misclassified: Vec<Misclassified> = Vec::new();
+    for index in 0..data.len() {
+        let sample = data.get(index);
+        let outputs = network.predict_proba(sample.inputs.clone());
+        let predicted = argmax(&outputs);
+        if predicted != sample.classification {
+            misclassified.push(Misclassified {
+                index,
+                actual: sample.classification,
+                predicted,
+                confidence: outputs[predicted],
+                inputs: sample.inputs,
+            });
+        }
+    }
+    misclassified.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    misclassified
+}
+
+// writes one image per sample into dir, named like 00042_true-4_pred-9_conf-0.873.png,
+// and index.csv listing them in the given order
+// the contact sheet takes the first samples, inputs need to be square images scaled to 0..1
+pub fn export_misclassified(
+    dir: impl AsRef<Path>,
+    samples: &[Misclassified],
+    class_names: &[String],
+    options: &ExportOptions,
+) -> Result<(), std::io::Error> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let Some(first) = samples.first() else {
+        std::fs::write(dir.join("index.csv"), INDEX_HEADER)?;
+        return Ok(());
+    };
+    let side = (first.inputs.len() as f64).sqrt() as usize;
+    if side * side != first.inputs.len() {
+        return Err(invalid_data(format!(
+            "Can't export {} inputs as a square image",
+            first.inputs.len()
+        )));
+    }
+    let name = |class: usize| {
+        class_names
+            .get(class)
+            .cloned()
+            .unwrap_or_else(|| class.to_string())
+    };
+
+    let mut index = String::from(INDEX_HEADER);
+    let mut images: Vec<GrayImage> = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let file_name = format!(
+            "{:05}_true-{}_pred-{}_conf-{:.3}.{}",
+            sample.index,
+            file_safe(&name(sample.actual)),
+            file_safe(&name(sample.predicted)),
+            sample.confidence,
+            options.format.extension()
+        );
+        let image = GrayImage::from_inputs(side, side, &sample.inputs)?;
+        image.save(dir.join(&file_name))?;
+        images.push(image);
+        writeln!(
+            index,
+            "{},{},{},{},{}",
+            sample.index,
+            csv_cell(&name(sample.actual)),
+            csv_cell(&name(sample.predicted)),
+            sample.confidence,
+            file_name
+        )
+        .expect("Writing to a String can't fail.");
+    }
+    std::fs::write(dir.join("index.csv"), index)?;
+
+    if options.contact_sheet > 0 {
+        let sheet = contact_sheet(&images[..options.contact_sheet.min(images.len())], 2);
+        sheet.save(dir.join(format!("contact_sheet.{}", options.format.extension())))?;
+    }
+    Ok(())
+}
+
+const INDEX_HEADER: &str = "index,true,predicted,confidence,file\n";
+
+// lays out equally sized images row by row in a square grid, separated by gap gray pixels
+pub fn contact_sheet(images: &[GrayImage], gap: usize) -> GrayImage {
+    let Some(first) = images.first() else {
+        return GrayImage {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+        };
+    };
+    let (cell_width, cell_height) = (first.width + gap, first.height + gap);
+    let columns = (images.len() as f64).sqrt().ceil() as usize;
+    let rows = images.len().div_ceil(columns);
+    let width = columns * cell_width + gap;
+    let height = rows * cell_height + gap;
+    let mut pixels = vec![128u8; width * height];
+    for (i, image) in images.iter().enumerate() {
+        let left = gap + (i % columns) * cell_width;
+        let top = gap + (i / columns) * cell_height;
+        for y in 0..image.height {
+            let start = (top + y) * width + left;
+            pixels[start..start + image.width]
+                .copy_from_slice(&image.pixels[y * image.width..(y + 1) * image.width]);
+        }
+    }
+    GrayImage {
+        width,
+        height,
+        pixels,
+    }
+}
+
+// class names are used in file names, anything but letters and digits becomes _
+fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn csv_cell(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -1,384 +1,566 @@
-use std::{
-    sync::{Arc, Mutex},
-    thread,
-};
-
 use rand::Rng;
-use rulinalg::matrix::{BaseMatrix, Matrix};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-use crate::helpers;
-use helpers::{d_sigmoid, get_weight_delta, sigmoid};
+use crate::config::{GradientClipping, LayerConfig, Loss, Optimizer, Task};
+use crate::dataset::Dataset;
+use crate::layers::{Activation, BiasData, Dense, Layer, LayerData, Shape, WeightData};
+use crate::optimizer::OptimizerState;
+use crate::preprocessing::{apply_all, Preprocessor, TargetStandardization};
+use crate::sequential::Sequential;
+use crate::tabular::CsvEncoding;
 
 // TODO: implement pruning
 #[derive(Debug)]
 pub struct Network {
-    weights: Vec<Matrix<f64>>,
-    biases: Vec<Matrix<f64>>,
+    model: Sequential,
     learning_rate: f64,
+    optimizer: Optimizer,
+    optimizer_state: OptimizerState,
+    clipping: GradientClipping,
+    loss: Loss,
+    task: Task,
+    // applied to the inputs before the first layer, both in training and inference
+    preprocessing: Vec<Preprocessor>,
+    // of regression targets, applied to them in training and undone on the outputs
+    target_standardization: Option<TargetStandardization>,
+    // of the columns of the CSV file the network was trained on, for reading other CSV files
+    csv_encoding: Option<CsvEncoding>,
+    // label of every output, empty for models saved before they were recorded
+    class_names: Vec<String>,
 }
 
 impl Network {
     pub fn new(layers: Vec<usize>, number_of_inputs: usize, learning_rate: f64) -> Self {
-        let mut rng = rand::thread_rng();
-        let mut weights: Vec<Matrix<f64>> = Vec::with_capacity(layers.len());
-        let mut biases: Vec<Matrix<f64>> = Vec::with_capacity(layers.len());
-        let mut prev_layer_outputs = number_of_inputs;
-        for layer in layers {
-            weights.push(Matrix::from_fn(layer, prev_layer_outputs, |_, _| {
-                rng.gen::<f64>() - rng.gen::<f64>()
-            }));
-            biases.push(Matrix::from_fn(layer, 1, |_, _| {
-                rng.gen::<f64>() - rng.gen::<f64>()
-            }));
-            prev_layer_outputs = layer;
+        Network::with_rng(
+            layers,
+            number_of_inputs,
+            learning_rate,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    // same as new, with the initial weights drawn from rng
+    pub fn with_rng<R: Rng>(
+        layers: Vec<usize>,
+        number_of_inputs: usize,
+        learning_rate: f64,
+        rng: &mut R,
+    ) -> Self {
+        let layers: Vec<LayerConfig> = layers
+            .into_iter()
+            .map(|size| LayerConfig::dense(size, Activation::Sigmoid))
+            .collect();
+        Network::from_config(&layers, Shape::flat(number_of_inputs), learning_rate, rng)
+            .expect("Dense layers always fit.")
+    }
+
+    // builds the layers of a config for inputs of the given shape
+    pub fn from_config<R: Rng>(
+        layers: &[LayerConfig],
+        input: Shape,
+        learning_rate: f64,
+        rng: &mut R,
+    ) -> Result<Self, String> {
+        let mut model = Sequential::with_input_shape(input).seed(rng.gen());
+        for config in layers {
+            model.push_config(config)?;
+        }
+        Network::from_sequential(model, learning_rate)
+    }
+
+    pub fn from_sequential(model: Sequential, learning_rate: f64) -> Result<Self, String> {
+        if model.is_empty() {
+            return Err("A network needs at least one layer".to_string());
         }
-        Network {
-            weights,
-            biases,
+        Ok(Network {
+            model,
             learning_rate,
+            optimizer: Optimizer::Sgd { learning_rate },
+            optimizer_state: OptimizerState::default(),
+            clipping: GradientClipping::default(),
+            loss: Loss::MeanSquaredError,
+            task: Task::Classification,
+            preprocessing: Vec::new(),
+            target_standardization: None,
+            csv_encoding: None,
+            class_names: Vec::new(),
+        })
+    }
+
+    // the first preprocessing step takes the raw inputs, the last one feeds the first layer
+    pub fn set_preprocessing(&mut self, preprocessing: Vec<Preprocessor>) {
+        let layer_inputs = self.model.input_shape().size();
+        if let Some(last) = preprocessing.last() {
+            if last.output_size() != layer_inputs {
+                panic!(
+                    "Preprocessing outputs {} values but the first layer takes {}",
+                    last.output_size(),
+                    layer_inputs
+                );
+            }
         }
+        self.preprocessing = preprocessing;
     }
 
-    pub fn feed_forward(&self, inputs: Vec<f64>) -> Vec<f64> {
-        if self.weights.is_empty() {
-            panic!("No starting weights set!");
+    // seeds the dropout masks of the following training
+    pub fn set_seed(&mut self, seed: u64) {
+        self.model.set_seed(seed);
+    }
+
+    pub fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    // the learning rate of the optimizer is only the starting one, see set_learning_rate
+    pub fn set_optimizer(&mut self, optimizer: Optimizer) {
+        self.learning_rate = optimizer.learning_rate();
+        self.optimizer = optimizer;
+        self.optimizer_state.reset();
+    }
+
+    pub fn set_gradient_clipping(&mut self, clipping: GradientClipping) {
+        self.clipping = clipping;
+    }
+
+    pub fn set_loss(&mut self, loss: Loss) {
+        self.loss = loss;
+    }
+
+    pub fn loss(&self) -> Loss {
+        self.loss
+    }
+
+    pub fn set_task(&mut self, task: Task) {
+        self.task = task;
+    }
+
+    pub fn task(&self) -> Task {
+        self.task
+    }
+
+    pub fn set_target_standardization(&mut self, standardization: Option<TargetStandardization>) {
+        if let Some(standardization) = &standardization {
+            if standardization.size() != self.output_size() {
+                panic!(
+                    "Network has {} outputs but got a standardization of {} targets",
+                    self.output_size(),
+                    standardization.size()
+                );
+            }
         }
-        if self.biases.is_empty() {
-            panic!("No starting biases set!");
+        self.target_standardization = standardization;
+    }
+
+    pub fn target_standardization(&self) -> Option<&TargetStandardization> {
+        self.target_standardization.as_ref()
+    }
+
+    pub fn set_csv_encoding(&mut self, encoding: Option<CsvEncoding>) {
+        self.csv_encoding = encoding;
+    }
+
+    pub fn csv_encoding(&self) -> Option<&CsvEncoding> {
+        self.csv_encoding.as_ref()
+    }
+
+    // the regularization term of the loss
+    pub fn penalty(&self) -> f64 {
+        self.model.penalty()
+    }
+
+    pub fn preprocessing(&self) -> &[Preprocessor] {
+        &self.preprocessing
+    }
+
+    pub fn set_class_names(&mut self, class_names: Vec<String>) -> Result<(), String> {
+        if !class_names.is_empty() && class_names.len() != self.output_size() {
+            return Err(format!(
+                "Network has {} outputs but {} class names",
+                self.output_size(),
+                class_names.len()
+            ));
         }
-        if inputs.len() != self.weights[0].cols() {
-            panic!("Inputs length needs to be {}", self.weights[0].cols());
+        self.class_names = class_names;
+        Ok(())
+    }
+
+    pub fn class_names(&self) -> &[String] {
+        &self.class_names
+    }
+
+    pub fn layers(&self) -> &[Box<dyn Layer>] {
+        self.model.layers()
+    }
+
+    // activation of the output layer, None when the last layer has none
+    pub fn output_activation(&self) -> Option<Activation> {
+        self.layers().last().and_then(|layer| layer.activation())
+    }
+
+    // number of raw inputs, before preprocessing
+    pub fn input_size(&self) -> usize {
+        match self.preprocessing.first() {
+            Some(preprocessor) => preprocessor.input_size(),
+            None => self.model.input_shape().size(),
         }
+    }
 
-        let mut layer_output: Matrix<f64> = Matrix::new(inputs.len(), 1, inputs);
+    pub fn output_size(&self) -> usize {
+        self.model.output_shape().size()
+    }
 
-        for layer in 0..self.biases.len() {
-            layer_output = Matrix::new(
-                self.biases[layer].rows(),
-                1,
-                (&self.weights[layer] * layer_output + &self.biases[layer])
-                    .into_vec()
-                    .iter()
-                    .map(|x| sigmoid(*x))
-                    .collect::<Vec<f64>>(),
-            );
+    // checks that the network can be trained and evaluated on the given dataset
+    pub fn validate<D: Dataset + ?Sized>(&self, training_set: &D) -> Result<(), String> {
+        if self.input_size() != training_set.num_inputs() {
+            return Err(format!(
+                "Network takes {} inputs but the dataset has {}",
+                self.input_size(),
+                training_set.num_inputs()
+            ));
+        }
+        if self.output_size() != training_set.num_outputs() {
+            return Err(format!(
+                "Network has {} outputs but the dataset targets have {} values",
+                self.output_size(),
+                training_set.num_outputs()
+            ));
+        }
+        Ok(())
+    }
+
+    // regression outputs are in the units of the targets, whatever the network learned
+    pub fn feed_forward(&self, inputs: Vec<f64>) -> Vec<f64> {
+        if inputs.len() != self.input_size() {
+            panic!("Inputs length needs to be {}", self.input_size());
+        }
+        let outputs = self.model.forward(apply_all(&self.preprocessing, inputs));
+        match &self.target_standardization {
+            Some(standardization) => standardization.invert(outputs),
+            None => outputs,
         }
-        layer_output.into_vec()
     }
 
-    pub fn train(&mut self, training_data: Vec<TrainingData>, batch_size: usize, epoch: usize) {
+    // minimizes the loss plus the penalties of the layers,
+    // with the gradients summed over each batch
+    // stops at the first NaN or infinite value, leaving the network as it was then,
+    // see snapshot and restore for going back to an earlier state
+    pub fn train<D: Dataset + ?Sized>(
+        &mut self,
+        training_data: &D,
+        batch_size: usize,
+        epoch: usize,
+    ) -> Result<(), NonFinite> {
         let now = std::time::Instant::now();
         let data_len = training_data.len();
+        let batch_size = batch_size.max(1);
         for epoch_i in 0..epoch {
-            let mut summed_gradients: Arc<Mutex<Vec<Matrix<f64>>>> =
-                Arc::new(Mutex::new(Vec::new()));
-            let mut summed_weight_deltas: Arc<Mutex<Vec<Matrix<f64>>>> =
-                Arc::new(Mutex::new(Vec::new()));
-            let mut handles = vec![];
-            for (i, data) in training_data.iter().enumerate() {
-                // feed-forward
-                let mut layer_outputs: Vec<Matrix<f64>> =
-                    vec![Matrix::new(data.inputs.len(), 1, data.inputs.clone())];
-                for layer in 0..self.biases.len() {
-                    layer_outputs.push(Matrix::new(
-                        self.biases[layer].rows(),
-                        1,
-                        (&self.weights[layer] * &layer_outputs[layer] + &self.biases[layer])
-                            .into_vec()
+            for (batch, batch_start) in (0..data_len).step_by(batch_size).enumerate() {
+                let non_finite = |layers: &[Box<dyn Layer>], layer: usize, values| NonFinite {
+                    epoch: epoch_i + 1,
+                    batch: batch + 1,
+                    layer: layer + 1,
+                    description: layers[layer].to_string(),
+                    values,
+                };
+                let (inputs, targets): (Vec<Vec<f64>>, Vec<Vec<f64>>) = (batch_start
+                    ..data_len.min(batch_start + batch_size))
+                    .map(|index| {
+                        let data = training_data.get(index);
+                        let target = match &self.target_standardization {
+                            Some(standardization) => standardization.apply(data.target),
+                            None => data.target,
+                        };
+                        (apply_all(&self.preprocessing, data.inputs), target)
+                    })
+                    .unzip();
+                let activations = self.model.forward_train(inputs);
+                if let Some(layer) = first_non_finite(activations[1..].iter()) {
+                    return Err(non_finite(self.layers(), layer, "activations"));
+                }
+                // back-propagate, starting from the derivative of the loss
+                let output_gradients = activations[activations.len() - 1]
+                    .iter()
+                    .zip(targets)
+                    .map(|(outputs, target)| {
+                        outputs
                             .iter()
-                            .map(|x| sigmoid(*x))
-                            .collect::<Vec<f64>>(),
-                    ));
+                            .zip(target)
+                            .map(|(output, target)| self.loss.gradient(*output, target))
+                            .collect()
+                    })
+                    .collect();
+                let mut gradients = self.model.backward(&activations, output_gradients);
+                if let Some(layer) = first_non_finite(gradients.iter()) {
+                    return Err(non_finite(self.layers(), layer, "gradients"));
                 }
-                let layer_gradients: Vec<Matrix<f64>> =
-                    self.calcualte_gradient(&layer_outputs, &data.target);
-
-                let cloned_gradients = Arc::clone(&summed_gradients);
-                let cloned_weights = Arc::clone(&summed_weight_deltas);
-                let layer_count = self.weights.len();
-                let handle = thread::spawn(move || {
-                    let mut summed = cloned_gradients.lock().expect("Mutex mess.");
-                    let mut weights = cloned_weights.lock().expect("Mutex mess.");
-
-                    if summed.is_empty() {
-                        for i in 0..layer_count {
-                            (*weights)
-                                .push(get_weight_delta(&layer_outputs[i], &layer_gradients[i + 1]));
-                        }
-                        *summed = layer_gradients;
-                    } else {
-                        for (i, gradient) in layer_gradients.into_iter().enumerate() {
-                            if i > 0 {
-                                (*weights)[i - 1] +=
-                                    get_weight_delta(&layer_outputs[i - 1], &gradient);
-                            }
-                            (*summed)[i] += gradient;
-                        }
-                    }
-                });
-                handles.push(handle);
-
-                if handles.len() == batch_size || i == data_len - 1 {
-                    for handle in handles {
-                        handle.join().expect("Threading mess.");
-                    }
-                    handles = Vec::new();
-                    for i in 0..self.biases.len() {
-                        self.biases[i] = &self.biases[i]
-                            + &(*summed_gradients.lock().expect("Mutex mess."))[i + 1];
-                    }
-                    (0..self.weights.len()).for_each(|i| {
-                        self.weights[i] = &self.weights[i]
-                            + &(*summed_weight_deltas.lock().expect("Mutex mess."))[i];
-                    });
-
-                    summed_gradients = Arc::new(Mutex::new(Vec::new()));
-                    summed_weight_deltas = Arc::new(Mutex::new(Vec::new()));
+                self.clipping.clip(&mut gradients);
+                self.optimizer_state.step(
+                    &self.optimizer,
+                    self.learning_rate,
+                    self.model.layers_mut(),
+                    gradients,
+                );
+                let parameters = self.layers().iter().map(|layer| layer.parameters());
+                if let Some(layer) = first_non_finite(parameters) {
+                    return Err(non_finite(self.layers(), layer, "weights"));
                 }
             }
             println!("Completed epoch {} in {:.2?}", epoch_i + 1, now.elapsed());
         }
+        Ok(())
+    }
+
+    // the layers as they are now, for restore to go back to
+    pub fn snapshot(&self) -> Vec<LayerData> {
+        self.model.to_data()
+    }
+
+    // replaces the layers by those of a snapshot and starts the optimizer over
+    pub fn restore(&mut self, snapshot: Vec<LayerData>) -> Result<(), String> {
+        self.model.restore(snapshot)?;
+        self.optimizer_state.reset();
+        Ok(())
     }
 
     pub fn output_data(&self) -> NetworkData {
-        let mut weights: Vec<WeightData> = Vec::new();
-        let mut biases: Vec<BiasData> = Vec::new();
-        for layer in 0..self.biases.len() {
-            let weight_rows = self.weights[layer].rows();
-            let weight_cols = self.weights[layer].cols();
-            let weight_data = self.weights[layer].clone().into_vec();
-            let bias_rows = self.biases[layer].rows();
-            let bias_data = self.biases[layer].clone().into_vec();
-            weights.push(WeightData {
-                rows: weight_rows,
-                cols: weight_cols,
-                data: weight_data,
-            });
-            biases.push(BiasData {
-                rows: bias_rows,
-                data: bias_data,
-            });
+        NetworkData {
+            weights: Vec::new(),
+            biases: Vec::new(),
+            layers: self.model.to_data(),
+            task: self.task,
+            loss: self.loss,
+            preprocessing: self.preprocessing.clone(),
+            target_standardization: self.target_standardization.clone(),
+            csv_encoding: self.csv_encoding.clone(),
+            class_names: self.class_names.clone(),
         }
-        NetworkData { weights, biases }
     }
 
-    pub fn from_data(data: NetworkData, learning_rate: f64) -> Self {
-        let mut weights: Vec<Matrix<f64>> = Vec::with_capacity(data.weights.len());
-        let mut biases: Vec<Matrix<f64>> = Vec::with_capacity(data.biases.len());
-        for layer in 0..data.weights.len() {
-            weights.push(Matrix::new(
-                data.weights[layer].rows,
-                data.weights[layer].cols,
-                data.weights[layer].data.clone(),
-            ));
-            biases.push(Matrix::new(
-                data.biases[layer].rows,
-                1,
-                data.biases[layer].data.clone(),
-            ));
+    // models saved before layers were stored hold sigmoid dense layers as weights and biases
+    pub fn from_data(data: NetworkData, learning_rate: f64) -> Result<Self, String> {
+        let layers = if data.layers.is_empty() {
+            if data.weights.len() != data.biases.len() {
+                return Err(format!(
+                    "Model has {} weight matrices but {} bias vectors",
+                    data.weights.len(),
+                    data.biases.len()
+                ));
+            }
+            data.weights
+                .into_iter()
+                .zip(data.biases)
+                .map(|(weights, biases)| {
+                    Dense::from_data(weights, biases, Activation::Sigmoid).map(LayerData::Dense)
+                })
+                .collect::<Result<Vec<LayerData>, String>>()?
+        } else {
+            data.layers
+        };
+        let mut network = Network::from_sequential(Sequential::from_data(layers)?, learning_rate)?;
+        let layer_inputs = network.model.input_shape().size();
+        if let Some(last) = data.preprocessing.last() {
+            if last.output_size() != layer_inputs {
+                return Err(format!(
+                    "Preprocessing outputs {} values but the first layer takes {}",
+                    last.output_size(),
+                    layer_inputs
+                ));
+            }
         }
-        Network {
-            weights,
-            biases,
-            learning_rate,
+        if let Some(standardization) = &data.target_standardization {
+            if standardization.size() != network.output_size() {
+                return Err(format!(
+                    "Network has {} outputs but a standardization of {} targets",
+                    network.output_size(),
+                    standardization.size()
+                ));
+            }
         }
+        data.loss.validate()?;
+        network.set_task(data.task);
+        network.set_loss(data.loss);
+        network.set_preprocessing(data.preprocessing);
+        network.set_target_standardization(data.target_standardization);
+        network.set_csv_encoding(data.csv_encoding);
+        network.set_class_names(data.class_names)?;
+        Ok(network)
     }
+}
 
-    fn calcualte_gradient(
-        &self,
-        layer_outputs: &Vec<Matrix<f64>>,
-        target: &[f64],
-    ) -> Vec<Matrix<f64>> {
-        let mut layer_gradients: Vec<Matrix<f64>> =
-            vec![Matrix::<f64>::zeros(1, 1); layer_outputs.len()];
-        // last layer, aka output layer, gets special calculation
-        let last_layer_index = layer_outputs.len() - 1;
-        layer_gradients[last_layer_index] = Matrix::new(
-            layer_outputs[last_layer_index].rows(),
-            1,
-            (layer_outputs[last_layer_index].clone())
-                .into_vec()
-                .iter()
-                .enumerate()
-                .map(|(i, output)| (target[i] - *output) * d_sigmoid(*output) * self.learning_rate)
-                .collect::<Vec<f64>>(),
-        );
-        // will need to skip layer_gradients[0] as that's the inputs
-        for i in (1..last_layer_index).rev() {
-            layer_gradients[i] = Matrix::new(
-                layer_outputs[i].rows(),
-                1,
-                (self.weights[i].transpose() * &layer_gradients[i + 1])
-                    .into_vec()
-                    .iter()
-                    .map(|x: &f64| d_sigmoid(*x) * self.learning_rate)
-                    .collect::<Vec<f64>>(),
-            );
-        }
-        layer_gradients
+// where training first came across a NaN or infinite value
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonFinite {
+    pub epoch: usize,
+    pub batch: usize,
+    // starting at 1, like in inspect
+    pub layer: usize,
+    pub description: String,
+    // activations, gradients or weights
+    pub values: &'static str,
+}
+
+impl fmt::Display for NonFinite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "NaN or infinite {} in layer {} ({}) at batch {} of epoch {}",
+            self.values, self.layer, self.description, self.batch, self.epoch
+        )
     }
 }
 
-// RELU training, doesn't quite work, need softmax for output layer probably
-// impl Network {
-//     pub fn feed_forward_relu(&self, inputs: Vec<f64>) -> Vec<f64> {
-//         if self.weights.is_empty() {
-//             panic!("No starting weights set!");
-//         }
-//         if self.biases.is_empty() {
-//             panic!("No starting biases set!");
-//         }
-//         if inputs.len() != self.weights[0].cols() {
-//             panic!("Inputs length needs to be {}", self.weights[0].cols());
-//         }
-
-//         let mut layer_output: Matrix<f64> = Matrix::new(inputs.len(), 1, inputs);
-
-//         for layer in 0..self.biases.len() {
-//             layer_output = Matrix::new(
-//                 self.biases[layer].rows(),
-//                 1,
-//                 (&self.weights[layer] * layer_output + &self.biases[layer])
-//                     .into_vec()
-//                     .iter()
-//                     .map(|x| relu(*x))
-//                     .collect::<Vec<f64>>(),
-//             );
-//         }
-//         layer_output.into_vec()
-//     }
-
-//     pub fn train_relu(
-//         &mut self,
-//         training_data: Vec<TrainingData>,
-//         batch_size: usize,
-//         epoch: usize,
-//     ) {
-//         let now = std::time::Instant::now();
-//         let data_len = training_data.len();
-//         let mut summed_layer_output: Vec<Matrix<f64>> = Vec::new();
-//         let mut summed_target: Vec<f64> = Vec::new();
-//         for epoch_i in 0..epoch {
-//             for (idx, data) in training_data.iter().enumerate() {
-//                 // feed-forward
-//                 let mut layer_output: Vec<Matrix<f64>> =
-//                     vec![Matrix::new(data.inputs.len(), 1, data.inputs.clone())];
-//                 for layer in 0..self.biases.len() {
-//                     layer_output.push(Matrix::new(
-//                         self.biases[layer].rows(),
-//                         1,
-//                         (&self.weights[layer] * &layer_output[layer] + &self.biases[layer])
-//                             .into_vec()
-//                             .iter()
-//                             .map(|x| relu(*x))
-//                             .collect::<Vec<f64>>(),
-//                     ));
-//                 }
-//                 if summed_layer_output.is_empty() {
-//                     summed_layer_output = layer_output;
-//                 } else {
-//                     for (i, output) in layer_output.into_iter().enumerate() {
-//                         summed_layer_output[i] += output;
-//                     }
-//                 }
-//                 if summed_target.is_empty() {
-//                     summed_target = data.target.clone();
-//                 } else {
-//                     for (i, target) in data.target.clone().into_iter().enumerate() {
-//                         summed_target[i] += target;
-//                     }
-//                 }
-
-//                 if idx > 0 && (idx + 1) % batch_size == 0 || idx == data_len {
-//                     let mut avg_layer_output: Vec<Matrix<f64>> =
-//                         Vec::with_capacity(summed_layer_output.len());
-//                     for layer in summed_layer_output.into_iter() {
-//                         avg_layer_output.push(Matrix::new(
-//                             layer.rows(),
-//                             1,
-//                             layer
-//                                 .into_vec()
-//                                 .iter()
-//                                 .map(|x| x / batch_size as f64)
-//                                 .collect::<Vec<f64>>(),
-//                         ));
-//                     }
-//                     let avg_target: Vec<f64> = summed_target
-//                         .iter()
-//                         .map(|target| target / batch_size as f64)
-//                         .collect();
-
-//                     // back-propagate
-//                     let mut layer_gradients: Vec<Matrix<f64>> =
-//                         vec![Matrix::<f64>::zeros(1, 1); avg_layer_output.len()];
-//                     // last layer, aka output layer, gets special calculation
-//                     let last_layer_index = avg_layer_output.len() - 1;
-//                     layer_gradients[last_layer_index] = Matrix::new(
-//                         avg_layer_output[last_layer_index].rows(),
-//                         1,
-//                         (avg_layer_output[last_layer_index].clone())
-//                             .into_vec()
-//                             .iter()
-//                             .enumerate()
-//                             .map(|(i, output)| {
-//                                 (avg_target[i] - *output) * d_relu(*output) * self.learning_rate
-//                             })
-//                             .collect::<Vec<f64>>(),
-//                     );
-//                     // will need to skip layer_gradients[0] as that's the inputs
-//                     for i in (1..last_layer_index).rev() {
-//                         layer_gradients[i] = Matrix::new(
-//                             avg_layer_output[i].rows(),
-//                             1,
-//                             (self.weights[i].transpose() * &layer_gradients[i + 1])
-//                                 .into_vec()
-//                                 .iter()
-//                                 .map(|x: &f64| d_relu(*x) * self.learning_rate)
-//                                 .collect::<Vec<f64>>(),
-//                         );
-//                     }
-
-//                     for i in 0..self.biases.len() {
-//                         self.biases[i] = &self.biases[i] + &layer_gradients[i + 1];
-//                     }
-//                     for i in 0..self.weights.len() {
-//                         self.weights[i] = &self.weights[i]
-//                             + get_weight_delta(&avg_layer_output[i], &layer_gradients[i + 1]);
-//                     }
-
-//                     summed_layer_output = Vec::new();
-//                     summed_target = Vec::new();
-//                 }
-//             }
-//             println!("Completed epoch {} in {:.2?}", epoch_i + 1, now.elapsed());
-//         }
-//     }
-// }
+impl std::error::Error for NonFinite {}
+
+// index of the first layer with a value that isn't finite
+fn first_non_finite<V>(layers: impl Iterator<Item = V>) -> Option<usize>
+where
+    V: IntoIterator,
+    V::Item: AsRef<[f64]>,
+{
+    layers
+        .map(|values| {
+            values
+                .into_iter()
+                .any(|values| values.as_ref().iter().any(|x| !x.is_finite()))
+        })
+        .position(|non_finite| non_finite)
+}
 
 #[derive(Clone)]
 pub struct TrainingData {
     pub inputs: Vec<f64>,
     pub target: Vec<f64>,
-    pub classification: u8,
+    // index of the class, 0 for regression samples
+    pub classification: usize,
+}
+
+// samples of a dataset together with the names of its classes,
+// or of its targets for regression
+#[derive(Clone)]
+pub struct TrainingSet {
+    pub samples: Vec<TrainingData>,
+    pub class_names: Vec<String>,
+}
+
+impl TrainingSet {
+    pub fn new(samples: Vec<TrainingData>, class_names: Vec<String>) -> Self {
+        TrainingSet {
+            samples,
+            class_names,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct NetworkData {
+    // only in models saved before layers were stored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     weights: Vec<WeightData>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     biases: Vec<BiasData>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    layers: Vec<LayerData>,
+    #[serde(default, skip_serializing_if = "Task::is_classification")]
+    task: Task,
+    // what the model was trained on, for evaluations to report the same loss
+    #[serde(default, skip_serializing_if = "Loss::is_mean_squared_error")]
+    loss: Loss,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    preprocessing: Vec<Preprocessor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_standardization: Option<TargetStandardization>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    csv_encoding: Option<CsvEncoding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    class_names: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct WeightData {
-    rows: usize,
-    cols: usize,
-    data: Vec<f64>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
 
-#[derive(Serialize, Deserialize)]
-struct BiasData {
-    rows: usize,
-    // cols is always 1
-    data: Vec<f64>,
+    // four samples of three inputs in two classes
+    fn training_set() -> TrainingSet {
+        let samples = (0..4)
+            .map(|i| TrainingData {
+                inputs: vec![i as f64 / 4.0, 1.0 - i as f64 / 4.0, 0.5],
+                target: if i % 2 == 0 {
+                    vec![1.0, 0.0]
+                } else {
+                    vec![0.0, 1.0]
+                },
+                classification: i % 2,
+            })
+            .collect();
+        TrainingSet::new(samples, vec!["even".to_string(), "odd".to_string()])
+    }
+
+    fn network() -> Network {
+        let mut network = Network::with_rng(vec![4, 2], 3, 0.1, &mut StdRng::seed_from_u64(1));
+        network.set_optimizer(Optimizer::adam(0.01));
+        network
+    }
+
+    fn saved(network: &Network) -> String {
+        serde_json::to_string(&network.output_data()).unwrap()
+    }
+
+    #[test]
+    fn training_stops_at_non_finite_inputs() {
+        let mut data = training_set();
+        data.samples[2].inputs[1] = f64::NAN;
+        let error = network().train(&data, 2, 1).unwrap_err();
+        assert_eq!((error.epoch, error.batch, error.layer), (1, 2, 1));
+        assert_eq!(error.values, "activations");
+    }
+
+    #[test]
+    fn training_stops_at_non_finite_weights() {
+        let mut network = network();
+        network.set_learning_rate(f64::INFINITY);
+        let error = network.train(&training_set(), 2, 1).unwrap_err();
+        assert_eq!((error.epoch, error.batch), (1, 1));
+        assert_eq!(error.values, "weights");
+    }
+
+    #[test]
+    fn restore_rolls_back_the_weights_and_the_optimizer() {
+        let mut network = network();
+        network.train(&training_set(), 2, 1).unwrap();
+        let snapshot = network.snapshot();
+        let before = saved(&network);
+
+        network.set_learning_rate(f64::INFINITY);
+        assert!(network.train(&training_set(), 2, 1).is_err());
+        network.restore(snapshot).unwrap();
+        assert_eq!(saved(&network), before);
+
+        // the Adam averages of the failed epoch are gone too,
+        // so training goes on like it would from a loaded model
+        network.set_learning_rate(0.01);
+        network.train(&training_set(), 2, 1).unwrap();
+        let data: NetworkData = serde_json::from_str(&before).unwrap();
+        let mut loaded = Network::from_data(data, 0.01).unwrap();
+        loaded.set_optimizer(Optimizer::adam(0.01));
+        loaded.train(&training_set(), 2, 1).unwrap();
+        assert_eq!(saved(&network), saved(&loaded));
+    }
+
+    #[test]
+    fn saved_networks_load_with_their_settings() {
+        let mut network = network();
+        network.set_task(Task::Regression);
+        network.set_loss(Loss::Huber { delta: 0.5 });
+        let loaded = Network::from_data(network.output_data(), 0.1).unwrap();
+        assert_eq!(loaded.task(), Task::Regression);
+        assert_eq!(loaded.loss(), Loss::Huber { delta: 0.5 });
+        assert_eq!(loaded.class_names(), network.class_names());
+        let inputs = vec![0.1, 0.2, 0.3];
+        assert_eq!(
+            loaded.feed_forward(inputs.clone()),
+            network.feed_forward(inputs)
+        );
+    }
 }
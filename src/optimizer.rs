@@ -0,0 +1,212 @@
+use crate::config::Optimizer;
+use crate::layers::Layer;
+
+// what an optimizer keeps between steps, started over whenever training restarts
+#[derive(Debug, Default)]
+pub struct OptimizerState {
+    steps: i32,
+    // running averages of the gradients and their squares, shaped like the gradients
+    first: Vec<Vec<Vec<f64>>>,
+    second: Vec<Vec<Vec<f64>>>,
+}
+
+impl OptimizerState {
+    pub fn reset(&mut self) {
+        *self = OptimizerState::default();
+    }
+
+    // updates the parameters of every layer from their gradients
+    pub fn step(
+        &mut self,
+        optimizer: &Optimizer,
+        learning_rate: f64,
+        layers: &mut [Box<dyn Layer>],
+        gradients: Vec<Vec<Vec<f64>>>,
+    ) {
+        match *optimizer {
+            Optimizer::Sgd { .. } => {
+                for (layer, layer_gradients) in layers.iter_mut().zip(gradients) {
+                    for (parameters, gradients) in
+                        layer.parameters_mut().into_iter().zip(layer_gradients)
+                    {
+                        for (parameter, gradient) in parameters.iter_mut().zip(gradients) {
+                            *parameter -= learning_rate * gradient;
+                        }
+                    }
+                }
+            }
+            Optimizer::Adam {
+                beta1,
+                beta2,
+                epsilon,
+                weight_decay,
+                ..
+            } => {
+                if self.first.is_empty() {
+                    let zeros: Vec<Vec<Vec<f64>>> =
+                        layers.iter().map(|l| l.zero_gradients()).collect();
+                    self.first = zeros.clone();
+                    self.second = zeros;
+                }
+                self.steps += 1;
+                // corrects the averages for starting at zero
+                let first_correction = 1.0 - beta1.powi(self.steps);
+                let second_correction = 1.0 - beta2.powi(self.steps);
+                for (i, (layer, layer_gradients)) in layers.iter_mut().zip(gradients).enumerate() {
+                    let penalized = layer.penalized();
+                    for (j, (parameters, gradients)) in layer
+                        .parameters_mut()
+                        .into_iter()
+                        .zip(layer_gradients)
+                        .enumerate()
+                    {
+                        let decay = if penalized[j] { weight_decay } else { 0.0 };
+                        let first = &mut self.first[i][j];
+                        let second = &mut self.second[i][j];
+                        for (k, (parameter, gradient)) in
+                            parameters.iter_mut().zip(gradients).enumerate()
+                        {
+                            first[k] = beta1 * first[k] + (1.0 - beta1) * gradient;
+                            second[k] = beta2 * second[k] + (1.0 - beta2) * gradient * gradient;
+                            let step = first[k]
+                                / first_correction
+                                / ((second[k] / second_correction).sqrt() + epsilon);
+                            *parameter -= learning_rate * (step + decay * *parameter);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GradientClipping;
+    use crate::layers::{Activation, Dense};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn dense() -> Vec<Box<dyn Layer>> {
+        let mut rng = StdRng::seed_from_u64(1);
+        vec![Box::new(Dense::new(3, 2, Activation::Identity, &mut rng))]
+    }
+
+    fn parameters(layers: &[Box<dyn Layer>]) -> Vec<Vec<f64>> {
+        layers[0].parameters().iter().map(|p| p.to_vec()).collect()
+    }
+
+    fn adam(weight_decay: f64) -> Optimizer {
+        Optimizer::Adam {
+            learning_rate: 0.1,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-12,
+            weight_decay,
+        }
+    }
+
+    #[test]
+    fn adam_decays_only_the_weights_apart_from_the_gradients() {
+        let mut layers = dense();
+        let before = parameters(&layers);
+        let gradients = vec![layers[0].zero_gradients()];
+        let mut state = OptimizerState::default();
+        // without gradients only the decay moves the weights, biases aren't penalized
+        state.step(&adam(0.5), 0.1, &mut layers, gradients);
+        let after = parameters(&layers);
+        for (w, before) in after[0].iter().zip(&before[0]) {
+            assert!((w - before * (1.0 - 0.1 * 0.5)).abs() < 1e-12);
+        }
+        assert_eq!(after[1], before[1]);
+    }
+
+    #[test]
+    fn adam_steps_are_independent_of_the_gradient_scale() {
+        for weight_decay in [0.0, 0.5] {
+            let mut layers = dense();
+            let before = parameters(&layers);
+            // the first step of Adam is learning_rate in the direction of the gradient,
+            // the decay is added to it and not to the gradient, as in AdamW
+            let gradients: Vec<Vec<f64>> = before
+                .iter()
+                .map(|p| (0..p.len()).map(|k| (k as f64 - 2.5) * 1e3).collect())
+                .collect();
+            let mut state = OptimizerState::default();
+            state.step(
+                &adam(weight_decay),
+                0.1,
+                &mut layers,
+                vec![gradients.clone()],
+            );
+            let after = parameters(&layers);
+            for j in 0..2 {
+                let decay = if j == 0 { weight_decay } else { 0.0 };
+                for k in 0..before[j].len() {
+                    let expected =
+                        before[j][k] - 0.1 * (gradients[j][k].signum() + decay * before[j][k]);
+                    assert!((after[j][k] - expected).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sgd_steps_along_the_gradients() {
+        let mut layers = dense();
+        let before = parameters(&layers);
+        let gradients = vec![vec![vec![1.0; 6], vec![-2.0; 2]]];
+        let sgd = Optimizer::Sgd { learning_rate: 0.1 };
+        OptimizerState::default().step(&sgd, 0.1, &mut layers, gradients);
+        let after = parameters(&layers);
+        assert!(after[0]
+            .iter()
+            .zip(&before[0])
+            .all(|(a, b)| (a - (b - 0.1)).abs() < 1e-12));
+        assert!(after[1]
+            .iter()
+            .zip(&before[1])
+            .all(|(a, b)| (a - (b + 0.2)).abs() < 1e-12));
+    }
+
+    #[test]
+    fn clipping_by_value_and_norm() {
+        let gradients = || vec![vec![vec![3.0, -4.0], vec![0.0]], vec![vec![-12.0]]];
+        let assert_close = |clipped: Vec<Vec<Vec<f64>>>, expected: [f64; 4]| {
+            let clipped: Vec<f64> = clipped.into_iter().flatten().flatten().collect();
+            for (clipped, expected) in clipped.iter().zip(expected) {
+                assert!((clipped - expected).abs() < 1e-12, "{:?}", clipped);
+            }
+        };
+
+        let mut clipped = gradients();
+        GradientClipping::default().clip(&mut clipped);
+        assert_eq!(clipped, gradients());
+
+        let mut clipped = gradients();
+        let by_value = GradientClipping {
+            value: Some(2.0),
+            norm: None,
+        };
+        by_value.clip(&mut clipped);
+        assert_close(clipped, [2.0, -2.0, 0.0, -2.0]);
+
+        // the norm of 3, -4, 0 and -12 is 13
+        let mut clipped = gradients();
+        let by_norm = GradientClipping {
+            value: None,
+            norm: Some(2.6),
+        };
+        by_norm.clip(&mut clipped);
+        assert_close(clipped, [0.6, -0.8, 0.0, -2.4]);
+
+        // values get clipped first, leaving a norm below 4
+        let mut clipped = gradients();
+        let both = GradientClipping {
+            value: Some(2.0),
+            norm: Some(4.0),
+        };
+        both.clip(&mut clipped);
+        assert_close(clipped, [2.0, -2.0, 0.0, -2.0]);
+    }
+}
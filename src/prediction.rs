@@ -0,0 +1,75 @@
+use serde::Serialize;
+
+use crate::helpers::argmax;
+use crate::layers::Activation;
+use crate::network::Network;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClassProbability {
+    pub class: usize,
+    pub label: String,
+    pub probability: f64,
+}
+
+impl Network {
+    // index of the most likely class
+    pub fn predict(&self, inputs: Vec<f64>) -> usize {
+        argmax(&self.feed_forward(inputs))
+    }
+
+    // probability of every class, summing up to 1
+    // softmax outputs already are, sigmoid outputs are independent of each other, so they're
+    // turned back into the output layer's weighted sums and normalized with softmax
+    pub fn predict_proba(&self, inputs: Vec<f64>) -> Vec<f64> {
+        let outputs = self.feed_forward(inputs);
+        match self.output_activation() {
+            Some(Activation::Softmax) => outputs,
+            Some(Activation::Sigmoid) => Activation::Softmax.activate(
+                outputs
+                    .into_iter()
+                    .map(|y| {
+                        let y = y.clamp(1e-15, 1.0 - 1e-15);
+                        (y / (1.0 - y)).ln()
+                    })
+                    .collect(),
+            ),
+            _ => Activation::Softmax.activate(outputs),
+        }
+    }
+
+    // the k most likely classes, most likely first
+    pub fn predict_top_k(&self, inputs: Vec<f64>, k: usize) -> Vec<ClassProbability> {
+        self.top_k(&self.predict_proba(inputs), k)
+    }
+
+    // the k classes with the highest of the given probabilities, most likely first
+    pub fn top_k(&self, probabilities: &[f64], k: usize) -> Vec<ClassProbability> {
+        let mut classes: Vec<ClassProbability> = probabilities
+            .iter()
+            .enumerate()
+            .map(|(class, probability)| ClassProbability {
+                class,
+                label: self.label(class),
+                probability: *probability,
+            })
+            .collect();
+        classes.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+        classes.truncate(k);
+        classes
+    }
+
+    pub fn predict_top_k_batch(&self, inputs: &[Vec<f64>], k: usize) -> Vec<Vec<ClassProbability>> {
+        inputs
+            .iter()
+            .map(|x| self.predict_top_k(x.clone(), k))
+            .collect()
+    }
+
+    // name of a class from the model, or its index if the model has no class names
+    pub fn label(&self, class: usize) -> String {
+        self.class_names()
+            .get(class)
+            .cloned()
+            .unwrap_or_else(|| class.to_string())
+    }
+}
@@ -0,0 +1,409 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dataset::Dataset;
+use crate::helpers::symmetric_eigen;
+
+// what to fit on the training data, see Preprocessor for the fitted result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Preprocessing {
+    // scales every feature to 0..1 using its training minimum and maximum
+    MinMax,
+    // zero mean and unit variance per feature
+    Standardize,
+    // subtracts the mean image, keeping the scale of the inputs
+    MeanImage,
+    // decorrelates the features and scales them to unit variance,
+    // optionally keeping only the strongest components
+    PcaWhitening {
+        components: Option<usize>,
+        epsilon: f64,
+    },
+}
+
+// a fitted preprocessing step, stored with the network so inference
+// transforms inputs exactly the same way training did
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Preprocessor {
+    MinMax {
+        min: Vec<f64>,
+        max: Vec<f64>,
+    },
+    Standardize {
+        mean: Vec<f64>,
+        std_dev: Vec<f64>,
+    },
+    MeanImage {
+        mean: Vec<f64>,
+    },
+    PcaWhitening {
+        mean: Vec<f64>,
+        // one row per component, already divided by the component's standard deviation
+        components: Vec<Vec<f64>>,
+    },
+}
+
+impl Preprocessor {
+    // fits every step on the output of the steps before it
+    pub fn fit_all<D: Dataset + ?Sized>(
+        steps: &[Preprocessing],
+        data: &D,
+    ) -> Result<Vec<Preprocessor>, String> {
+        let mut fitted: Vec<Preprocessor> = Vec::with_capacity(steps.len());
+        for step in steps {
+            let inputs = |i: usize| apply_all(&fitted, data.get(i).inputs);
+            fitted.push(Preprocessor::fit(step, data.len(), inputs)?);
+        }
+        Ok(fitted)
+    }
+
+    // fits a single step, inputs returns the i-th of count samples
+    pub fn fit<F: Fn(usize) -> Vec<f64>>(
+        step: &Preprocessing,
+        count: usize,
+        inputs: F,
+    ) -> Result<Self, String> {
+        if count == 0 {
+            panic!("Can't fit preprocessing without any data");
+        }
+        let size = inputs(0).len();
+        if let Preprocessing::PcaWhitening {
+            components: Some(components),
+            ..
+        } = step
+        {
+            if *components == 0 || *components > size {
+                return Err(format!(
+                    "PCA needs from 1 to {} components, the number of its inputs, not {}",
+                    size, components
+                ));
+            }
+        }
+        Ok(match step {
+            Preprocessing::MinMax => {
+                let mut min = vec![f64::INFINITY; size];
+                let mut max = vec![f64::NEG_INFINITY; size];
+                for i in 0..count {
+                    for (j, x) in inputs(i).into_iter().enumerate() {
+                        min[j] = min[j].min(x);
+                        max[j] = max[j].max(x);
+                    }
+                }
+                Preprocessor::MinMax { min, max }
+            }
+            Preprocessing::Standardize => {
+                let mean = mean(count, size, &inputs);
+                let mut variance = vec![0.0; size];
+                for i in 0..count {
+                    for (j, x) in inputs(i).into_iter().enumerate() {
+                        variance[j] += (x - mean[j]).powi(2) / count as f64;
+                    }
+                }
+                Preprocessor::Standardize {
+                    mean,
+                    std_dev: variance.into_iter().map(f64::sqrt).collect(),
+                }
+            }
+            Preprocessing::MeanImage => Preprocessor::MeanImage {
+                mean: mean(count, size, &inputs),
+            },
+            Preprocessing::PcaWhitening {
+                components,
+                epsilon,
+            } => {
+                let mean = mean(count, size, &inputs);
+                // only the upper triangle gets accumulated, the covariance is symmetric
+                let mut covariance = vec![0.0; size * size];
+                for i in 0..count {
+                    let centered: Vec<f64> = inputs(i)
+                        .into_iter()
+                        .zip(mean.iter())
+                        .map(|(x, m)| x - m)
+                        .collect();
+                    for (j, x) in centered.iter().enumerate() {
+                        if *x == 0.0 {
+                            continue;
+                        }
+                        for k in j..size {
+                            covariance[j * size + k] += x * centered[k];
+                        }
+                    }
+                }
+                for j in 0..size {
+                    for k in j..size {
+                        covariance[j * size + k] /= count as f64;
+                        covariance[k * size + j] = covariance[j * size + k];
+                    }
+                }
+                // strongest components first
+                let (values, u) = symmetric_eigen(covariance, size);
+                let mut order: Vec<usize> = (0..size).collect();
+                order.sort_by(|a, b| values[*b].total_cmp(&values[*a]));
+                let keep = components.unwrap_or(size);
+                let components = (0..keep)
+                    .map(|c| {
+                        let c = order[c];
+                        let scale = 1.0 / (values[c].max(0.0) + epsilon).sqrt();
+                        (0..size).map(|j| u[[j, c]] * scale).collect()
+                    })
+                    .collect();
+                Preprocessor::PcaWhitening { mean, components }
+            }
+        })
+    }
+
+    pub fn apply(&self, inputs: Vec<f64>) -> Vec<f64> {
+        if inputs.len() != self.input_size() {
+            panic!(
+                "Preprocessing needs {} inputs, got {}",
+                self.input_size(),
+                inputs.len()
+            );
+        }
+        match self {
+            Preprocessor::MinMax { min, max } => inputs
+                .into_iter()
+                .enumerate()
+                .map(|(j, x)| {
+                    let range = max[j] - min[j];
+                    if range > 0.0 {
+                        (x - min[j]) / range
+                    } else {
+                        0.0
+                    }
+                })
+                .collect(),
+            Preprocessor::Standardize { mean, std_dev } => inputs
+                .into_iter()
+                .enumerate()
+                .map(|(j, x)| {
+                    // constant features, like MNIST's border pixels, only get centered
+                    if std_dev[j] > 0.0 {
+                        (x - mean[j]) / std_dev[j]
+                    } else {
+                        x - mean[j]
+                    }
+                })
+                .collect(),
+            Preprocessor::MeanImage { mean } => inputs
+                .into_iter()
+                .zip(mean.iter())
+                .map(|(x, m)| x - m)
+                .collect(),
+            Preprocessor::PcaWhitening { mean, components } => {
+                let centered: Vec<f64> = inputs
+                    .into_iter()
+                    .zip(mean.iter())
+                    .map(|(x, m)| x - m)
+                    .collect();
+                components
+                    .iter()
+                    .map(|c| c.iter().zip(centered.iter()).map(|(a, b)| a * b).sum())
+                    .collect()
+            }
+        }
+    }
+
+    // same as the type tag in saved models
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preprocessor::MinMax { .. } => "min_max",
+            Preprocessor::Standardize { .. } => "standardize",
+            Preprocessor::MeanImage { .. } => "mean_image",
+            Preprocessor::PcaWhitening { .. } => "pca_whitening",
+        }
+    }
+
+    pub fn input_size(&self) -> usize {
+        match self {
+            Preprocessor::MinMax { min, .. } => min.len(),
+            Preprocessor::Standardize { mean, .. }
+            | Preprocessor::MeanImage { mean }
+            | Preprocessor::PcaWhitening { mean, .. } => mean.len(),
+        }
+    }
+
+    pub fn output_size(&self) -> usize {
+        match self {
+            Preprocessor::PcaWhitening { components, .. } => components.len(),
+            _ => self.input_size(),
+        }
+    }
+}
+
+// zero mean and unit variance per regression target, fitted on the training targets
+// the network learns the standardized targets and its outputs are turned back with invert
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetStandardization {
+    pub mean: Vec<f64>,
+    pub std_dev: Vec<f64>,
+}
+
+impl TargetStandardization {
+    pub fn fit<D: Dataset + ?Sized>(data: &D) -> Self {
+        match Preprocessor::fit(&Preprocessing::Standardize, data.len(), |i| {
+            data.get(i).target
+        }) {
+            Ok(Preprocessor::Standardize { mean, std_dev }) => {
+                TargetStandardization { mean, std_dev }
+            }
+            _ => unreachable!("Standardize fits a Standardize preprocessor."),
+        }
+    }
+
+    // constant targets only get centered, like constant features
+    pub fn apply(&self, target: Vec<f64>) -> Vec<f64> {
+        target
+            .into_iter()
+            .enumerate()
+            .map(|(j, t)| {
+                if self.std_dev[j] > 0.0 {
+                    (t - self.mean[j]) / self.std_dev[j]
+                } else {
+                    t - self.mean[j]
+                }
+            })
+            .collect()
+    }
+
+    pub fn invert(&self, outputs: Vec<f64>) -> Vec<f64> {
+        outputs
+            .into_iter()
+            .enumerate()
+            .map(|(j, y)| {
+                if self.std_dev[j] > 0.0 {
+                    y * self.std_dev[j] + self.mean[j]
+                } else {
+                    y + self.mean[j]
+                }
+            })
+            .collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.mean.len()
+    }
+}
+
+pub fn apply_all(preprocessors: &[Preprocessor], inputs: Vec<f64>) -> Vec<f64> {
+    preprocessors
+        .iter()
+        .fold(inputs, |inputs, preprocessor| preprocessor.apply(inputs))
+}
+
+fn mean<F: Fn(usize) -> Vec<f64>>(count: usize, size: usize, inputs: &F) -> Vec<f64> {
+    let mut mean = vec![0.0; size];
+    for i in 0..count {
+        for (j, x) in inputs(i).into_iter().enumerate() {
+            mean[j] += x / count as f64;
+        }
+    }
+    mean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // correlated samples of the given size from a fixed linear mix of a small grid
+    fn correlated(size: usize) -> Vec<Vec<f64>> {
+        (0..200)
+            .map(|i| {
+                let (a, b, c, d) = (
+                    (i % 7) as f64,
+                    (i % 11) as f64,
+                    (i % 13) as f64,
+                    (i % 17) as f64,
+                );
+                [a + b, 2.0 * a - c, b + 2.0 * c, a + d][..size].to_vec()
+            })
+            .collect()
+    }
+
+    fn whitened_covariance(size: usize, components: Option<usize>) -> Vec<Vec<f64>> {
+        let samples = correlated(size);
+        let step = Preprocessing::PcaWhitening {
+            components,
+            epsilon: 0.0,
+        };
+        let pca = Preprocessor::fit(&step, samples.len(), |i| samples[i].clone()).unwrap();
+        let outputs: Vec<Vec<f64>> = samples.into_iter().map(|x| pca.apply(x)).collect();
+        let n = outputs[0].len();
+        (0..n)
+            .map(|j| {
+                (0..n)
+                    .map(|k| {
+                        outputs.iter().map(|x| x[j] * x[k]).sum::<f64>() / outputs.len() as f64
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pca_whitening_decorrelates_features() {
+        for size in [2, 3, 4] {
+            let covariance = whitened_covariance(size, None);
+            for (j, row) in covariance.iter().enumerate() {
+                for (k, value) in row.iter().enumerate() {
+                    let expected = if j == k { 1.0 } else { 0.0 };
+                    assert!((value - expected).abs() < 1e-6, "{:?}", covariance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pca_whitening_keeps_the_strongest_components() {
+        let samples = correlated(4);
+        let step = Preprocessing::PcaWhitening {
+            components: Some(1),
+            epsilon: 0.0,
+        };
+        let pca = Preprocessor::fit(&step, samples.len(), |i| samples[i].clone()).unwrap();
+        let Preprocessor::PcaWhitening { components, .. } = &pca else {
+            unreachable!()
+        };
+        // the first component has the smallest scale, since it's divided by the largest deviation
+        let full = Preprocessor::fit(
+            &Preprocessing::PcaWhitening {
+                components: None,
+                epsilon: 0.0,
+            },
+            samples.len(),
+            |i| samples[i].clone(),
+        )
+        .unwrap();
+        let Preprocessor::PcaWhitening {
+            components: all, ..
+        } = &full
+        else {
+            unreachable!()
+        };
+        let norm = |v: &Vec<f64>| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        assert_eq!(components.len(), 1);
+        assert!(all.windows(2).all(|pair| norm(&pair[0]) <= norm(&pair[1])));
+        assert!((norm(&components[0]) - norm(&all[0])).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pca_rejects_more_components_than_inputs() {
+        let samples = correlated(3);
+        let fit = |components| {
+            let step = Preprocessing::PcaWhitening {
+                components: Some(components),
+                epsilon: 0.0,
+            };
+            Preprocessor::fit(&step, samples.len(), |i| samples[i].clone())
+        };
+        assert!(fit(3).is_ok());
+        assert!(fit(0).is_err());
+        let error = fit(4).err().unwrap();
+        assert!(
+            error.contains("from 1 to 3") && error.contains("not 4"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::config::{LayerConfig, LayerKind};
+use crate::layers::{
+    Activation, AvgPool2d, BatchNorm, Conv2d, Dense, Dropout, GlobalAvgPool, Layer, LayerData,
+    LayerNorm, MaxPool2d, Pool2d, Reshape, Residual, Shape,
+};
+
+// a stack of layers, each one taking what the one before it outputs
+// built one layer at a time, as in
+//   Sequential::new(784)
+//       .dense(128, Activation::Relu)
+//       .dropout(0.2)
+//       .dense(10, Activation::Softmax)
+// the builder methods panic on layers that don't fit, push and push_config return the error
+#[derive(Debug)]
+pub struct Sequential {
+    input: Shape,
+    layers: Vec<Box<dyn Layer>>,
+    // draws the initial weights of new layers and the dropout masks while training
+    rng: StdRng,
+}
+
+impl Sequential {
+    pub fn new(inputs: usize) -> Self {
+        Sequential::with_input_shape(Shape::flat(inputs))
+    }
+
+    pub fn with_input_shape(input: Shape) -> Self {
+        Sequential {
+            input,
+            layers: Vec::new(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    // makes the weights of the layers added after it, and the dropout masks, reproducible
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.set_seed(seed);
+        self
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn dense(self, size: usize, activation: Activation) -> Self {
+        self.with_config(LayerConfig::dense(size, activation))
+    }
+
+    pub fn conv2d(
+        self,
+        filters: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+        activation: Activation,
+    ) -> Self {
+        self.with_config(LayerConfig {
+            size: filters,
+            activation: Some(activation),
+            kernel_size: Some(kernel_size),
+            stride: Some(stride),
+            padding: Some(padding),
+            ..LayerConfig::empty(LayerKind::Conv2d)
+        })
+    }
+
+    pub fn max_pool2d(self, size: usize, stride: usize) -> Self {
+        self.with_config(LayerConfig {
+            size,
+            stride: Some(stride),
+            ..LayerConfig::empty(LayerKind::MaxPool2d)
+        })
+    }
+
+    pub fn avg_pool2d(self, size: usize, stride: usize) -> Self {
+        self.with_config(LayerConfig {
+            size,
+            stride: Some(stride),
+            ..LayerConfig::empty(LayerKind::AvgPool2d)
+        })
+    }
+
+    pub fn global_avg_pool(self) -> Self {
+        self.with_config(LayerConfig::empty(LayerKind::GlobalAvgPool))
+    }
+
+    pub fn flatten(self) -> Self {
+        self.with_config(LayerConfig::empty(LayerKind::Flatten))
+    }
+
+    pub fn reshape(self, shape: Shape) -> Self {
+        self.with_config(LayerConfig {
+            shape: Some(shape),
+            ..LayerConfig::empty(LayerKind::Reshape)
+        })
+    }
+
+    // zeroes a fraction rate of the outputs so far while training
+    pub fn dropout(self, rate: f64) -> Self {
+        self.with_config(LayerConfig {
+            rate: Some(rate),
+            ..LayerConfig::empty(LayerKind::Dropout)
+        })
+    }
+
+    // normalizes every channel of the outputs so far over each training batch
+    pub fn batch_norm(self) -> Self {
+        self.with_config(LayerConfig::empty(LayerKind::BatchNorm))
+    }
+
+    // normalizes every sample of the outputs so far over all of its values
+    pub fn layer_norm(self) -> Self {
+        self.with_config(LayerConfig::empty(LayerKind::LayerNorm))
+    }
+
+    // adds the layers that block adds to the Sequential it's given,
+    // with their inputs added to their outputs, as in
+    //   .residual(|block| block.dense(32, Activation::Relu).dense(32, Activation::Relu))
+    pub fn residual(mut self, block: impl FnOnce(Sequential) -> Sequential) -> Self {
+        let inner = Sequential {
+            input: self.output_shape(),
+            layers: Vec::new(),
+            rng: self.rng.clone(),
+        };
+        let mut inner = block(inner);
+        let residual =
+            Residual::new(inner.layers, &mut inner.rng).unwrap_or_else(|e| panic!("{}", e));
+        self.rng = inner.rng;
+        self.layer(Box::new(residual))
+    }
+
+    // adds a layer built elsewhere, such as one of a kind of your own
+    pub fn layer(mut self, layer: Box<dyn Layer>) -> Self {
+        self.push(layer).unwrap_or_else(|e| panic!("{}", e));
+        self
+    }
+
+    fn with_config(mut self, config: LayerConfig) -> Self {
+        self.push_config(&config)
+            .unwrap_or_else(|e| panic!("{}", e));
+        self
+    }
+
+    pub fn push(&mut self, layer: Box<dyn Layer>) -> Result<(), String> {
+        let inputs = self.output_shape().size();
+        if layer.input_size() != inputs {
+            return Err(format!(
+                "Layer {} takes {} values but gets {}",
+                self.layers.len() + 1,
+                layer.input_size(),
+                inputs
+            ));
+        }
+        self.layers.push(layer);
+        Ok(())
+    }
+
+    // builds the layer for what the model outputs so far
+    pub fn push_config(&mut self, config: &LayerConfig) -> Result<(), String> {
+        let layer = build_layer(config, self.output_shape(), &mut self.rng)?;
+        self.push(layer)
+    }
+
+    pub fn input_shape(&self) -> Shape {
+        self.input
+    }
+
+    // the input shape while there are no layers
+    pub fn output_shape(&self) -> Shape {
+        self.layers
+            .last()
+            .map_or(self.input, |layer| layer.output_shape())
+    }
+
+    pub fn layers(&self) -> &[Box<dyn Layer>] {
+        &self.layers
+    }
+
+    pub fn layers_mut(&mut self) -> &mut [Box<dyn Layer>] {
+        &mut self.layers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn forward(&self, inputs: Vec<f64>) -> Vec<f64> {
+        self.layers
+            .iter()
+            .fold(inputs, |outputs, layer| layer.forward(&outputs))
+    }
+
+    // runs a batch through the layers in training mode,
+    // returning the inputs of every layer followed by the outputs of the last one
+    pub fn forward_train(&mut self, inputs: Vec<Vec<f64>>) -> Vec<Vec<Vec<f64>>> {
+        let mut activations = vec![inputs];
+        for layer in self.layers.iter_mut() {
+            let outputs = layer.forward_train(&activations[activations.len() - 1], &mut self.rng);
+            activations.push(outputs);
+        }
+        activations
+    }
+
+    // gradients of every parameter of every layer summed over the batch, given the
+    // activations of forward_train and the gradients of the loss by the outputs
+    // the gradients of the penalties are added once for every sample
+    pub fn backward(
+        &self,
+        activations: &[Vec<Vec<f64>>],
+        output_gradients: Vec<Vec<f64>>,
+    ) -> Vec<Vec<Vec<f64>>> {
+        let mut gradients: Vec<Vec<Vec<f64>>> =
+            self.layers.iter().map(|l| l.zero_gradients()).collect();
+        let mut output_gradients = output_gradients;
+        for (i, layer) in self.layers.iter().enumerate().rev() {
+            output_gradients = layer.backward(
+                &activations[i],
+                &activations[i + 1],
+                output_gradients,
+                &mut gradients[i],
+            );
+        }
+        let samples = activations[0].len() as f64;
+        for (layer, gradients) in self.layers.iter().zip(gradients.iter_mut()) {
+            layer.add_penalty_gradients(gradients, samples);
+        }
+        gradients
+    }
+
+    // the regularization term of the loss, the penalties of all layers
+    pub fn penalty(&self) -> f64 {
+        self.layers.iter().map(|layer| layer.penalty()).sum()
+    }
+
+    pub fn to_data(&self) -> Vec<LayerData> {
+        self.layers.iter().map(|layer| layer.to_data()).collect()
+    }
+
+    // replaces the layers by ones taking the same inputs and giving the same outputs
+    pub fn restore(&mut self, layers: Vec<LayerData>) -> Result<(), String> {
+        let restored = Sequential::from_data(layers)?;
+        if restored.input.size() != self.input.size()
+            || restored.output_shape().size() != self.output_shape().size()
+        {
+            return Err(format!(
+                "Can't replace layers from {} to {} by ones from {} to {}",
+                self.input,
+                self.output_shape(),
+                restored.input,
+                restored.output_shape()
+            ));
+        }
+        self.layers = restored.layers;
+        Ok(())
+    }
+
+    // the input shape is taken from the first layer
+    pub fn from_data(layers: Vec<LayerData>) -> Result<Self, String> {
+        let mut built = Vec::with_capacity(layers.len());
+        for (i, layer) in layers.into_iter().enumerate() {
+            built.push(
+                layer
+                    .into_layer()
+                    .map_err(|e| format!("Layer {}: {}", i + 1, e))?,
+            );
+        }
+        let input = built
+            .first()
+            .ok_or("A network needs at least one layer")?
+            .input_shape();
+        let mut model = Sequential::with_input_shape(input);
+        for layer in built {
+            model.push(layer)?;
+        }
+        Ok(model)
+    }
+}
+
+// builds the layer a config describes for inputs of the given shape
+fn build_layer(
+    config: &LayerConfig,
+    shape: Shape,
+    rng: &mut StdRng,
+) -> Result<Box<dyn Layer>, String> {
+    let mut layer: Box<dyn Layer> = match config.kind {
+        LayerKind::Dense | LayerKind::Conv2d if config.size == 0 => {
+            return Err("Dense and convolution layers need a size".to_string())
+        }
+        LayerKind::Dense => Box::new(Dense::new(
+            shape.size(),
+            config.size,
+            config.activation.unwrap_or_default(),
+            rng,
+        )),
+        LayerKind::Conv2d => Box::new(Conv2d::new(
+            shape,
+            config.size,
+            config.kernel_size.unwrap_or(3),
+            config.stride.unwrap_or(1),
+            config.padding.unwrap_or(0),
+            config.activation.unwrap_or_default(),
+            rng,
+        )?),
+        LayerKind::MaxPool2d => Box::new(MaxPool2d(Pool2d::new(
+            shape,
+            config.size,
+            config.stride.unwrap_or(config.size),
+        )?)),
+        LayerKind::AvgPool2d => Box::new(AvgPool2d(Pool2d::new(
+            shape,
+            config.size,
+            config.stride.unwrap_or(config.size),
+        )?)),
+        LayerKind::GlobalAvgPool => Box::new(GlobalAvgPool::new(shape)),
+        LayerKind::Flatten => Box::new(Reshape::flatten(shape)),
+        LayerKind::Reshape => Box::new(Reshape::new(
+            shape,
+            config
+                .shape
+                .ok_or("Reshape layers need a shape to reshape to")?,
+        )?),
+        LayerKind::BatchNorm => Box::new(BatchNorm::new(shape, config.momentum.unwrap_or(0.1))?),
+        LayerKind::LayerNorm => Box::new(LayerNorm::new(shape)),
+        LayerKind::Residual => {
+            let mut layers: Vec<Box<dyn Layer>> = Vec::with_capacity(config.layers.len());
+            let mut inner = shape;
+            for config in config.layers.iter() {
+                let layer = build_layer(config, inner, rng)?;
+                inner = layer.output_shape();
+                layers.push(layer);
+            }
+            Box::new(Residual::new(layers, rng)?)
+        }
+        LayerKind::Dropout => Box::new(Dropout::new(
+            shape,
+            config.rate.ok_or("Dropout layers need a rate")?,
+        )?),
+    };
+    if let Some(regularization) = config.regularization {
+        layer.set_regularization(regularization)?;
+    }
+    Ok(layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Sequential {
+        Sequential::with_input_shape(Shape::new(1, 12, 12))
+            .seed(3)
+            .conv2d(4, 3, 1, 0, Activation::Relu)
+            .max_pool2d(2, 2)
+            .flatten()
+            .dense(8, Activation::Sigmoid)
+            .residual(|block| block.dense(8, Activation::Relu))
+            .dense(3, Activation::Softmax)
+    }
+
+    #[test]
+    fn infers_the_shapes_of_the_layers() {
+        let model = model();
+        let shapes: Vec<Shape> = model.layers().iter().map(|l| l.output_shape()).collect();
+        assert_eq!(
+            shapes,
+            vec![
+                Shape::new(4, 10, 10),
+                Shape::new(4, 5, 5),
+                Shape::flat(100),
+                Shape::flat(8),
+                Shape::flat(8),
+                Shape::flat(3),
+            ]
+        );
+        assert_eq!(model.layers()[3].input_size(), 100);
+        assert_eq!(model.forward(vec![0.5; 144]).len(), 3);
+    }
+
+    #[test]
+    fn rejects_layers_that_dont_fit() {
+        let mut model = Sequential::with_input_shape(Shape::new(1, 4, 4)).seed(1);
+        assert!(model
+            .push_config(&LayerConfig::dense(0, Activation::Relu))
+            .is_err());
+        let conv = |size, kernel_size| LayerConfig {
+            size,
+            kernel_size: Some(kernel_size),
+            ..LayerConfig::empty(LayerKind::Conv2d)
+        };
+        assert!(model.push_config(&conv(0, 3)).is_err());
+        assert!(model.push_config(&conv(2, 5)).is_err());
+        let pool = LayerConfig {
+            size: 5,
+            ..LayerConfig::empty(LayerKind::MaxPool2d)
+        };
+        assert!(model.push_config(&pool).is_err());
+        let reshape = LayerConfig {
+            shape: Some(Shape::new(3, 5, 1)),
+            ..LayerConfig::empty(LayerKind::Reshape)
+        };
+        assert!(model.push_config(&reshape).is_err());
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let dense = Dense::new(15, 2, Activation::Relu, &mut rng);
+        let error = model.push(Box::new(dense)).err().unwrap();
+        assert_eq!(error, "Layer 1 takes 15 values but gets 16");
+        assert!(model.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "need a size")]
+    fn builder_panics_on_layers_that_dont_fit() {
+        Sequential::new(4).dense(0, Activation::Relu);
+    }
+
+    #[test]
+    fn built_models_save_and_load() {
+        let model = model();
+        let saved = serde_json::to_string(&model.to_data()).unwrap();
+        let loaded = Sequential::from_data(serde_json::from_str(&saved).unwrap()).unwrap();
+        assert_eq!(loaded.input_shape(), model.input_shape());
+        assert_eq!(loaded.output_shape(), model.output_shape());
+        assert_eq!(serde_json::to_string(&loaded.to_data()).unwrap(), saved);
+        let inputs: Vec<f64> = (0..144).map(|i| (i % 7) as f64 / 7.0).collect();
+        assert_eq!(loaded.forward(inputs.clone()), model.forward(inputs));
+
+        assert!(Sequential::from_data(Vec::new()).is_err());
+    }
+}
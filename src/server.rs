@@ -0,0 +1,400 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::Read,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::config::Task;
+use crate::idx::invalid_data;
+use crate::image::{DigitOptions, GrayImage};
+use crate::network::{Network, NetworkData};
+
+// request bodies above this are refused, a 28x28 image as JSON is around 10 kB
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub address: String,
+    // number of requests handled at the same time
+    pub threads: usize,
+    // classes returned when a request doesn't ask for a number
+    pub top_k: usize,
+    // applied to posted images, size is taken from the model
+    pub digit: DigitOptions,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            address: "127.0.0.1:8080".to_string(),
+            threads: 4,
+            top_k: 3,
+            digit: DigitOptions::default(),
+        }
+    }
+}
+
+// the model being served, swapped out as a whole when the file changes
+// requests keep using the Arc they started with, so a reload never changes a network mid-request
+struct LoadedModel {
+    network: Arc<Network>,
+    // of the last version of the file that was tried
+    modified: Option<SystemTime>,
+}
+
+// serves predictions of the model at model_path until the process is stopped
+//   GET  /health   liveness check
+//   GET  /model    inputs, outputs, class names and layers of the loaded model
+//   POST /predict  {"inputs": [...]} or {"instances": [[...], ...]} as JSON,
+//                  or the bytes of a PNG/BMP/PGM image
+//                  regression models answer with their outputs instead of predictions
+// the number of classes can be set per request with ?top_k=N, images also take
+// ?invert=auto|always|never and ?center=false
+pub fn serve(model_path: impl AsRef<Path>, options: &ServerOptions) -> Result<(), std::io::Error> {
+    let model_path = model_path.as_ref().to_path_buf();
+    let network = load(&model_path)?;
+    let server = Server::http(&options.address)
+        .map_err(|e| invalid_data(format!("Unable to listen on {}: {}", options.address, e)))?;
+    println!(
+        "Serving {} on http://{}",
+        model_path.display(),
+        server.server_addr()
+    );
+    run(server, network, model_path, options);
+    Ok(())
+}
+
+// answers requests on an already bound server, never returns
+fn run(server: Server, network: Network, model_path: PathBuf, options: &ServerOptions) {
+    let server = Arc::new(server);
+    let model = Arc::new(RwLock::new(LoadedModel {
+        network: Arc::new(network),
+        modified: modified(&model_path),
+    }));
+
+    {
+        let model = Arc::clone(&model);
+        thread::spawn(move || watch(&model_path, &model));
+    }
+    let workers: Vec<thread::JoinHandle<()>> = (0..options.threads.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let model = Arc::clone(&model);
+            let options = options.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    let network = Arc::clone(&model.read().expect("Lock mess.").network);
+                    // a panic only fails its own request, the dropped request answers with a 500
+                    // and the worker goes on with the next one
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                        handle(request, &network, &options)
+                    }));
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().expect("Threading mess.");
+    }
+}
+
+fn load(path: &Path) -> Result<Network, std::io::Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let data: NetworkData = serde_json::from_str(&contents)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    // the learning rate doesn't matter for inference
+    Network::from_data(data, 0.0).map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// polls the model file and swaps in the new network once it parses,
+// a half written or broken file keeps the previous one serving
+fn watch(path: &Path, model: &RwLock<LoadedModel>) {
+    loop {
+        thread::sleep(RELOAD_INTERVAL);
+        let current = modified(path);
+        if current.is_none() || current == model.read().expect("Lock mess.").modified {
+            continue;
+        }
+        match load(path) {
+            Ok(network) => {
+                let mut model = model.write().expect("Lock mess.");
+                model.network = Arc::new(network);
+                model.modified = current;
+                println!("Reloaded {}", path.display());
+            }
+            Err(e) => {
+                // only warn again once the file changes another time
+                model.write().expect("Lock mess.").modified = current;
+                eprintln!("Not reloading {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PredictRequest {
+    inputs: Option<Vec<f64>>,
+    instances: Option<Vec<Vec<f64>>>,
+    top_k: Option<usize>,
+}
+
+fn handle(mut request: Request, network: &Network, options: &ServerOptions) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query: Vec<(&str, &str)> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+
+    let result = match (request.method(), path) {
+        (Method::Get, "/health") => Ok(json!({ "status": "ok" })),
+        (Method::Get, "/model") => Ok(metadata(network)),
+        (Method::Post, "/predict") => {
+            let is_json = request.headers().iter().any(|h| {
+                h.field.equiv("Content-Type") && h.value.as_str().starts_with("application/json")
+            });
+            let mut body: Vec<u8> = Vec::new();
+            match request
+                .as_reader()
+                .take(MAX_BODY_SIZE)
+                .read_to_end(&mut body)
+            {
+                Ok(_) if is_json => predict_json(network, &body, &query, options),
+                Ok(_) => predict_image(network, &body, &query, options),
+                Err(e) => Err((400, format!("Unable to read request: {}", e))),
+            }
+        }
+        (_, "/health" | "/model" | "/predict") => Err((405, "Method not allowed".to_string())),
+        _ => Err((404, "Not found".to_string())),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err((status, message)) => (status, json!({ "error": message })),
+    };
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(
+            Header::from_bytes("Content-Type", "application/json").expect("Valid header."),
+        );
+    // the client hanging up early isn't the server's problem
+    let _ = request.respond(response);
+}
+
+fn metadata(network: &Network) -> Value {
+    json!({
+        "inputs": network.input_size(),
+        "task": network.task(),
+        "outputs": network.output_size(),
+        "class_names": (0..network.output_size()).map(|c| network.label(c)).collect::<Vec<String>>(),
+        "layers": network.layers().iter().map(|layer| json!({
+            "type": layer.to_config().kind,
+            "inputs": layer.input_size(),
+            "outputs": layer.output_size(),
+            "activation": layer.activation(),
+            "parameters": layer.parameter_count(),
+        })).collect::<Vec<Value>>(),
+        "preprocessing": network.preprocessing().iter().map(|p| p.name()).collect::<Vec<&str>>(),
+    })
+}
+
+fn query_value<'a>(query: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    query.iter().find(|(key, _)| *key == name).map(|(_, v)| *v)
+}
+
+fn top_k(query: &[(&str, &str)], default: usize) -> Result<usize, (u16, String)> {
+    match query_value(query, "top_k") {
+        Some(value) => value
+            .parse()
+            .map_err(|_| (400, format!("Invalid top_k {:?}", value))),
+        None => Ok(default),
+    }
+}
+
+fn predict_json(
+    network: &Network,
+    body: &[u8],
+    query: &[(&str, &str)],
+    options: &ServerOptions,
+) -> Result<Value, (u16, String)> {
+    let request: PredictRequest =
+        serde_json::from_slice(body).map_err(|e| (400, format!("Invalid JSON: {}", e)))?;
+    let k = match request.top_k {
+        Some(k) => k,
+        None => top_k(query, options.top_k)?,
+    };
+    let check = |inputs: &Vec<f64>| {
+        if inputs.len() == network.input_size() {
+            Ok(())
+        } else {
+            Err((
+                400,
+                format!(
+                    "Model takes {} inputs, got {}",
+                    network.input_size(),
+                    inputs.len()
+                ),
+            ))
+        }
+    };
+    let regression = network.task() == Task::Regression;
+    match (request.inputs, request.instances) {
+        (Some(inputs), None) => {
+            check(&inputs)?;
+            if regression {
+                return Ok(json!({ "outputs": network.feed_forward(inputs) }));
+            }
+            Ok(json!({ "predictions": network.predict_top_k(inputs, k) }))
+        }
+        (None, Some(instances)) => {
+            instances.iter().try_for_each(check)?;
+            if regression {
+                let outputs: Vec<Vec<f64>> = instances
+                    .into_iter()
+                    .map(|inputs| network.feed_forward(inputs))
+                    .collect();
+                return Ok(json!({ "outputs": outputs }));
+            }
+            Ok(json!({ "predictions": network.predict_top_k_batch(&instances, k) }))
+        }
+        _ => Err((400, "Expected either inputs or instances".to_string())),
+    }
+}
+
+fn predict_image(
+    network: &Network,
+    body: &[u8],
+    query: &[(&str, &str)],
+    options: &ServerOptions,
+) -> Result<Value, (u16, String)> {
+    let side = (network.input_size() as f64).sqrt() as usize;
+    if side * side != network.input_size() {
+        return Err((400, "Model doesn't take square images".to_string()));
+    }
+    let mut digit = DigitOptions {
+        size: side,
+        ..options.digit.clone()
+    };
+    if let Some(invert) = query_value(query, "invert") {
+        digit.invert = invert.parse().map_err(|e| (400, e))?;
+    }
+    if let Some(center) = query_value(query, "center") {
+        digit.center = center != "false" && center != "0";
+    }
+    let k = top_k(query, options.top_k)?;
+    let image = GrayImage::decode(body).map_err(|e| (400, e.to_string()))?;
+    let inputs = image.to_digit(&digit).to_inputs();
+    if network.task() == Task::Regression {
+        return Ok(json!({ "outputs": network.feed_forward(inputs) }));
+    }
+    Ok(json!({ "predictions": network.predict_top_k(inputs, k) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpStream},
+    };
+
+    fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn serves_health_model_and_predictions() {
+        let mut network = Network::with_rng(vec![3, 2], 4, 0.1, &mut StdRng::seed_from_u64(1));
+        network
+            .set_class_names(vec!["even".to_string(), "odd".to_string()])
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("served-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            serde_json::to_string(&network.output_data()).unwrap(),
+        )
+        .unwrap();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let options = ServerOptions {
+            threads: 2,
+            ..ServerOptions::default()
+        };
+        let model_path = path.clone();
+        thread::spawn(move || run(server, network, model_path, &options));
+
+        assert_eq!(
+            request(address, "GET", "/health", ""),
+            (200, json!({ "status": "ok" }))
+        );
+
+        let (status, model) = request(address, "GET", "/model", "");
+        assert_eq!(status, 200);
+        assert_eq!(model["inputs"], 4);
+        assert_eq!(model["outputs"], 2);
+        assert_eq!(model["class_names"], json!(["even", "odd"]));
+        assert_eq!(model["layers"].as_array().unwrap().len(), 2);
+
+        let (status, single) = request(
+            address,
+            "POST",
+            "/predict",
+            r#"{"inputs": [0, 0.5, 1, 0], "top_k": 1}"#,
+        );
+        assert_eq!(status, 200);
+        let predictions = single["predictions"].as_array().unwrap();
+        assert_eq!(predictions.len(), 1);
+        assert!(["even", "odd"].contains(&predictions[0]["label"].as_str().unwrap()));
+
+        let (status, batch) = request(
+            address,
+            "POST",
+            "/predict",
+            r#"{"instances": [[0, 0, 0, 0], [1, 1, 1, 1]]}"#,
+        );
+        assert_eq!(status, 200);
+        let predictions = batch["predictions"].as_array().unwrap();
+        assert_eq!(predictions.len(), 2);
+        // top_k defaults to 3, there are only two classes
+        assert!(predictions
+            .iter()
+            .all(|classes| classes.as_array().unwrap().len() == 2));
+
+        let (status, error) = request(address, "POST", "/predict", r#"{"inputs": [1, 2]}"#);
+        assert_eq!(status, 400);
+        assert_eq!(error["error"], "Model takes 4 inputs, got 2");
+        assert_eq!(request(address, "GET", "/predict", "").0, 405);
+        assert_eq!(request(address, "GET", "/missing", "").0, 404);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,631 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt, path::Path, str::FromStr};
+
+use crate::idx::invalid_data;
+use crate::network::{TrainingData, TrainingSet};
+
+// a column of a CSV file, either by position or by its header name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LabelKind {
+    // every distinct value is a class, targets are one-hot encoded
+    Categorical,
+    // every label column is a regression target
+    Numeric,
+}
+
+// written as error, drop, mean or fill:VALUE
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MissingValues {
+    // fail loading with the offending line number
+    Error,
+    // leave out rows with any missing feature
+    DropRow,
+    // replace missing numeric features with a fixed value
+    Fill(f64),
+    // replace missing numeric features with the column mean
+    Mean,
+}
+
+impl FromStr for MissingValues {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<MissingValues, String> {
+        match policy.split_once(':') {
+            None if policy == "error" => Ok(MissingValues::Error),
+            None if policy == "drop" => Ok(MissingValues::DropRow),
+            None if policy == "mean" => Ok(MissingValues::Mean),
+            Some(("fill", value)) => match value.parse::<f64>() {
+                Ok(value) if value.is_finite() => Ok(MissingValues::Fill(value)),
+                _ => Err(format!("Invalid fill value {:?}", value)),
+            },
+            _ => Err(format!("Unknown missing value policy {:?}", policy)),
+        }
+    }
+}
+
+impl TryFrom<String> for MissingValues {
+    type Error = String;
+
+    fn try_from(policy: String) -> Result<MissingValues, String> {
+        policy.parse()
+    }
+}
+
+impl From<MissingValues> for String {
+    fn from(policy: MissingValues) -> String {
+        policy.to_string()
+    }
+}
+
+impl fmt::Display for MissingValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MissingValues::Error => write!(f, "error"),
+            MissingValues::DropRow => write!(f, "drop"),
+            MissingValues::Fill(value) => write!(f, "fill:{}", value),
+            MissingValues::Mean => write!(f, "mean"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub has_header: bool,
+    // categorical labels take a single column, numeric ones any number of them
+    pub label_columns: Vec<Column>,
+    pub label_kind: LabelKind,
+    // defaults to every column except the labels
+    pub feature_columns: Option<Vec<Column>>,
+    // feature columns holding categories instead of numbers, these get one-hot encoded
+    pub categorical_columns: Vec<Column>,
+    pub missing_values: MissingValues,
+    // cell contents treated as missing whatever their case, in addition to empty cells
+    pub missing_markers: Vec<String>,
+    // fitted on the training file, replaces feature_columns, categorical_columns and the
+    // classes found in the file so other files are encoded exactly the same way
+    pub encoding: Option<CsvEncoding>,
+}
+
+impl CsvOptions {
+    pub fn new(label_column: Column) -> Self {
+        CsvOptions {
+            delimiter: ',',
+            has_header: true,
+            label_columns: vec![label_column],
+            label_kind: LabelKind::Categorical,
+            feature_columns: None,
+            categorical_columns: Vec::new(),
+            missing_values: MissingValues::Error,
+            missing_markers: vec!["NA".to_string(), "NaN".to_string(), "?".to_string()],
+            encoding: None,
+        }
+    }
+
+    // same as new, but picks a tab delimiter for .tsv and .tab files
+    pub fn for_file(path: impl AsRef<Path>, label_column: Column) -> Self {
+        let mut options = CsvOptions::new(label_column);
+        if let Some(extension) = path.as_ref().extension().and_then(|e| e.to_str()) {
+            if extension.eq_ignore_ascii_case("tsv") || extension.eq_ignore_ascii_case("tab") {
+                options.delimiter = '\t';
+            }
+        }
+        options
+    }
+}
+
+// how the columns of a CSV file became inputs and targets, stored with the network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CsvEncoding {
+    pub features: Vec<FeatureEncoding>,
+    // classes of categorical labels in the order of the outputs, empty for numeric labels
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeatureEncoding {
+    // by header name, or by index for files without a header
+    pub column: Column,
+    // one input per category for categorical features, one input for numeric ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub categories: Option<Vec<String>>,
+}
+
+enum Feature {
+    Numeric {
+        column: usize,
+    },
+    Categorical {
+        column: usize,
+        categories: Vec<String>,
+    },
+}
+
+pub fn load_csv(
+    path: impl AsRef<Path>,
+    options: &CsvOptions,
+) -> Result<(TrainingSet, CsvEncoding), std::io::Error> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_csv(&contents, options)
+}
+
+// the samples together with how they were encoded, which is options.encoding when it's set
+pub fn parse_csv(
+    contents: &str,
+    options: &CsvOptions,
+) -> Result<(TrainingSet, CsvEncoding), std::io::Error> {
+    let mut records: Vec<(usize, Vec<String>)> = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| Ok((i + 1, split_record(line, options.delimiter, i + 1)?)))
+        .collect::<Result<_, std::io::Error>>()?;
+    let header: Vec<String> = if options.has_header && !records.is_empty() {
+        records.remove(0).1
+    } else {
+        Vec::new()
+    };
+    let column_count = records
+        .first()
+        .map_or(header.len(), |(_, record)| record.len());
+    if let Some((line, record)) = records.iter().find(|(_, r)| r.len() != column_count) {
+        return Err(invalid_data(format!(
+            "Line {} has {} columns, expected {}",
+            line,
+            record.len(),
+            column_count
+        )));
+    }
+
+    let resolve = |column: &Column| -> Result<usize, std::io::Error> {
+        let index = match column {
+            Column::Index(index) => Some(*index),
+            Column::Name(name) => header.iter().position(|h| h == name),
+        };
+        index
+            .filter(|i| *i < column_count)
+            .ok_or_else(|| invalid_data(format!("Column {:?} not found in the CSV file", column)))
+    };
+    let label_columns: Vec<usize> = options
+        .label_columns
+        .iter()
+        .map(resolve)
+        .collect::<Result<_, _>>()?;
+    if label_columns.is_empty()
+        || (options.label_kind == LabelKind::Categorical && label_columns.len() > 1)
+    {
+        return Err(invalid_data(format!(
+            "Expected {} label column, got {}",
+            match options.label_kind {
+                LabelKind::Categorical => "a single",
+                LabelKind::Numeric => "at least one",
+            },
+            label_columns.len()
+        )));
+    }
+    let encoding = options.encoding.as_ref();
+    let feature_columns: Vec<usize> = match (encoding, &options.feature_columns) {
+        (Some(encoding), _) => encoding
+            .features
+            .iter()
+            .map(|feature| resolve(&feature.column))
+            .collect::<Result<_, _>>()?,
+        (None, Some(columns)) => columns.iter().map(resolve).collect::<Result<_, _>>()?,
+        (None, None) => (0..column_count)
+            .filter(|i| !label_columns.contains(i))
+            .collect(),
+    };
+    if let Some(column) = feature_columns.iter().find(|c| label_columns.contains(c)) {
+        return Err(invalid_data(format!(
+            "Column {} is both a feature and a label",
+            header.get(*column).cloned().unwrap_or(column.to_string())
+        )));
+    }
+    let categorical_columns: Vec<usize> = options
+        .categorical_columns
+        .iter()
+        .map(resolve)
+        .collect::<Result<_, _>>()?;
+
+    // "nan" would otherwise parse as a number and feed NaN to the network
+    let is_missing = |cell: &str| {
+        cell.is_empty()
+            || options
+                .missing_markers
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(cell))
+    };
+
+    // rows without a label can't be used for training, whatever the missing value policy
+    records.retain(|(_, record)| label_columns.iter().all(|c| !is_missing(&record[*c])));
+    if options.missing_values == MissingValues::DropRow {
+        records.retain(|(_, record)| feature_columns.iter().all(|c| !is_missing(&record[*c])));
+    }
+    if options.missing_values == MissingValues::Error {
+        for (line, record) in records.iter() {
+            if let Some(column) = feature_columns.iter().find(|c| is_missing(&record[**c])) {
+                let name = header.get(*column).cloned().unwrap_or(column.to_string());
+                return Err(invalid_data(format!(
+                    "Missing value in column {} on line {}",
+                    name, line
+                )));
+            }
+        }
+    }
+
+    let features: Vec<Feature> = feature_columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            if let Some(encoding) = encoding {
+                return match &encoding.features[i].categories {
+                    Some(categories) => Feature::Categorical {
+                        column: *column,
+                        categories: categories.clone(),
+                    },
+                    None => Feature::Numeric { column: *column },
+                };
+            }
+            if categorical_columns.contains(column) {
+                let categories: BTreeSet<&str> = records
+                    .iter()
+                    .map(|(_, record)| record[*column].as_str())
+                    .filter(|cell| !is_missing(cell))
+                    .collect();
+                Feature::Categorical {
+                    column: *column,
+                    categories: categories.into_iter().map(String::from).collect(),
+                }
+            } else {
+                Feature::Numeric { column: *column }
+            }
+        })
+        .collect();
+
+    let parse_number = |cell: &str, line: usize| -> Result<f64, std::io::Error> {
+        match cell.trim().parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(number),
+            _ => Err(invalid_data(format!(
+                "Could not parse {:?} on line {} as a number",
+                cell, line
+            ))),
+        }
+    };
+
+    // fill values for missing numeric features, per feature column
+    let mut fill_values: Vec<f64> = vec![0.0; features.len()];
+    for (i, feature) in features.iter().enumerate() {
+        if let Feature::Numeric { column } = feature {
+            fill_values[i] = match options.missing_values {
+                MissingValues::Fill(value) => value,
+                MissingValues::Mean => {
+                    let mut sum = 0.0;
+                    let mut count = 0;
+                    for (line, record) in records.iter() {
+                        if !is_missing(&record[*column]) {
+                            sum += parse_number(&record[*column], *line)?;
+                            count += 1;
+                        }
+                    }
+                    if count > 0 {
+                        sum / count as f64
+                    } else {
+                        0.0
+                    }
+                }
+                MissingValues::Error | MissingValues::DropRow => 0.0,
+            };
+        }
+    }
+
+    // numeric labels are named after their columns
+    let class_names: Vec<String> = match (options.label_kind, encoding) {
+        (LabelKind::Categorical, Some(encoding)) if !encoding.labels.is_empty() => {
+            encoding.labels.clone()
+        }
+        (LabelKind::Categorical, _) => sorted_categories(
+            records
+                .iter()
+                .map(|(_, record)| record[label_columns[0]].as_str())
+                .collect(),
+        ),
+        (LabelKind::Numeric, _) => label_columns
+            .iter()
+            .map(|c| header.get(*c).cloned().unwrap_or(format!("column {}", c)))
+            .collect(),
+    };
+
+    let mut samples: Vec<TrainingData> = Vec::with_capacity(records.len());
+    for (line, record) in records.iter() {
+        let mut inputs: Vec<f64> = Vec::new();
+        for (i, feature) in features.iter().enumerate() {
+            match feature {
+                Feature::Numeric { column } => {
+                    let cell = &record[*column];
+                    if is_missing(cell) {
+                        inputs.push(fill_values[i]);
+                    } else {
+                        inputs.push(parse_number(cell, *line)?);
+                    }
+                }
+                // a missing category leaves every one-hot input at zero
+                Feature::Categorical { column, categories } => {
+                    inputs.extend(categories.iter().map(|c| {
+                        if *c == record[*column] {
+                            1.0
+                        } else {
+                            0.0
+                        }
+                    }));
+                }
+            }
+        }
+
+        let (target, classification) = match options.label_kind {
+            LabelKind::Categorical => {
+                let label = &record[label_columns[0]];
+                let classification = class_names
+                    .iter()
+                    .position(|name| name == label)
+                    .ok_or_else(|| {
+                        invalid_data(format!(
+                            "Label {:?} on line {} is not one of the classes {}",
+                            label,
+                            line,
+                            class_names.join(", ")
+                        ))
+                    })?;
+                let mut target = vec![0.0; class_names.len()];
+                target[classification] = 1.0;
+                (target, classification)
+            }
+            LabelKind::Numeric => (
+                label_columns
+                    .iter()
+                    .map(|c| parse_number(&record[*c], *line))
+                    .collect::<Result<Vec<f64>, _>>()?,
+                0,
+            ),
+        };
+        samples.push(TrainingData {
+            inputs,
+            target,
+            classification,
+        });
+    }
+
+    let encoding = CsvEncoding {
+        features: features
+            .iter()
+            .map(|feature| {
+                let (column, categories) = match feature {
+                    Feature::Numeric { column } => (*column, None),
+                    Feature::Categorical { column, categories } => {
+                        (*column, Some(categories.clone()))
+                    }
+                };
+                FeatureEncoding {
+                    column: match header.get(column) {
+                        Some(name) => Column::Name(name.clone()),
+                        None => Column::Index(column),
+                    },
+                    categories,
+                }
+            })
+            .collect(),
+        labels: match options.label_kind {
+            LabelKind::Categorical => class_names.clone(),
+            LabelKind::Numeric => Vec::new(),
+        },
+    };
+    Ok((TrainingSet::new(samples, class_names), encoding))
+}
+
+// distinct values in order, numerically if every value is a number
+fn sorted_categories(values: Vec<&str>) -> Vec<String> {
+    let mut categories: Vec<&str> = values
+        .into_iter()
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
+        .collect();
+    if categories.iter().all(|c| c.trim().parse::<f64>().is_ok()) {
+        categories.sort_by(|a, b| {
+            let a: f64 = a.trim().parse().expect("Checked above.");
+            let b: f64 = b.trim().parse().expect("Checked above.");
+            a.total_cmp(&b)
+        });
+    }
+    categories.into_iter().map(String::from).collect()
+}
+
+// splits one line into cells, supporting double quoted cells with "" as an escaped quote
+fn split_record(
+    line: &str,
+    delimiter: char,
+    line_number: usize,
+) -> Result<Vec<String>, std::io::Error> {
+    let mut cells: Vec<String> = Vec::new();
+    let mut cell = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    cell.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                cell.push(c);
+            }
+        } else if c == '"' && cell.trim().is_empty() {
+            cell.clear();
+            in_quotes = true;
+        } else if c == delimiter {
+            cells.push(cell.trim().to_string());
+            cell = String::new();
+        } else {
+            cell.push(c);
+        }
+    }
+    if in_quotes {
+        return Err(invalid_data(format!(
+            "Unterminated quote on line {}",
+            line_number
+        )));
+    }
+    cells.push(cell.trim().to_string());
+    Ok(cells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(set: &TrainingSet) -> Vec<Vec<f64>> {
+        set.samples.iter().map(|s| s.inputs.clone()).collect()
+    }
+
+    #[test]
+    fn reads_headers_and_delimiters() {
+        let mut options = CsvOptions::for_file("data.tsv", Column::Name("label".to_string()));
+        assert_eq!(options.delimiter, '\t');
+        let (set, encoding) = parse_csv("x\tlabel\ty\n1\tb\t2\n\"3\"\ta\t4\n", &options).unwrap();
+        assert_eq!(inputs(&set), vec![vec![1.0, 2.0], vec![3.0, 4.0]]);
+        assert_eq!(set.class_names, vec!["a", "b"]);
+        assert_eq!(
+            set.samples
+                .iter()
+                .map(|s| s.classification)
+                .collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        assert_eq!(encoding.features[1].column, Column::Name("y".to_string()));
+
+        // without a header the first line is a sample and columns go by index
+        options.delimiter = ';';
+        options.has_header = false;
+        options.label_columns = vec![Column::Index(2)];
+        let (set, encoding) = parse_csv("1;2;a\n3;4;b\n", &options).unwrap();
+        assert_eq!(inputs(&set), vec![vec![1.0, 2.0], vec![3.0, 4.0]]);
+        assert_eq!(encoding.features[0].column, Column::Index(0));
+        assert!(parse_csv("1;2;a\n3;4;b\n", &CsvOptions::new(Column::Index(2))).is_err());
+    }
+
+    #[test]
+    fn one_hot_encodes_categorical_features() {
+        let mut options = CsvOptions::new(Column::Name("label".to_string()));
+        options.categorical_columns = vec![Column::Name("color".to_string())];
+        let csv = "color,size,label\nred,1,x\nblue,2,y\ngreen,3,x\nred,4,y\n";
+        let (set, encoding) = parse_csv(csv, &options).unwrap();
+        // categories in sorted order: blue, green, red
+        assert_eq!(
+            inputs(&set),
+            vec![
+                vec![0.0, 0.0, 1.0, 1.0],
+                vec![1.0, 0.0, 0.0, 2.0],
+                vec![0.0, 1.0, 0.0, 3.0],
+                vec![0.0, 0.0, 1.0, 4.0],
+            ]
+        );
+        assert_eq!(
+            encoding.features[0].categories,
+            Some(vec![
+                "blue".to_string(),
+                "green".to_string(),
+                "red".to_string()
+            ])
+        );
+        assert_eq!(encoding.features[1].categories, None);
+    }
+
+    #[test]
+    fn applies_the_missing_value_policy() {
+        // "nan" isn't a number to train on, whatever its case
+        let csv = "a,b,label\n1,2,x\nNA,4,y\n5,nan,x\n7,8,\n";
+        let mut options = CsvOptions::new(Column::Name("label".to_string()));
+        let error = parse_csv(csv, &options).err().unwrap().to_string();
+        assert!(error.contains("column a on line 3"), "{}", error);
+
+        // the row without a label is always left out
+        options.missing_values = MissingValues::DropRow;
+        let (set, _) = parse_csv(csv, &options).unwrap();
+        assert_eq!(inputs(&set), vec![vec![1.0, 2.0]]);
+
+        options.missing_values = MissingValues::Fill(-1.0);
+        let (set, _) = parse_csv(csv, &options).unwrap();
+        assert_eq!(
+            inputs(&set),
+            vec![vec![1.0, 2.0], vec![-1.0, 4.0], vec![5.0, -1.0]]
+        );
+
+        options.missing_values = MissingValues::Mean;
+        let (set, _) = parse_csv(csv, &options).unwrap();
+        assert_eq!(
+            inputs(&set),
+            vec![vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 3.0]]
+        );
+
+        // infinite values aren't missing, they're invalid
+        assert!(parse_csv("a,label\ninf,x\n", &options).is_err());
+    }
+
+    #[test]
+    fn parses_missing_value_policies() {
+        for policy in ["error", "drop", "mean", "fill:-1.5"] {
+            assert_eq!(policy.parse::<MissingValues>().unwrap().to_string(), policy);
+        }
+        assert!("fill".parse::<MissingValues>().is_err());
+        assert!("fill:nan".parse::<MissingValues>().is_err());
+        assert!("skip".parse::<MissingValues>().is_err());
+    }
+
+    #[test]
+    fn reads_several_numeric_labels() {
+        let mut options = CsvOptions::new(Column::Name("t1".to_string()));
+        options.label_columns.push(Column::Index(2));
+        let csv = "t1,x,t2\n0.5,1,-2\n1.5,3,4\n";
+        assert!(parse_csv(csv, &options).is_err());
+
+        options.label_kind = LabelKind::Numeric;
+        let (set, encoding) = parse_csv(csv, &options).unwrap();
+        assert_eq!(inputs(&set), vec![vec![1.0], vec![3.0]]);
+        assert_eq!(set.samples[0].target, vec![0.5, -2.0]);
+        assert_eq!(set.samples[1].target, vec![1.5, 4.0]);
+        assert_eq!(set.class_names, vec!["t1", "t2"]);
+        assert!(encoding.labels.is_empty());
+    }
+
+    #[test]
+    fn encodes_other_files_like_the_training_file() {
+        let mut options = CsvOptions::new(Column::Name("label".to_string()));
+        options.categorical_columns = vec![Column::Name("color".to_string())];
+        let (_, encoding) = parse_csv("color,size,label\nred,1,x\nblue,2,y\n", &options).unwrap();
+
+        // columns are found by name, and unknown categories leave every one-hot input at zero
+        options.encoding = Some(encoding.clone());
+        let (set, refitted) =
+            parse_csv("label,size,color\ny,5,green\nx,6,red\n", &options).unwrap();
+        assert_eq!(inputs(&set), vec![vec![0.0, 0.0, 5.0], vec![0.0, 1.0, 6.0]]);
+        assert_eq!(set.class_names, vec!["x", "y"]);
+        assert_eq!(refitted, encoding);
+
+        // unknown labels can't be scored
+        let error = parse_csv("color,size,label\nred,1,z\n", &options)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("\"z\" on line 2"), "{}", error);
+    }
+}